@@ -0,0 +1,130 @@
+//! Benchmark each stage of compression on its own, in both directions.
+//!
+//! Every stage is run over the same 1 MB inputs, whatever the stages before it would have done to
+//! them, so that the numbers for one stage don't move when another one changes. The one exception
+//! is the second run-length encoding, which is given the move-to-front transform of each input.
+//! Run them with `cargo bench`.
+use std::hint::black_box;
+
+use beeziptoo::transforms::{burrows_wheeler, move_to_front, rle1, rle2};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// How long each input is.
+const LEN: usize = 1_000_000;
+
+/// The longest a single Burrows-Wheeler transform can be, since that is as big as a block gets.
+const BLOCK_LEN: usize = 899_981;
+
+/// English text, random bytes, and long runs, each [`LEN`] bytes long.
+fn inputs() -> [(&'static str, Vec<u8>); 3] {
+    let text = include_str!("../LICENSE")
+        .as_bytes()
+        .repeat(LEN.div_ceil(30_000));
+
+    // A simple linear congruential generator is plenty here.
+    let mut state: u32 = 0x2545_f491;
+    let random = (0..LEN)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        })
+        .collect();
+
+    let runs = (0..LEN).map(|i| b'a' + (i / 1_000 % 26) as u8).collect();
+
+    [
+        ("text", text[..LEN].to_vec()),
+        ("random", random),
+        ("runs", runs),
+    ]
+}
+
+fn bench_rle1(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rle1");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for (name, data) in inputs() {
+        let encoded = rle1::encode(&data);
+
+        group.bench_with_input(BenchmarkId::new("encode", name), &data, |b, data| {
+            b.iter(|| rle1::encode(black_box(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| rle1::decode(black_box(encoded)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// The transform only takes a block at a time, so the inputs are split into blocks like
+/// [`beeziptoo::compress`] would split them.
+fn bench_burrows_wheeler(c: &mut Criterion) {
+    let mut group = c.benchmark_group("burrows_wheeler");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for (name, data) in inputs() {
+        let encoded: Vec<(Vec<u8>, u32)> = data
+            .chunks(BLOCK_LEN)
+            .map(burrows_wheeler::encode)
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("encode", name), &data, |b, data| {
+            b.iter(|| {
+                for block in black_box(data).chunks(BLOCK_LEN) {
+                    black_box(burrows_wheeler::encode(block));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| {
+                for (block, origin_pointer) in black_box(encoded) {
+                    black_box(burrows_wheeler::decode(block, *origin_pointer).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_move_to_front(c: &mut Criterion) {
+    let mut group = c.benchmark_group("move_to_front");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for (name, data) in inputs() {
+        let encoded = move_to_front::encode(&data);
+
+        group.bench_with_input(BenchmarkId::new("encode", name), &data, |b, data| {
+            b.iter(|| move_to_front::encode(black_box(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| move_to_front::decode(black_box(encoded)))
+        });
+    }
+    group.finish();
+}
+
+/// This stage is all about runs of zeros, which the inputs hardly have until the move-to-front
+/// transform makes them.
+fn bench_rle2(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rle2");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for (name, data) in inputs() {
+        let data = move_to_front::encode(&data);
+        let encoded = rle2::encode(&data);
+
+        group.bench_with_input(BenchmarkId::new("encode", name), &data, |b, data| {
+            b.iter(|| rle2::encode(black_box(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| rle2::decode(black_box(encoded)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_rle1,
+    bench_burrows_wheeler,
+    bench_move_to_front,
+    bench_rle2
+);
+criterion_main!(benches);
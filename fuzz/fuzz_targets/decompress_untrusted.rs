@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::io::{Cursor, Read};
+
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes should only ever be an error, never a panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut decompressed) = beeziptoo::decompress(Cursor::new(data)) {
+        let _ = decompressed.read_to_end(&mut vec![]);
+    }
+});
@@ -1,7 +1,13 @@
 //! Define the Burrows-Wheeler encode and decode steps.
-use std::collections::VecDeque;
+use alloc::{vec, vec::Vec};
+use core::num::TryFromIntError;
+#[cfg(test)]
+use std::{cmp::Ordering, collections::VecDeque};
+
+mod randomization;
 
-use crate::file_format::OriginPointer;
+#[cfg(feature = "std")]
+pub(crate) use randomization::derandomize;
 
 /// Stores BWT-encoded information.
 #[derive(Debug, Default, Clone)]
@@ -15,12 +21,40 @@ pub(crate) struct BwtEncoded {
     pub(crate) origin_pointer: OriginPointer,
 }
 
-impl BwtEncoded {
-    pub(crate) fn new(data: Vec<u8>, origin_pointer: OriginPointer) -> Self {
-        BwtEncoded {
-            data,
-            origin_pointer,
-        }
+/// Where the rotation that is the block itself ended up after sorting, as stored in the block
+/// header.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct OriginPointer(pub(crate) u32);
+
+impl TryFrom<usize> for OriginPointer {
+    type Error = TryFromIntError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        Ok(OriginPointer(value.try_into()?))
+    }
+}
+
+// TODO: `.into()` should work here since we don't think Rust supports any 16-bit
+// platforms. What gives?
+impl TryFrom<OriginPointer> for usize {
+    type Error = TryFromIntError;
+
+    fn try_from(value: OriginPointer) -> Result<Self, Self::Error> {
+        value.0.try_into()
+    }
+}
+
+impl TryFrom<&OriginPointer> for usize {
+    type Error = TryFromIntError;
+
+    fn try_from(value: &OriginPointer) -> Result<Self, Self::Error> {
+        value.0.try_into()
+    }
+}
+
+impl From<u32> for OriginPointer {
+    fn from(value: u32) -> Self {
+        Self(value)
     }
 }
 
@@ -28,35 +62,54 @@ impl BwtEncoded {
 ///
 /// # Notes
 ///
-/// The origin pointer (index of original row in the sorted rotation block) is appended to the end of the
-/// data as 3 little-endian bytes.
+/// The origin pointer (index of original row in the sorted rotation block) is returned alongside
+/// the data rather than in it, since the file format stores it in the block header.
 pub(super) fn encode(data: &[u8]) -> BwtEncoded {
+    let mut output = Vec::new();
+
+    let origin_pointer = encode_into(data, &mut Vec::new(), &mut Vec::new(), &mut output);
+
+    BwtEncoded {
+        data: output,
+        origin_pointer,
+    }
+}
+
+/// Encode with the Burrows-Wheeler Transform into `output`, replacing its contents, and return the
+/// origin pointer.
+///
+/// `text` and `rotations` are scratch space for sorting the rotations. Their contents are
+/// overwritten, but passing in the same `Vec`s for each block avoids reallocating them. See
+/// [`encode`].
+pub(super) fn encode_into(
+    data: &[u8],
+    text: &mut Vec<usize>,
+    rotations: &mut Vec<usize>,
+    output: &mut Vec<u8>,
+) -> OriginPointer {
     // ASSUMPTION max block size for this stage is 900 kB
     assert!(data.len() < 900_000);
 
+    output.clear();
     if data.is_empty() {
-        return BwtEncoded::default();
+        return OriginPointer::default();
     }
 
-    let mut all_rotations = all_rotations(data);
-    all_rotations.sort_unstable();
-    let origin_pointer: OriginPointer = all_rotations
-        .binary_search_by_key(&data, |v| &v[..])
-        .expect("`data` must be in `all_rotations`")
+    sorted_rotations(data, text, rotations);
+    let origin_pointer = rotations
+        .iter()
+        .position(|&start| start == 0)
+        .expect("every rotation was sorted")
         .try_into()
         .expect("`origin_pointer` must fit into 24 bits");
-    let data: Vec<u8> = all_rotations
-        .into_iter()
-        .map(|mut v| {
-            v.pop()
-                .expect("presence of outer `Vec` means inner `Vec` was not empty")
-        })
-        .collect();
-
-    BwtEncoded {
-        data,
-        origin_pointer,
-    }
+    // The last byte of each rotation is the one just before its start.
+    output.extend(
+        rotations
+            .iter()
+            .map(|&start| data[(start + data.len() - 1) % data.len()]),
+    );
+
+    origin_pointer
 }
 
 /// Errors that can occur when decoding a Burrows-Wheeler array.
@@ -64,6 +117,10 @@ pub(super) fn encode(data: &[u8]) -> BwtEncoded {
 pub enum DecodeError {
     #[error("origin pointer out of range")]
     InvalidOriginPointer,
+    #[error("transform vector does not cycle back to the origin")]
+    BrokenCycle,
+    #[error("block has no data")]
+    EmptyBlock,
 }
 
 /// Decode the Burrows-Wheeler transform.
@@ -74,15 +131,35 @@ pub enum DecodeError {
 /// is not a rotation of the input. The algorithm is quite sensitive to the encoder and decoder
 /// using the same sort ordering, and if they do not it is possible to get a row that is missing
 /// characters.
+#[cfg(test)]
 pub(super) fn decode(
     BwtEncoded {
         data,
         origin_pointer,
     }: &BwtEncoded,
 ) -> Result<Vec<u8>, DecodeError> {
+    let mut output = Vec::new();
+
+    decode_into(data, *origin_pointer, &mut Vec::new(), &mut output)?;
+
+    Ok(output)
+}
+
+/// Decode the Burrows-Wheeler transform into `output`, replacing its contents.
+///
+/// `permutation` is scratch space for the transform vector. Its contents are overwritten, but
+/// passing in the same `Vec` for each block avoids reallocating it. See [`decode`].
+pub(super) fn decode_into(
+    data: &[u8],
+    origin_pointer: OriginPointer,
+    permutation: &mut Vec<usize>,
+    output: &mut Vec<u8>,
+) -> Result<(), DecodeError> {
+    output.clear();
+
     // ASSUMPTION it is valid for this to be empty
     if data.is_empty() {
-        return Ok(Vec::new());
+        return Ok(());
     }
 
     let origin_pointer: usize = origin_pointer.try_into().unwrap();
@@ -100,6 +177,7 @@ pub(super) fn decode(
     for value in data.iter() {
         frequency_histogram[*value as usize] += 1;
     }
+    let counts = frequency_histogram;
 
     // for i, v in enumerate(cumm):
     //     cumm[i] = n
@@ -111,7 +189,8 @@ pub(super) fn decode(
     }
 
     // perm = [0]*len(bwt)
-    let mut permutation = vec![0; data.len()];
+    permutation.clear();
+    permutation.resize(data.len(), 0);
 
     // for i, v in enumerate(bwt):
     //     perm[cumm[v]] = i
@@ -126,7 +205,7 @@ pub(super) fn decode(
     // i = perm[ptr]
     let mut input_index = permutation[origin_pointer];
     // data = bytearray(len(bwt))
-    let mut output = vec![0; data.len()];
+    output.resize(data.len(), 0);
 
     // for j in range(len(bwt)):
     //     data[j] = bwt[i]
@@ -136,10 +215,282 @@ pub(super) fn decode(
         input_index = permutation[input_index];
     }
 
-    Ok(output)
+    // A corrupt last column can give a transform vector whose cycle through the origin doesn't
+    // line up with the block. We can't require that the walk visits every position, because for a
+    // periodic block (such as "abcabc") the cycle is only as long as the period, and that is
+    // fine. What a valid block does guarantee is that the cycle length divides the block length,
+    // so the walk ends where it started, and that the output is a permutation of the input.
+    if input_index != permutation[origin_pointer] {
+        return Err(DecodeError::BrokenCycle);
+    }
+    let mut output_counts = [0; 256];
+    for value in output.iter() {
+        output_counts[*value as usize] += 1;
+    }
+    if output_counts != counts {
+        return Err(DecodeError::BrokenCycle);
+    }
+
+    Ok(())
+}
+
+/// Sort the rotations of `data`, filling `rotations` with the index that each one starts at.
+///
+/// `text` is overwritten with the text whose suffix array is built.
+///
+/// Rotations are sorted through the suffix array of `data` repeated twice: the first `data.len()`
+/// bytes of each of the first `data.len()` suffixes are exactly the rotations, so the suffixes
+/// starting there sort in the same order as the rotations. That suffix array is built with SA-IS,
+/// which takes time linear in the length of the block, however repetitive it is.
+///
+/// If the data is periodic (for example, every byte is the same), some rotations are equal to each
+/// other. Those are given in the order of their starting index, so that the rotation at index 0
+/// (the data itself) is always the first of its equals and the origin pointer is deterministic.
+fn sorted_rotations(data: &[u8], text: &mut Vec<usize>, rotations: &mut Vec<usize>) {
+    let len = data.len();
+    // Shift every byte up by one so that 0 is free for the sentinel that SA-IS needs.
+    text.clear();
+    text.extend(
+        data.iter()
+            .chain(data)
+            .map(|&byte| usize::from(byte) + 1)
+            .chain([0]),
+    );
+    suffix_array(text, 257, rotations);
+    rotations.retain(|&start| start < len);
+
+    // Equal rotations are suffixes that only differ once one of them runs out, and the shorter one
+    // (with the later start) sorts first. They start a whole number of periods apart, so each run
+    // of equal rotations is as long as the number of times the period fits into the block.
+    let equals = len / period(data);
+    if equals > 1 {
+        for group in rotations.chunks_mut(equals) {
+            group.reverse();
+        }
+    }
+}
+
+/// Find the length of the shortest `period` such that `data` is `data[..period]` repeated,
+/// which is `data.len()` if it isn't a repetition at all.
+fn period(data: &[u8]) -> usize {
+    // The longest proper prefix of `data` that is also a suffix, from the KMP failure function.
+    let mut border = 0;
+    let mut borders = vec![0; data.len()];
+    for index in 1..data.len() {
+        while border > 0 && data[index] != data[border] {
+            border = borders[border - 1];
+        }
+        if data[index] == data[border] {
+            border += 1;
+        }
+        borders[index] = border;
+    }
+
+    let period = data.len() - border;
+    if data.len().is_multiple_of(period) {
+        period
+    } else {
+        data.len()
+    }
+}
+
+/// A slot in the suffix array that hasn't been filled in yet.
+const EMPTY: usize = usize::MAX;
+
+/// Build the suffix array of `text` with SA-IS, the induced sorting algorithm from Nong, Zhang and
+/// Chan's "Two Efficient Algorithms for Linear Time Suffix Array Construction".
+///
+/// Every value in `text` must be less than `alphabet_size`, and the last one must be a sentinel
+/// that is smaller than every other value.
+///
+/// Each suffix is either S-type, if it sorts before the suffix after it, or L-type. The S-type
+/// suffixes just after an L-type suffix are the left-most S-type, or LMS, suffixes. Once the LMS
+/// suffixes are in order, the order of every other suffix can be induced from them with two passes
+/// over the array. The LMS suffixes are put in order by giving each LMS substring (the text from
+/// one LMS suffix to the next) a name, and sorting the suffixes of the string of names, which is
+/// at most half as long, the same way.
+fn suffix_array(text: &[usize], alphabet_size: usize, suffixes: &mut Vec<usize>) {
+    let len = text.len();
+    suffixes.clear();
+    if len == 1 {
+        suffixes.push(0);
+        return;
+    }
+
+    let mut is_s_type = vec![false; len];
+    is_s_type[len - 1] = true;
+    for index in (0..len - 1).rev() {
+        is_s_type[index] = text[index] < text[index + 1]
+            || (text[index] == text[index + 1] && is_s_type[index + 1]);
+    }
+    let is_lms = |index: usize| index > 0 && is_s_type[index] && !is_s_type[index - 1];
+
+    let mut bucket_sizes = vec![0; alphabet_size];
+    for &value in text {
+        bucket_sizes[value] += 1;
+    }
+
+    // Sort the LMS substrings, by dropping the LMS suffixes into the ends of their buckets in any
+    // order and inducing from there.
+    let lms: Vec<usize> = (0..len).filter(|&index| is_lms(index)).collect();
+    suffixes.resize(len, EMPTY);
+    place_lms(text, &bucket_sizes, lms.iter().copied(), suffixes);
+    induce(text, &is_s_type, &bucket_sizes, suffixes);
+
+    // Name each LMS substring by its rank, with equal substrings sharing a name.
+    let mut names = vec![EMPTY; len];
+    let mut name = 0;
+    let mut previous = None;
+    for &start in suffixes.iter().filter(|&&start| is_lms(start)) {
+        if let Some(previous) = previous {
+            if !lms_substrings_equal(text, &is_s_type, previous, start) {
+                name += 1;
+            }
+        }
+        names[start] = name;
+        previous = Some(start);
+    }
+
+    // The sentinel's substring is the only one that ends the reduced string, and it has the
+    // smallest name, so the reduced string has a sentinel too.
+    let reduced: Vec<usize> = lms.iter().map(|&start| names[start]).collect();
+    let mut reduced_suffixes = vec![];
+    if name + 1 == reduced.len() {
+        // Every name is different, so the names already give the order of the suffixes.
+        reduced_suffixes.resize(reduced.len(), 0);
+        for (index, &name) in reduced.iter().enumerate() {
+            reduced_suffixes[name] = index;
+        }
+    } else {
+        suffix_array(&reduced, name + 1, &mut reduced_suffixes);
+    }
+
+    // Now that the LMS suffixes are in order, place them and induce everything else.
+    suffixes.fill(EMPTY);
+    place_lms(
+        text,
+        &bucket_sizes,
+        reduced_suffixes.into_iter().rev().map(|index| lms[index]),
+        suffixes,
+    );
+    induce(text, &is_s_type, &bucket_sizes, suffixes);
+}
+
+/// Put each of the `lms` suffixes at the end of its bucket, working backwards from the end of each
+/// bucket in the order they are given.
+fn place_lms(
+    text: &[usize],
+    bucket_sizes: &[usize],
+    lms: impl Iterator<Item = usize>,
+    suffixes: &mut [usize],
+) {
+    let mut tails = bucket_tails(bucket_sizes);
+    for start in lms {
+        let tail = &mut tails[text[start]];
+        *tail -= 1;
+        suffixes[*tail] = start;
+    }
+}
+
+/// Induce the order of the L-type suffixes from the LMS suffixes in `suffixes`, and then the order
+/// of the S-type suffixes from the L-type suffixes.
+fn induce(text: &[usize], is_s_type: &[bool], bucket_sizes: &[usize], suffixes: &mut [usize]) {
+    // Each L-type suffix sorts after the suffix that follows it, so scanning forwards and putting
+    // each one at the head of its bucket puts them in order.
+    let mut heads = bucket_heads(bucket_sizes);
+    for index in 0..suffixes.len() {
+        let start = suffixes[index];
+        if start != EMPTY && start > 0 && !is_s_type[start - 1] {
+            let head = &mut heads[text[start - 1]];
+            suffixes[*head] = start - 1;
+            *head += 1;
+        }
+    }
+
+    // The same goes for S-type suffixes backwards from the tails of their buckets. This replaces
+    // the LMS suffixes that were placed there to start with.
+    let mut tails = bucket_tails(bucket_sizes);
+    for index in (0..suffixes.len()).rev() {
+        let start = suffixes[index];
+        if start != EMPTY && start > 0 && is_s_type[start - 1] {
+            let tail = &mut tails[text[start - 1]];
+            *tail -= 1;
+            suffixes[*tail] = start - 1;
+        }
+    }
+}
+
+/// Find where each bucket starts.
+fn bucket_heads(bucket_sizes: &[usize]) -> Vec<usize> {
+    bucket_sizes
+        .iter()
+        .scan(0, |total, &size| {
+            let head = *total;
+            *total += size;
+            Some(head)
+        })
+        .collect()
+}
+
+/// Find where each bucket ends, exclusive.
+fn bucket_tails(bucket_sizes: &[usize]) -> Vec<usize> {
+    bucket_sizes
+        .iter()
+        .scan(0, |total, &size| {
+            *total += size;
+            Some(*total)
+        })
+        .collect()
+}
+
+/// Check whether the LMS substrings starting at `a` and `b` are the same, in both their values
+/// and their types.
+fn lms_substrings_equal(text: &[usize], is_s_type: &[bool], a: usize, b: usize) -> bool {
+    let last = text.len() - 1;
+    // The sentinel is unique, so nothing else can equal its substring.
+    if a == last || b == last {
+        return a == b;
+    }
+
+    let is_lms = |index: usize| is_s_type[index] && !is_s_type[index - 1];
+    for offset in 0.. {
+        let (a, b) = (a + offset, b + offset);
+        if text[a] != text[b] || is_s_type[a] != is_s_type[b] {
+            return false;
+        }
+        // The substrings end at the next LMS suffix, and the sentinel is one, so neither of these
+        // can run off the end of the text.
+        if offset > 0 && (is_lms(a) || is_lms(b)) {
+            return is_lms(a) && is_lms(b);
+        }
+    }
+
+    unreachable!("every LMS substring ends at the sentinel at the latest")
+}
+
+/// Compare the rotations of `data` that start at `a` and `b`, without building them.
+#[cfg(test)]
+fn compare_rotations(data: &[u8], mut a: usize, mut b: usize) -> Ordering {
+    let len = data.len();
+    let mut remaining = len;
+
+    while remaining > 0 {
+        // Compare as much as we can before either rotation wraps around to the start.
+        let chunk = remaining.min(len - a).min(len - b);
+        match data[a..a + chunk].cmp(&data[b..b + chunk]) {
+            Ordering::Equal => {}
+            ordering => return ordering,
+        }
+        a = (a + chunk) % len;
+        b = (b + chunk) % len;
+        remaining -= chunk;
+    }
+
+    Ordering::Equal
 }
 
 /// Generate every possible rotation of the given data.
+#[cfg(test)]
 fn all_rotations(data: &[u8]) -> Vec<Vec<u8>> {
     let mut deque: VecDeque<u8> = data.iter().copied().collect();
     let mut output = Vec::with_capacity(deque.len());
@@ -163,9 +514,81 @@ mod tests {
         assert_eq!(input.as_slice(), result);
     }
 
+    /// Decoding with the data and origin pointer from the encoder should give back the input,
+    /// wherever the original row ends up in the sort.
+    #[test]
+    fn roundtrip_origin_pointer() {
+        for input in [
+            &b"cdab"[..],
+            b"abcd",
+            b"dcba",
+            b"banana",
+            b"mississippi",
+            b"z",
+        ] {
+            let encoded = encode(input);
+            let mut output = vec![];
+
+            decode_into(
+                &encoded.data,
+                encoded.origin_pointer,
+                &mut vec![],
+                &mut output,
+            )
+            .unwrap();
+
+            assert_eq!(output, input);
+        }
+    }
+
     mod decode {
         use super::*;
 
+        /// Generate `len` pseudo-random bytes from the given seed.
+        fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+            // xorshift64* is plenty for test data.
+            let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+            (0..len)
+                .map(|_| {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+                })
+                .collect()
+        }
+
+        /// The inverse must hold for arbitrary binary data, including 0x00 and 0xff.
+        #[test]
+        fn random_binary() {
+            for seed in 0..100 {
+                let input = random_bytes(seed, 1_000);
+
+                let decoded = decode(&encode(&input)).unwrap();
+
+                assert_eq!(decoded, input, "seed {seed}");
+            }
+        }
+
+        /// The smallest and largest byte values sort first and last, which exercises both ends of
+        /// the histogram.
+        #[test]
+        fn extreme_bytes() {
+            // The rotations of [0xff, 0x00, 0xff, 0x01] sorted are:
+            // 0: 00 ff 01 ff
+            // 1: 01 ff 00 ff
+            // 2: ff 00 ff 01 <-- the original
+            // 3: ff 01 ff 00
+            let input = BwtEncoded {
+                data: vec![0xff, 0xff, 0x01, 0x00],
+                origin_pointer: 2.into(),
+            };
+
+            let decoded = decode(&input).unwrap();
+
+            assert_eq!(decoded, [0xff, 0x00, 0xff, 0x01]);
+        }
+
         /// This tests the example from
         /// <https://en.wikipedia.org/wiki/Burrows%E2%80%93Wheeler_transform>.
         #[test]
@@ -197,6 +620,86 @@ mod tests {
             assert_eq!(decoded, b"cdab");
         }
 
+        /// Reused buffers should not leak data from a previous block.
+        #[test]
+        fn reused_buffers() {
+            let mut permutation = vec![];
+            let mut output = vec![];
+
+            decode_into(b"BNN^AAsA", 6.into(), &mut permutation, &mut output).unwrap();
+            assert_eq!(output, b"^BANANAs");
+
+            decode_into(b"dabc", 2.into(), &mut permutation, &mut output).unwrap();
+            assert_eq!(output, b"cdab");
+        }
+
+        /// A periodic block has a cycle as long as its period, which is still valid.
+        #[test]
+        fn periodic() {
+            let input = b"abcabcabcabc";
+
+            let decoded = decode(&encode(input)).unwrap();
+
+            assert_eq!(decoded, input);
+        }
+
+        /// The origin pointer comes from the archive, so one that is past the end of an otherwise
+        /// valid block should be an error rather than a panic.
+        #[test]
+        fn origin_pointer_past_end() {
+            let input = BwtEncoded {
+                data: b"dabc".to_vec(),
+                origin_pointer: 4.into(),
+            };
+
+            let decoded = decode(&input);
+
+            assert!(matches!(decoded, Err(DecodeError::InvalidOriginPointer)));
+        }
+
+        /// A cycle through the origin whose length doesn't divide the block length is corrupt.
+        #[test]
+        fn short_cycle() {
+            // The transform vector for this is [1, 0, 2], so the origin is on a cycle of length 2.
+            let input = BwtEncoded {
+                data: b"bab".to_vec(),
+                origin_pointer: 0.into(),
+            };
+
+            let decoded = decode(&input);
+
+            assert!(matches!(decoded, Err(DecodeError::BrokenCycle)));
+        }
+
+        /// A cycle whose length does divide the block length, but that misses some bytes, is
+        /// corrupt too.
+        #[test]
+        fn output_not_a_permutation() {
+            // The transform vector for this is the identity, so this would decode to "aaa".
+            let input = BwtEncoded {
+                data: b"aab".to_vec(),
+                origin_pointer: 0.into(),
+            };
+
+            let decoded = decode(&input);
+
+            assert!(matches!(decoded, Err(DecodeError::BrokenCycle)));
+        }
+
+        /// Decoding takes a single counting pass and a walk of the transform vector, so even a large
+        /// block should be quick.
+        #[test]
+        fn large_block() {
+            let input = random_bytes(7, 500_000);
+            let encoded = encode(&input);
+            let start = std::time::Instant::now();
+
+            let decoded = decode(&encoded).unwrap();
+
+            assert!(start.elapsed() < std::time::Duration::from_secs(1));
+            assert_eq!(decoded, input);
+        }
+
         /// Test with empty data.
         #[test]
         fn empty() {
@@ -219,6 +722,81 @@ mod tests {
             assert_eq!(origin_pointer, 2);
         }
 
+        /// Every rotation of a block of identical bytes is the same, so the origin pointer should be
+        /// the first of them.
+        #[test]
+        fn all_identical() {
+            let input = [b'q'; 1000];
+
+            let encoded = encode(&input);
+
+            assert_eq!(encoded.data, input);
+            let origin_pointer: usize = encoded.origin_pointer.try_into().unwrap();
+            assert_eq!(origin_pointer, 0);
+            assert_eq!(decode(&encoded).unwrap(), input);
+        }
+
+        /// Periodic data has groups of equal rotations too.
+        #[test]
+        fn periodic() {
+            let input = b"abcabcabc";
+
+            let encoded = encode(input);
+
+            assert_eq!(encoded.data, b"cccaaabbb");
+            let origin_pointer: usize = encoded.origin_pointer.try_into().unwrap();
+            assert_eq!(origin_pointer, 0);
+            assert_eq!(decode(&encoded).unwrap(), input);
+        }
+
+        /// The suffix array sort should agree with sorting the rotations one by one, including
+        /// for data with lots of equal rotations.
+        #[test]
+        fn matches_comparison_sort() {
+            let mut state = 0x9e37_79b9_u32;
+            let mut noise = |len: usize, values: u32| -> Vec<u8> {
+                (0..len)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        ((state >> 16) % values) as u8
+                    })
+                    .collect()
+            };
+            let mut inputs = vec![b"a".to_vec(), b"ba".to_vec(), b"mississippi".to_vec()];
+            inputs.extend((1..200).map(|len| noise(len, 3)));
+            inputs.extend((1..50).map(|len| noise(len, 256)));
+            inputs.push(b"abaabaabaaba".to_vec());
+            inputs.push(b"xyzxy".repeat(40));
+
+            // The same buffers for every input, to check that nothing is left over between them.
+            let (mut text, mut rotations) = (vec![], vec![]);
+            for input in inputs {
+                let mut expected: Vec<usize> = (0..input.len()).collect();
+                expected.sort_by(|&a, &b| compare_rotations(&input, a, b));
+
+                sorted_rotations(&input, &mut text, &mut rotations);
+
+                assert_eq!(rotations, expected, "{input:?}");
+            }
+        }
+
+        /// Highly repetitive data should encode quickly, which it didn't when the rotations were
+        /// compared one by one.
+        #[test]
+        fn long_repetitive() {
+            let runs = vec![b'a'; 200_000];
+            let pairs = b"ab".repeat(100_000);
+            let almost: Vec<u8> = (0..200_000_u32)
+                .map(|i| if i == 123_456 { b'b' } else { b'a' })
+                .collect();
+
+            for input in [runs, pairs, almost] {
+                let encoded = encode(&input);
+
+                assert_eq!(decode(&encoded).unwrap(), input);
+            }
+        }
+
         #[test]
         fn empty() {
             let encoded = encode(&[]);
@@ -227,6 +805,43 @@ mod tests {
         }
     }
 
+    mod compare_rotations {
+        use super::*;
+
+        /// Sorting the rotations by index should put them in the same order as sorting the
+        /// rotations themselves.
+        #[test]
+        fn matches_all_rotations() {
+            for input in [
+                &b"banana"[..],
+                b"abcabcabc",
+                b"aaaa",
+                b"\xff\x00\xff\x01",
+                b"z",
+            ] {
+                let mut expected = all_rotations(input);
+                expected.sort();
+
+                let mut rotations: Vec<usize> = (0..input.len()).collect();
+                rotations.sort_by(|&a, &b| compare_rotations(input, a, b));
+
+                let rotations: Vec<Vec<u8>> = rotations
+                    .into_iter()
+                    .map(|start| [&input[start..], &input[..start]].concat())
+                    .collect();
+                assert_eq!(rotations, expected);
+            }
+        }
+
+        /// Equal rotations of periodic data should compare equal.
+        #[test]
+        fn periodic() {
+            assert_eq!(compare_rotations(b"abab", 0, 2), Ordering::Equal);
+            assert_eq!(compare_rotations(b"abab", 0, 1), Ordering::Less);
+            assert_eq!(compare_rotations(b"abab", 3, 2), Ordering::Greater);
+        }
+    }
+
     mod all_rotations {
         use super::*;
 
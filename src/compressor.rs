@@ -0,0 +1,271 @@
+//! Compress data as it is written, one block at a time.
+
+use std::io::{self, Write};
+
+use crate::{
+    file_format::{self, bitstream::BitWriter, StreamCrc},
+    rle1, CompressOptions, EncodeScratch,
+};
+
+/// A writer that compresses everything written to it into `output`.
+///
+/// Only the block that is being filled is kept in memory, so this can compress far more data than
+/// would fit in memory at once. Each block is compressed and written out as soon as it is full.
+/// Since this is a [`Write`], a reader can be piped into it with [`io::copy`].
+/// Call [`Compressor::finish`] once all the data has been written, to write out the last block and
+/// the end of the stream.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::{Read, Write};
+///
+/// use beeziptoo::{decompress, CompressOptions, Compressor};
+///
+/// let mut compressor = Compressor::new(vec![], CompressOptions::default());
+/// compressor.write_all(b"If Peter Piper picked a peck").unwrap();
+/// compressor.write_all(b" of pickled peppers").unwrap();
+/// let compressed = compressor.finish().unwrap();
+///
+/// let mut buffer = vec![];
+/// decompress(&compressed[..])
+///     .unwrap()
+///     .read_to_end(&mut buffer)
+///     .unwrap();
+/// assert_eq!(buffer, b"If Peter Piper picked a peck of pickled peppers");
+/// ```
+#[derive(Debug)]
+pub struct Compressor<W>
+where
+    W: Write,
+{
+    output: BitWriter<W>,
+    options: CompressOptions,
+    rle1: rle1::BlockEncoder,
+    scratch: EncodeScratch,
+    stream_crc: StreamCrc,
+    /// How many blocks have been written so far. The stream header is written with the first.
+    blocks_written: u32,
+}
+
+impl<W> Compressor<W>
+where
+    W: Write,
+{
+    /// Start compressing into `output`.
+    pub fn new(output: W, options: CompressOptions) -> Self {
+        Compressor {
+            output: BitWriter::new(output),
+            options,
+            rle1: rle1::BlockEncoder::new(options.max_block_len()),
+            scratch: EncodeScratch::default(),
+            stream_crc: StreamCrc::default(),
+            blocks_written: 0,
+        }
+    }
+
+    /// Write out the last block and the end of the stream, and return the underlying writer.
+    ///
+    /// # Errors
+    ///
+    /// If writing to the underlying writer fails.
+    pub fn finish(self) -> io::Result<W> {
+        let (output, _blocks_written) = self.finish_with_block_count()?;
+
+        Ok(output)
+    }
+
+    /// Get a reference to the underlying writer.
+    ///
+    /// Only the blocks that are full have been written to it so far.
+    pub(crate) fn get_ref(&self) -> &W {
+        self.output.get_ref()
+    }
+
+    /// Like [`Compressor::finish`], but also return how many blocks were written.
+    pub(crate) fn finish_with_block_count(self) -> io::Result<(W, u32)> {
+        let Compressor {
+            mut output,
+            options,
+            rle1,
+            mut scratch,
+            mut stream_crc,
+            mut blocks_written,
+        } = self;
+
+        rle1.finish(|rle_data, crc| {
+            write_block(
+                &mut output,
+                options,
+                &mut scratch,
+                &mut stream_crc,
+                &mut blocks_written,
+                &rle_data,
+                crc,
+            )
+        })?;
+        if blocks_written == 0 {
+            file_format::write_stream_header(&mut output, options.block_size.level())?;
+        }
+        file_format::write_stream_footer(&mut output, stream_crc)?;
+
+        Ok((output.into_inner()?, blocks_written))
+    }
+}
+
+impl<W> Write for Compressor<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Compressor {
+            output,
+            options,
+            rle1,
+            scratch,
+            stream_crc,
+            blocks_written,
+        } = self;
+
+        rle1.write(buf, |rle_data, crc| {
+            write_block(
+                output,
+                *options,
+                scratch,
+                stream_crc,
+                blocks_written,
+                &rle_data,
+                crc,
+            )
+        })?;
+
+        Ok(buf.len())
+    }
+
+    /// Flush the underlying writer.
+    ///
+    /// The block that is being filled is only written out once it is full, or by
+    /// [`Compressor::finish`], so this doesn't make everything written so far decompressible.
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.get_mut().flush()
+    }
+}
+
+/// Compress a full block and write it to `output`, starting the stream first if need be.
+fn write_block<W>(
+    output: &mut BitWriter<W>,
+    options: CompressOptions,
+    scratch: &mut EncodeScratch,
+    stream_crc: &mut StreamCrc,
+    blocks_written: &mut u32,
+    rle_data: &[u8],
+    crc: u32,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    if *blocks_written == 0 {
+        file_format::write_stream_header(output, options.block_size.level())?;
+    }
+
+    let (block, _sizes) = scratch.encode_block(rle_data, crc, options.single_table);
+    file_format::write_block(output, &block)?;
+    stream_crc.combine(crc);
+    *blocks_written += 1;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::{decompress, BlockSize};
+
+    fn decompress_all(compressed: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![];
+        decompress(compressed)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap();
+        buffer
+    }
+
+    /// Feeding the data in small chunks should compress it into many blocks, which decompress back
+    /// to the data.
+    #[test]
+    fn chunks() {
+        // Only 16 different bytes, so that the blocks sort quickly but are still full.
+        let data: Vec<u8> = (0..5_000_000_u32)
+            .map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8)
+            .collect();
+        let options = CompressOptions {
+            block_size: BlockSize::K100,
+            ..Default::default()
+        };
+        let mut compressor = Compressor::new(vec![], options);
+
+        for chunk in data.chunks(4096) {
+            compressor.write_all(chunk).unwrap();
+        }
+        let compressed = compressor.finish().unwrap();
+
+        assert_eq!(crate::block_bit_ranges(&compressed[..]).unwrap().len(), 51);
+        assert_eq!(decompress_all(&compressed), data);
+    }
+
+    /// The output should not depend on how the data was split into writes.
+    #[test]
+    fn same_as_compress_with() {
+        let data: Vec<u8> = (0..10_000_u32)
+            .map(|i| (i % 251) as u8 ^ (i / 300) as u8)
+            .collect();
+        let options = CompressOptions::default();
+        let mut expected = vec![];
+        crate::compress_with(&data[..], options)
+            .unwrap()
+            .read_to_end(&mut expected)
+            .unwrap();
+        let mut compressor = Compressor::new(vec![], options);
+
+        for chunk in data.chunks(7) {
+            compressor.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(compressor.finish().unwrap(), expected);
+    }
+
+    /// A reader should be able to be piped straight into a `Compressor` with `io::copy`.
+    #[test]
+    fn io_copy() {
+        let data: Vec<u8> = (0..300_000_u32)
+            .map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8)
+            .collect();
+        let mut compressor = Compressor::new(
+            vec![],
+            CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            },
+        );
+
+        let copied = io::copy(&mut io::Cursor::new(&data), &mut compressor).unwrap();
+        let compressed = compressor.finish().unwrap();
+
+        assert_eq!(copied, 300_000);
+        assert_eq!(crate::block_bit_ranges(&compressed[..]).unwrap().len(), 4);
+        assert_eq!(decompress_all(&compressed), data);
+    }
+
+    /// Writing nothing should give the canonical empty archive.
+    #[test]
+    fn empty() {
+        let compressor = Compressor::new(vec![], CompressOptions::default());
+
+        assert_eq!(
+            compressor.finish().unwrap(),
+            b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! The CRC-32 that bzip2 uses to check each block.
+//!
+//! This uses the same polynomial as the CRC-32 in zlib and PNG, but bzip2 feeds each byte in most
+//! significant bit first instead of reflecting it, so the two give different values for the same
+//! data. It is the variant some references call CRC-32/BZIP2.
+
+/// The CRC-32 generator polynomial, without its implied `x^32` term.
+const POLYNOMIAL: u32 = 0x04c1_1db7;
+
+/// The CRC of every single byte value, so that the checksum can work a byte at a time.
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = (byte as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ POLYNOMIAL
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+
+    table
+}
+
+/// A CRC that is computed over data as it arrives.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc(u32);
+
+impl Default for Crc {
+    fn default() -> Self {
+        Crc(u32::MAX)
+    }
+}
+
+impl Crc {
+    /// Add `data` to the CRC.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.0 = data.iter().fold(self.0, |crc, &byte| {
+            (crc << 8) ^ TABLE[((crc >> 24) as u8 ^ byte) as usize]
+        });
+    }
+
+    /// The CRC of all the data so far.
+    pub(crate) fn value(self) -> u32 {
+        !self.0
+    }
+}
+
+/// Compute the CRC of `data`, as bzip2 stores it in a block header.
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc::default();
+    crc.update(data);
+
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nothing in, nothing changed: the initial value and the final inversion cancel out.
+    #[test]
+    fn empty() {
+        assert_eq!(checksum(b""), 0);
+    }
+
+    /// The standard check value for CRC-32/BZIP2.
+    #[test]
+    fn check_value() {
+        assert_eq!(checksum(b"123456789"), 0xfc89_1918);
+    }
+
+    /// Adding the data in pieces should give the same CRC as all at once.
+    #[test]
+    fn pieces() {
+        let mut crc = Crc::default();
+
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+
+        assert_eq!(crc.value(), checksum(b"123456789"));
+    }
+
+    /// This is the block CRC that `bzip2` writes for this input.
+    #[test]
+    fn peter_piper() {
+        assert_eq!(
+            checksum(b"If Peter Piper picked a peck of pickled peppers"),
+            0x5a0f_6f58
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Decompress data as it is read, one block at a time.
+
+use std::io::{self, Read};
+
+use crate::{
+    file_format::{StreamCrc, StreamItem, StreamReader},
+    DecodeScratch, DecompressError,
+};
+
+/// A reader that decompresses the `bzip2` stream in an underlying reader.
+///
+/// Only the block that is being read out is kept in memory, so this can decompress far more data
+/// than would fit in memory at once. Each block is decoded, and its CRC checked, when the previous
+/// one has been read to the end, so none of a corrupt block's bytes are ever read out.
+/// Concatenated streams are read one after the other, as they are by
+/// [`decompress`](crate::decompress). Errors part of the way through the stream are returned from
+/// [`Read::read`] as [`io::ErrorKind::InvalidData`] errors that wrap a [`DecompressError`].
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Read;
+///
+/// use beeziptoo::{compress, Decompressor};
+///
+/// let mut compressed = vec![];
+/// compress(&b"If Peter Piper picked a peck of pickled peppers"[..])
+///     .unwrap()
+///     .read_to_end(&mut compressed)
+///     .unwrap();
+///
+/// let mut buffer = vec![];
+/// Decompressor::new(&compressed[..])
+///     .unwrap()
+///     .read_to_end(&mut buffer)
+///     .unwrap();
+/// assert_eq!(buffer, b"If Peter Piper picked a peck of pickled peppers");
+/// ```
+pub struct Decompressor<R> {
+    stream: StreamReader<R>,
+    scratch: DecodeScratch,
+    /// How much of the current block has been read out already.
+    position: usize,
+    /// The CRCs of the blocks so far in the current stream, combined.
+    stream_crc: StreamCrc,
+}
+
+impl<R> Decompressor<R>
+where
+    R: Read,
+{
+    /// Start decompressing the stream in `data`.
+    ///
+    /// # Errors
+    ///
+    /// If `data` doesn't start with a valid `bzip2` stream header.
+    pub fn new(data: R) -> Result<Self, DecompressError> {
+        Ok(Decompressor {
+            stream: StreamReader::new(data)?,
+            scratch: DecodeScratch::default(),
+            position: 0,
+            stream_crc: StreamCrc::default(),
+        })
+    }
+
+    /// Decode the next block into the scratch buffers, returning `false` at the end of the input.
+    ///
+    /// The combined CRC of each stream's blocks is checked when its footer is reached.
+    fn next_block(&mut self) -> Result<bool, DecompressError> {
+        loop {
+            match self.stream.next_item()? {
+                Some(StreamItem::Block(block)) => {
+                    self.scratch.decode_block(&block)?;
+                    self.stream_crc.combine(block.crc());
+                    self.position = 0;
+
+                    return Ok(true);
+                }
+                Some(StreamItem::Footer(expected)) => {
+                    if expected != self.stream_crc {
+                        return Err(DecompressError::StreamChecksumMismatch {
+                            expected: expected.value(),
+                            found: self.stream_crc.value(),
+                        });
+                    }
+                    self.stream_crc = StreamCrc::default();
+                }
+                None => return Ok(false),
+            }
+        }
+    }
+}
+
+impl<R> Read for Decompressor<R>
+where
+    R: Read,
+{
+    /// Read decompressed bytes into `buf`.
+    ///
+    /// This never reads past the end of the current block, so it may fill less of `buf` than it
+    /// could have.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.position == self.scratch.block.len() {
+            if !self.next_block().map_err(into_io_error)? {
+                return Ok(0);
+            }
+        }
+
+        let remaining = &self.scratch.block[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+/// Pass I/O errors from the underlying reader through as they are, and wrap everything else.
+fn into_io_error(error: DecompressError) -> io::Error {
+    match error {
+        DecompressError::IOError(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+/// Undo [`into_io_error`], unwrapping the errors that it wrapped.
+pub(crate) fn from_io_error(error: io::Error) -> DecompressError {
+    if error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<DecompressError>())
+    {
+        let inner = error
+            .into_inner()
+            .expect("The error was checked to wrap another");
+        return *inner
+            .downcast()
+            .expect("The error was checked to wrap a DecompressError");
+    }
+
+    DecompressError::IOError(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{compress_with, BlockSize, CompressOptions};
+
+    fn compress_k100(data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        compress_with(
+            data,
+            CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .read_to_end(&mut compressed)
+        .unwrap();
+        compressed
+    }
+
+    /// Reading 512 bytes at a time should give back the data, across every block boundary.
+    #[test]
+    fn multi_block() {
+        // Only 16 different bytes, so that the blocks sort quickly but are still full.
+        let data: Vec<u8> = (0..350_000_u32)
+            .map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8)
+            .collect();
+        let compressed = compress_k100(&data);
+        assert_eq!(crate::block_bit_ranges(&compressed[..]).unwrap().len(), 4);
+        let mut decompressor = Decompressor::new(Cursor::new(compressed)).unwrap();
+        let mut decompressed = vec![];
+        let mut buffer = [0; 512];
+
+        loop {
+            let len = decompressor.read(&mut buffer).unwrap();
+            if len == 0 {
+                break;
+            }
+            decompressed.extend_from_slice(&buffer[..len]);
+        }
+
+        assert_eq!(decompressed, data);
+    }
+
+    /// `read_exact` should fill a buffer that spans two blocks.
+    #[test]
+    fn spans_blocks() {
+        let data: Vec<u8> = (0..150_000_u32)
+            .map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8)
+            .collect();
+        let compressed = compress_k100(&data);
+        let mut decompressor = Decompressor::new(&compressed[..]).unwrap();
+        let mut first = vec![0; 99_000];
+        let mut spanning = vec![0; 2_000];
+
+        decompressor.read_exact(&mut first).unwrap();
+        decompressor.read_exact(&mut spanning).unwrap();
+
+        assert_eq!(first, data[..99_000]);
+        assert_eq!(spanning, data[99_000..101_000]);
+    }
+
+    /// The empty archive should read as nothing.
+    #[test]
+    fn empty() {
+        let mut decompressor =
+            Decompressor::new(&b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00"[..]).unwrap();
+
+        assert_eq!(decompressor.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    /// A bad stream CRC should only be found once every block has been read.
+    #[test]
+    fn stream_checksum_mismatch() {
+        let mut compressed = compress_k100(b"If Peter Piper picked a peck of pickled peppers");
+        let len = compressed.len();
+        // The last byte is padding plus the end of the stream CRC.
+        compressed[len - 2] ^= 0x01;
+        let mut decompressor = Decompressor::new(&compressed[..]).unwrap();
+        let mut buffer = [0; 512];
+
+        assert_eq!(decompressor.read(&mut buffer).unwrap(), 47);
+        let error = decompressor.read(&mut buffer).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            error
+                .into_inner()
+                .unwrap()
+                .downcast::<DecompressError>()
+                .as_deref(),
+            Ok(DecompressError::StreamChecksumMismatch { .. })
+        ));
+    }
+
+    /// Nothing from a block that fails its CRC should be read out, though the blocks before it
+    /// are.
+    #[test]
+    fn corrupt_block() {
+        let data: Vec<u8> = (0..150_000_u32)
+            .map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8)
+            .collect();
+        let compressed = compress_k100(&data);
+        let mut first_block = vec![];
+        crate::decompress_each_block(&compressed[..], |index, block| {
+            if index == 0 {
+                first_block.extend_from_slice(block);
+            }
+        })
+        .unwrap();
+        let mut corrupted = compressed.clone();
+        let start = crate::block_bit_ranges(&compressed[..]).unwrap()[1].0;
+        // The block CRC comes straight after the 48 bit block magic.
+        let bit = start + 48;
+        corrupted[(bit / 8) as usize] ^= 0x80 >> (bit % 8);
+        let mut buffer = vec![];
+
+        let error = Decompressor::new(&corrupted[..])
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap_err();
+
+        assert!(matches!(
+            error
+                .into_inner()
+                .unwrap()
+                .downcast::<DecompressError>()
+                .as_deref(),
+            Ok(DecompressError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(buffer, first_block);
+    }
+
+    /// Anything after the end of the stream should be an error once the last block is read.
+    #[test]
+    fn trailing_data() {
+        let mut compressed = compress_k100(b"If Peter Piper picked a peck of pickled peppers");
+        compressed.extend_from_slice(b"junk");
+        let mut decompressor = Decompressor::new(&compressed[..]).unwrap();
+        let mut buffer = [0; 512];
+
+        assert_eq!(decompressor.read(&mut buffer).unwrap(), 47);
+        let error = decompressor.read(&mut buffer).unwrap_err();
+
+        assert!(matches!(
+            error
+                .into_inner()
+                .unwrap()
+                .downcast::<DecompressError>()
+                .as_deref(),
+            Ok(DecompressError::TrailingData)
+        ));
+    }
+
+    /// Concatenated streams should be read one after the other.
+    #[test]
+    fn concatenated() {
+        let input = [
+            compress_k100(b"foo"),
+            compress_k100(b""),
+            compress_k100(b"bar"),
+        ]
+        .concat();
+        let mut decompressed = vec![];
+
+        Decompressor::new(&input[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(decompressed, b"foobar");
+    }
+
+    /// A missing header should be reported straight away.
+    #[test]
+    fn missing_magic() {
+        assert!(matches!(
+            Decompressor::new(&b"not bzip2"[..]),
+            Err(DecompressError::MissingMagic)
+        ));
+    }
+}
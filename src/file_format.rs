@@ -5,17 +5,19 @@
 #![allow(unused)]
 
 use std::{
-    io::{self, ErrorKind, Read},
-    num::TryFromIntError,
+    io::{self, ErrorKind, Read, Write},
+    ops::Range,
 };
 
 use bitstream::Bit;
 
-use self::bitstream::Bitstream;
+use self::bitstream::{BitWriter, Bitstream};
 use crate::huffman::{self, tree::Tree, HuffmanCodedData, Symbol};
 
 pub(crate) mod bitstream;
 
+pub(crate) use crate::{burrows_wheeler::OriginPointer, move_to_front::SymbolStack};
+
 /// Errors that can occur when decoding bzip2 streams.
 #[derive(Debug, thiserror::Error)]
 pub enum DecodeError {
@@ -43,13 +45,6 @@ pub enum DecodeError {
     #[error("The block header should be BCD-coded pi.")]
     InvalidBlockHeader,
 
-    /// Invalid `randomized` bit.
-    ///
-    /// The `Randomized` field is a single bit, and in bzip2 should always be 0. This error means
-    /// it was 1, which is unexpected.
-    #[error("The Randomized field should be 0, but was 1.")]
-    InvalidRandomizedField,
-
     /// Invalid `Selector`.
     #[error("The selector should be a zero-terminated string of length at most 6")]
     InvalidSelector,
@@ -57,6 +52,21 @@ pub enum DecodeError {
     /// Invalid `Tree`.
     #[error("The tree data structure could not be deserialized")]
     InvalidTree,
+
+    /// The block's selectors ran out before its end-of-block symbol.
+    #[error("The block has too few selectors for its symbols")]
+    TooFewSelectors,
+
+    /// The stream uses the deprecated `bzip` format.
+    ///
+    /// The original `bzip` program wrote the header `BZ0`, and used arithmetic coding instead of
+    /// Huffman coding. We do not support that format.
+    #[error("The header is BZ0, which is the unsupported legacy bzip format.")]
+    UnsupportedFormat,
+
+    /// There is data after the end of the stream.
+    #[error("There is data after the end of the stream.")]
+    TrailingData,
 }
 
 impl DecodeError {
@@ -93,10 +103,10 @@ where
     // TODO: Should this return an iterator over blocks instead of a `Vec<StreamBlock>`.
     //
     // This is one of the things that precludes us from streaming the file.
-    fn blocks(&mut self) -> Result<Vec<StreamBlock>, DecodeError> {
+    fn blocks(&mut self, level: Level) -> Result<Vec<StreamBlock>, DecodeError> {
         let mut blocks = vec![];
 
-        while let Some(block) = self.next_block()? {
+        while let Some(block) = self.next_block(level)? {
             blocks.push(block);
         }
 
@@ -104,26 +114,17 @@ where
     }
 
     fn parse(mut self) -> Result<BZipFile, DecodeError> {
-        let stream = self.stream()?;
-
-        for _ in 0..7 {
-            match self.bitstream.get_next_bit() {
-                Ok(Bit::Zero) => continue,
-                Ok(Bit::One) => return Err(DecodeError::InvalidData),
-                Err(_) => break,
-            }
+        let mut streams = vec![self.stream()?];
+        while self.another_stream()? {
+            streams.push(self.stream()?);
         }
-        debug_assert!(
-            self.bitstream.is_empty()?,
-            "The parser failed to read the entire stream."
-        );
 
-        Ok(BZipFile { stream })
+        Ok(BZipFile { streams })
     }
 
     fn stream(&mut self) -> Result<BZipStream, DecodeError> {
         let header = self.stream_header()?;
-        let blocks = self.blocks()?;
+        let blocks = self.blocks(header.level)?;
         let footer = self.stream_footer()?;
 
         Ok(BZipStream {
@@ -133,11 +134,29 @@ where
         })
     }
 
+    /// After a stream footer and its padding, find whether another stream follows.
+    ///
+    /// Several streams can be concatenated into one file, so the input may go on to the header of
+    /// another stream, but anything else after the footer is an error.
+    fn another_stream(&mut self) -> Result<bool, DecodeError> {
+        if self.bitstream.is_empty()? {
+            return Ok(false);
+        }
+
+        match self.bitstream.peek_integer::<u32>(24) {
+            Ok(0x425a68) => Ok(true),
+            Ok(_) => Err(DecodeError::TrailingData),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                Err(DecodeError::TrailingData)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
     /// Read the stream footer
     fn stream_footer(&mut self) -> Result<StreamFooter, DecodeError> {
         Ok(StreamFooter {
             magic: self.footer_magic()?,
-            // TODO: Check the crc
             crc: self.stream_crc()?,
             padding: self.footer_padding()?,
         })
@@ -185,6 +204,9 @@ where
 
     fn version(&mut self) -> Result<Version, DecodeError> {
         let version: u8 = self.bitstream.get_integer(8)?;
+        if version == b'0' {
+            return Err(DecodeError::UnsupportedFormat);
+        }
         if version != b'h' {
             return Err(DecodeError::InvalidHeader);
         }
@@ -201,21 +223,26 @@ where
         Ok(Level(level - b'1' + 1))
     }
 
-    fn next_block(&mut self) -> Result<Option<StreamBlock>, DecodeError> {
+    /// Read the next block of a stream whose header declared `level`, or `None` at the footer.
+    fn next_block(&mut self, level: Level) -> Result<Option<StreamBlock>, DecodeError> {
         let maybe_magic: u64 = self.bitstream.peek_integer(48)?;
 
         if maybe_magic != 0x314159265359 {
             return Ok(None);
         }
 
+        let start = self.bitstream.position();
         let header = self.block_header()?;
         let trees = self.block_trees()?;
         let data = self.block_data(&trees)?;
+        let end = self.bitstream.position();
 
         Ok(Some(StreamBlock {
             header,
             trees,
             data,
+            level,
+            bit_range: start..end,
         }))
     }
 
@@ -225,10 +252,6 @@ where
         let randomized = self.bitstream.get_integer(1)?;
         let origin_pointer = self.bitstream.get_integer(24)?;
 
-        if randomized != 0 {
-            return Err(DecodeError::InvalidRandomizedField);
-        }
-
         Ok(BlockHeader {
             magic: BlockMagic(magic),
             crc: BlockCrc(crc),
@@ -238,36 +261,33 @@ where
     }
 
     fn symbol_map(&mut self) -> Result<SymbolMap, DecodeError> {
-        let l1: u16 = self.bitstream.get_integer(16)?;
-        let mut l2 = vec![];
-
-        {
-            let mut l1 = l1.reverse_bits();
-            while l1 != 0 {
-                if !l1.is_multiple_of(2) {
-                    l2.push(self.bitstream.get_integer(16)?);
-                }
-                l1 >>= 1;
-            }
-        }
-
-        Ok(SymbolMap { l1, l2 })
+        read_used_map(&mut self.bitstream)
     }
 
     /// Parse a single tree.
-    // TODO: Test this
+    ///
+    /// Like libbzip2, this rejects a code length outside `1..=20` as soon as it appears, rather
+    /// than once the lengths are all read, so that a corrupt run of deltas can't overflow.
     fn tree(&mut self, num_symbols: u16) -> Result<Tree, DecodeError> {
+        let check = |length| {
+            if (1..=huffman::MAX_CODE_LENGTH).contains(&length) {
+                Ok(length)
+            } else {
+                Err(DecodeError::InvalidTree)
+            }
+        };
+
         let mut bit_lengths = vec![];
-        let mut initial_bit_length: u8 = self.bitstream.get_integer(5)?;
+        let mut initial_bit_length: u8 = check(self.bitstream.get_integer(5)?)?;
 
         for _ in 0..num_symbols {
             while self.bitstream.peek_integer::<u8>(1)? == 1 {
                 let delta: u8 = self.bitstream.get_integer(2)?;
-                match delta {
-                    2 => initial_bit_length += 1,
-                    3 => initial_bit_length -= 1,
+                initial_bit_length = match delta {
+                    2 => check(initial_bit_length + 1)?,
+                    3 => check(initial_bit_length - 1)?,
                     _ => unreachable!(),
-                }
+                };
             }
 
             let terminator: u8 = self.bitstream.get_integer(1)?;
@@ -282,28 +302,6 @@ where
         Ok(bit_lengths.try_into()?)
     }
 
-    fn selector(&mut self) -> Result<Selector, DecodeError> {
-        const MAX_SELECTOR_BITS: u8 = 6;
-
-        let mut bits = vec![];
-
-        for i in 0..MAX_SELECTOR_BITS {
-            let bit = self.bitstream.get_next_bit()?;
-
-            if bit == Bit::Zero {
-                break;
-            }
-
-            if i == MAX_SELECTOR_BITS - 1 {
-                return Err(DecodeError::InvalidSelector);
-            }
-
-            bits.push(bit);
-        }
-
-        Ok(Selector(bits.len() as u8))
-    }
-
     fn block_trees(&mut self) -> Result<BlockTrees, DecodeError> {
         let sym_map = self.symbol_map()?;
         let num_symbols = sym_map.num_symbols();
@@ -311,17 +309,7 @@ where
         let num_trees: u8 = self.bitstream.get_integer(3)?;
         let num_selectors: u16 = self.bitstream.get_integer(15)?;
 
-        let mut pre_mtf_selectors = vec![];
-        for _ in 0..num_selectors {
-            pre_mtf_selectors.push(self.selector()?);
-        }
-
-        // TODONEXT: Fix the types here, they're strange.
-        // MTF decode
-        let bytes: Vec<u8> = pre_mtf_selectors.into_iter().map(|s| s.0).collect();
-        let symbol_stack: Vec<u8> = (0..num_trees).collect();
-        let selectors = crate::move_to_front::decode(&bytes, SymbolStack(symbol_stack));
-        let selectors: Vec<Selector> = selectors.into_iter().map(Selector).collect();
+        let selectors = read_selectors_mtf(&mut self.bitstream, num_selectors, num_trees)?;
 
         let mut trees = vec![];
         for _ in 0..num_trees {
@@ -346,7 +334,7 @@ where
                 .get(selector.0 as usize)
                 .ok_or(DecodeError::InvalidSelector)?;
 
-            for _ in 0..50 {
+            for _ in 0..huffman::GROUP_SIZE {
                 let symbol = tree.decode(&mut self.bitstream).map_err(|err| match err {
                     huffman::Error::InvalidNodeIndex => DecodeError::InvalidData,
                     huffman::Error::Io(error) => DecodeError::IOError(error),
@@ -361,6 +349,10 @@ where
             }
         }
 
+        if symbols.last() != Some(&Symbol::Eob) {
+            return Err(DecodeError::TooFewSelectors);
+        }
+
         Ok(BlockData(symbols))
     }
 }
@@ -371,11 +363,11 @@ where
 
 #[derive(Debug)]
 struct BZipFile {
-    stream: BZipStream,
+    streams: Vec<BZipStream>,
 }
 
 #[derive(Debug)]
-struct BZipStream {
+pub(crate) struct BZipStream {
     header: StreamHeader,
     // Bring these back when we are ready for them, which the universe will reveal in time
     // (spacetime).
@@ -395,6 +387,11 @@ pub(crate) struct StreamBlock {
     header: BlockHeader,
     trees: BlockTrees,
     data: BlockData,
+    /// The block size level from the header of the stream the block is in.
+    level: Level,
+    /// Where the block sits in the stream, in bits, from the start of its magic to the end of its
+    /// end-of-block symbol.
+    bit_range: Range<u64>,
 }
 
 impl StreamBlock {
@@ -406,15 +403,41 @@ impl StreamBlock {
         self.header.orig_ptr
     }
 
+    /// The CRC of the block's decompressed data, as stored in its header.
+    pub(crate) fn crc(&self) -> u32 {
+        self.header.crc.0
+    }
+
+    /// Whether the block was randomized by an old version of bzip2, and so needs derandomizing
+    /// after the Burrows-Wheeler transform is undone.
+    pub(crate) fn randomized(&self) -> bool {
+        self.header.randomized.0 != 0
+    }
+
     pub(crate) fn symbol_stack(&self) -> SymbolStack {
         self.trees.sym_map.symbol_stack()
     }
-}
 
-/// This is used with the move to front transform.
-#[derive(Debug)]
-#[cfg_attr(test, derive(PartialEq))]
-pub(crate) struct SymbolStack(pub(crate) Vec<u8>);
+    /// Which tree each group of 50 symbols was coded with.
+    #[cfg(test)]
+    pub(crate) fn selectors(&self) -> Vec<u8> {
+        self.trees
+            .selectors
+            .iter()
+            .map(|selector| selector.0)
+            .collect()
+    }
+
+    /// The most bytes the block can hold before the first run-length decoding, from the block
+    /// size level of its stream.
+    pub(crate) fn max_len(&self) -> usize {
+        usize::from(self.level.0) * 100_000
+    }
+
+    pub(crate) fn bit_range(&self) -> Range<u64> {
+        self.bit_range.clone()
+    }
+}
 
 #[derive(Debug)]
 struct BlockHeader {
@@ -438,7 +461,7 @@ struct StreamFooter {
     padding: Padding,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 struct Level(u8);
 
 #[derive(Debug)]
@@ -450,48 +473,144 @@ struct BlockCrc(u32);
 #[derive(Debug)]
 struct Randomized(u8);
 
-#[derive(Debug, Default, Clone, Copy)]
-pub(crate) struct OriginPointer(u32);
+/// The used map, which records which bytes appear in a block.
+///
+/// The 256 possible bytes are split into 16 ranges of 16 bytes. Each bit of `l1` records whether
+/// any byte in a range is used, starting with the most significant bit. Each used range then gets
+/// an entry in `l2`, whose bits record which bytes in the range are used.
+#[derive(Debug)]
+pub(crate) struct SymbolMap {
+    l1: u16,
+    l2: Vec<u16>,
+}
 
-impl TryFrom<usize> for OriginPointer {
-    type Error = TryFromIntError;
+/// Read a used map from the bitstream.
+pub(crate) fn read_used_map<R>(bitstream: &mut Bitstream<R>) -> Result<SymbolMap, DecodeError>
+where
+    R: Read,
+{
+    let l1: u16 = bitstream.get_integer(16)?;
+    let mut l2 = vec![];
 
-    fn try_from(value: usize) -> Result<Self, Self::Error> {
-        Ok(OriginPointer(value.try_into()?))
+    {
+        let mut l1 = l1.reverse_bits();
+        while l1 != 0 {
+            if !l1.is_multiple_of(2) {
+                l2.push(bitstream.get_integer(16)?);
+            }
+            l1 >>= 1;
+        }
     }
+
+    Ok(SymbolMap { l1, l2 })
 }
 
-// TODO: `.into()` should work here since we don't think Rust supports any 16-bit
-// platforms. What gives?
-impl TryFrom<OriginPointer> for usize {
-    type Error = TryFromIntError;
+/// Write the used map that describes the bytes in `symbol_stack`.
+pub(crate) fn write_used_map<W>(
+    bit_writer: &mut BitWriter<W>,
+    symbol_stack: &SymbolStack,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let SymbolMap { l1, l2 } = SymbolMap::new(symbol_stack);
 
-    fn try_from(value: OriginPointer) -> Result<Self, Self::Error> {
-        value.0.try_into()
+    bit_writer.write_bits(l1.into(), 16)?;
+    for l2 in l2 {
+        bit_writer.write_bits(l2.into(), 16)?;
     }
+
+    Ok(())
 }
 
-impl TryFrom<&OriginPointer> for usize {
-    type Error = TryFromIntError;
+/// The most selectors a block can need.
+///
+/// Each selector covers 50 symbols, and the largest block has 900,000 bytes plus an EOB symbol,
+/// so libbzip2 allows `2 + 900_000 / 50` of them.
+const MAX_SELECTORS: usize = 18_002;
+
+/// Read `num_selectors` selectors for a block with `num_trees` trees.
+///
+/// Each selector is stored as its move-to-front transformed value `v`, written as `v` one-bits
+/// followed by a zero-bit. This reads those values and undoes the move-to-front transform.
+fn read_selectors_mtf<R>(
+    bitstream: &mut Bitstream<R>,
+    num_selectors: u16,
+    num_trees: u8,
+) -> Result<Vec<Selector>, DecodeError>
+where
+    R: Read,
+{
+    if num_selectors == 0 {
+        return Err(DecodeError::InvalidSelector);
+    }
 
-    fn try_from(value: &OriginPointer) -> Result<Self, Self::Error> {
-        value.0.try_into()
+    let mut mtf_selectors = Vec::with_capacity(num_selectors.into());
+    for _ in 0..num_selectors {
+        let mut value = 0;
+        while bitstream.get_next_bit()? == Bit::One {
+            value += 1;
+            // A selector must refer to one of the trees.
+            if value >= num_trees {
+                return Err(DecodeError::InvalidSelector);
+            }
+        }
+        mtf_selectors.push(value);
     }
+    // The field is 15 bits wide, so it can claim more selectors than any block could use. Like
+    // libbzip2, we read them all so the bitstream stays in sync, but only keep the ones that could
+    // matter.
+    mtf_selectors.truncate(MAX_SELECTORS);
+
+    let symbol_stack = SymbolStack((0..num_trees).collect());
+    // With no trees, every selector is out of range.
+    let selectors = crate::move_to_front::decode(&mtf_selectors, symbol_stack)
+        .map_err(|_| DecodeError::InvalidSelector)?;
+
+    Ok(selectors.into_iter().map(Selector).collect())
 }
 
-impl From<u32> for OriginPointer {
-    fn from(value: u32) -> Self {
-        Self(value)
+/// Write the selectors, which are the inverse of [`read_selectors_mtf`].
+fn write_selectors_mtf<W>(bit_writer: &mut BitWriter<W>, selectors: &[Selector]) -> io::Result<()>
+where
+    W: Write,
+{
+    let selectors: Vec<u8> = selectors.iter().map(|selector| selector.0).collect();
+
+    // The move-to-front encoder starts with every byte in order, so the selector values (which
+    // are all smaller than the number of trees) only ever move around at the front of the list.
+    // That gives the same result as starting with just the trees.
+    for value in crate::move_to_front::encode(&selectors) {
+        for _ in 0..value {
+            bit_writer.write_bit(Bit::One)?;
+        }
+        bit_writer.write_bit(Bit::Zero)?;
     }
-}
 
-#[derive(Debug)]
-struct SymbolMap {
-    l1: u16,
-    l2: Vec<u16>,
+    Ok(())
 }
 
 impl SymbolMap {
+    /// Build the used map that describes the bytes in `symbol_stack`.
+    fn new(SymbolStack(symbols): &SymbolStack) -> Self {
+        let mut ranges = [0_u16; 16];
+
+        for &symbol in symbols {
+            ranges[(symbol / 16) as usize] |= 0x8000 >> (symbol % 16);
+        }
+
+        let mut l1 = 0;
+        let mut l2 = vec![];
+        for (i, range) in ranges.into_iter().enumerate() {
+            if range != 0 {
+                l1 |= 0x8000 >> i;
+                l2.push(range);
+            }
+        }
+
+        Self { l1, l2 }
+    }
+
     // TODO test this
     fn num_symbols(&self) -> u16 {
         // The spec says that num_syms is num_stack + 2
@@ -543,20 +662,266 @@ struct BlockData(Vec<Symbol>);
 
 #[derive(Debug)]
 struct FooterMagic;
-#[derive(Debug)]
-struct StreamCrc(u32);
+/// The combined CRC of every block in a stream, as stored in the stream footer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct StreamCrc(u32);
+
+impl StreamCrc {
+    /// Fold the CRC of the next block into the combined CRC.
+    pub(crate) fn combine(&mut self, block_crc: u32) {
+        self.0 = self.0.rotate_left(1) ^ block_crc;
+    }
+
+    pub(crate) fn value(self) -> u32 {
+        self.0
+    }
+}
 #[derive(Debug)]
 struct Padding(Vec<bitstream::Bit>);
 
 // =============================================================================
 
+impl BZipStream {
+    pub(crate) fn blocks(&self) -> &[StreamBlock] {
+        &self.blocks
+    }
+
+    /// The combined CRC of the stream's blocks, as stored in its footer.
+    pub(crate) fn crc(&self) -> StreamCrc {
+        self.footer.crc
+    }
+}
+
+/// Decode the blocks of every stream in `bytes`, one after another.
 pub fn decode(bytes: &[u8]) -> Result<Vec<StreamBlock>, DecodeError> {
+    let streams = decode_streams(bytes)?;
+
+    Ok(streams
+        .into_iter()
+        .flat_map(|stream| stream.blocks)
+        .collect())
+}
+
+/// Decode each of the concatenated streams in `bytes`.
+pub(crate) fn decode_streams(bytes: &[u8]) -> Result<Vec<BZipStream>, DecodeError> {
     let mut stream = bitstream::Bitstream::new(bytes);
     let mut parser = Parser::new(stream);
 
     let bzip_file = parser.parse()?;
 
-    Ok(bzip_file.stream.blocks)
+    Ok(bzip_file.streams)
+}
+
+/// Read just the stream header at the start of `reader`, and return the block size level it
+/// declares, from 1 to 9.
+pub(crate) fn read_level<R>(reader: R) -> Result<u8, DecodeError>
+where
+    R: Read,
+{
+    let header = Parser::new(reader).stream_header()?;
+
+    Ok(header.level.0)
+}
+
+/// Reads concatenated streams one block at a time, so that they never need to be in memory.
+pub(crate) struct StreamReader<R> {
+    parser: Parser<R>,
+    /// The block size level from the header of the current stream.
+    level: Level,
+    state: ReaderState,
+}
+
+/// Where a [`StreamReader`] is up to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReaderState {
+    /// Part of the way through the blocks of a stream.
+    InStream,
+    /// Just after the footer of a stream, which may be followed by another stream.
+    BetweenStreams,
+    /// At the end of the input.
+    Finished,
+}
+
+/// What [`StreamReader::next_item`] found next.
+#[derive(Debug)]
+pub(crate) enum StreamItem {
+    /// A block of the current stream.
+    Block(StreamBlock),
+    /// The end of the current stream, with the combined CRC of its blocks from the footer.
+    Footer(StreamCrc),
+}
+
+impl<R> StreamReader<R>
+where
+    R: Read,
+{
+    /// Start reading the streams in `reader`, checking the header of the first.
+    pub(crate) fn new(reader: R) -> Result<Self, DecodeError> {
+        let mut parser = Parser::new(reader);
+        let header = parser.stream_header()?;
+
+        Ok(StreamReader {
+            parser,
+            level: header.level,
+            state: ReaderState::InStream,
+        })
+    }
+
+    /// Read the next block or stream footer, or `None` at the end of the input.
+    ///
+    /// After a footer, `reader` must either end or go on to another stream.
+    pub(crate) fn next_item(&mut self) -> Result<Option<StreamItem>, DecodeError> {
+        match self.state {
+            ReaderState::Finished => return Ok(None),
+            ReaderState::BetweenStreams => {
+                if !self.parser.another_stream()? {
+                    self.state = ReaderState::Finished;
+                    return Ok(None);
+                }
+                self.level = self.parser.stream_header()?.level;
+                self.state = ReaderState::InStream;
+            }
+            ReaderState::InStream => {}
+        }
+
+        match self.parser.next_block(self.level)? {
+            Some(block) => Ok(Some(StreamItem::Block(block))),
+            None => {
+                let footer = self.parser.stream_footer()?;
+                self.state = ReaderState::BetweenStreams;
+                Ok(Some(StreamItem::Footer(footer.crc)))
+            }
+        }
+    }
+}
+
+/// A block that has been through every stage of compression, ready to be written out.
+#[derive(Debug)]
+pub(crate) struct EncodedBlock {
+    /// The CRC of the data before any stage of compression.
+    pub(crate) crc: u32,
+    /// The index of the original data in the sorted rotations from the Burrows-Wheeler transform.
+    pub(crate) origin_pointer: OriginPointer,
+    /// The bytes that the move-to-front transform was done over.
+    pub(crate) symbol_stack: SymbolStack,
+    /// The Huffman coded symbols, ending with `Eob`.
+    pub(crate) huffman: HuffmanCodedData,
+}
+
+/// Write a bzip2 stream of `blocks`, with a header declaring the block size `level`.
+#[cfg(test)]
+pub(crate) fn encode(level: u8, blocks: &[EncodedBlock]) -> Vec<u8> {
+    let mut bit_writer = BitWriter::new(vec![]);
+    let mut stream_crc = StreamCrc::default();
+
+    write_stream_header(&mut bit_writer, level).expect("Writing to a Vec cannot fail");
+    for block in blocks {
+        write_block(&mut bit_writer, block).expect("Writing to a Vec cannot fail");
+        stream_crc.combine(block.crc);
+    }
+    write_stream_footer(&mut bit_writer, stream_crc).expect("Writing to a Vec cannot fail");
+
+    bit_writer
+        .into_inner()
+        .expect("Writing to a Vec cannot fail")
+}
+
+/// Write the stream header, which declares the block size `level`.
+pub(crate) fn write_stream_header<W>(bit_writer: &mut BitWriter<W>, level: u8) -> io::Result<()>
+where
+    W: Write,
+{
+    debug_assert!(
+        (1..=9).contains(&level),
+        "level must be 1-9 but was {level}"
+    );
+
+    bit_writer.write_bits(u32::from_be_bytes([b'B', b'Z', b'h', b'0' + level]), 32)
+}
+
+/// Write the stream footer, with the combined CRC of every block that was written.
+///
+/// This doesn't pad the stream out to a whole byte, which flushing the `bit_writer` does.
+pub(crate) fn write_stream_footer<W>(
+    bit_writer: &mut BitWriter<W>,
+    stream_crc: StreamCrc,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    bit_writer.write_bits(0x177245, 24)?;
+    bit_writer.write_bits(0x385090, 24)?;
+    bit_writer.write_bits(stream_crc.value(), 32)
+}
+
+/// Write a single block.
+pub(crate) fn write_block<W>(bit_writer: &mut BitWriter<W>, block: &EncodedBlock) -> io::Result<()>
+where
+    W: Write,
+{
+    let EncodedBlock {
+        crc,
+        origin_pointer: OriginPointer(origin_pointer),
+        symbol_stack,
+        huffman: HuffmanCodedData { tables, blocks },
+    } = block;
+
+    bit_writer.write_bits(0x314159, 24)?;
+    bit_writer.write_bits(0x265359, 24)?;
+    bit_writer.write_bits(*crc, 32)?;
+    // We never randomize blocks.
+    bit_writer.write_bit(Bit::Zero)?;
+    bit_writer.write_bits(*origin_pointer, 24)?;
+
+    write_used_map(bit_writer, symbol_stack)?;
+
+    // These were checked against the limits of the format when the block was encoded, so the
+    // casts are safe.
+    bit_writer.write_bits(tables.len() as u32, 3)?;
+    bit_writer.write_bits(blocks.len() as u32, 15)?;
+    let selectors: Vec<Selector> = blocks
+        .iter()
+        .map(|block| Selector(block.tree_index))
+        .collect();
+    write_selectors_mtf(bit_writer, &selectors)?;
+    for table in tables {
+        write_tree(bit_writer, table)?;
+    }
+
+    for block in blocks {
+        for bit in &block.bitvec {
+            bit_writer.write_bits((*bit).into(), 1)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the code lengths of a single tree, which is the inverse of [`Parser::tree`].
+///
+/// The first length is written as a 5-bit integer. Each length (including the first) is then
+/// given as a series of changes from the one before it, `10` to add one and `11` to subtract one,
+/// followed by a `0`.
+fn write_tree<W>(bit_writer: &mut BitWriter<W>, code_lengths: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut current = code_lengths[0];
+    bit_writer.write_bits(current.into(), 5)?;
+
+    for &length in code_lengths {
+        while current < length {
+            bit_writer.write_bits(0b10, 2)?;
+            current += 1;
+        }
+        while current > length {
+            bit_writer.write_bits(0b11, 2)?;
+            current -= 1;
+        }
+        bit_writer.write_bit(Bit::Zero)?;
+    }
+
+    Ok(())
 }
 
 /// The block size of the uncompressed data, in bytes.
@@ -605,21 +970,6 @@ fn bcd_pi(bytes: &[u8]) -> Result<&[u8], DecodeError> {
     }
 }
 
-// TODO: CRC32 needs to be validated somewhere.
-//
-// This might be a free function, or it might be a method on a hypothetical `Block` type.
-
-fn crc32(bytes: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
-    if bytes.len() < 4 {
-        return Err(DecodeError::unexpected_eof("there were fewer than 4 bytes"));
-    }
-
-    let (crc, rest) = bytes.split_at(4);
-    // TODO: Figure out if little-endian is the correct endianness.
-    let crc = u32::from_le_bytes(crc.try_into().unwrap());
-    Ok((crc, rest))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -644,9 +994,9 @@ mod tests {
 
                     let file = parser.parse().expect("This should not fail to parse");
 
-                    assert_eq!(file.stream.header.level.0, 1);
-                    assert_eq!(file.stream.blocks.len(), 0);
-                    assert_eq!(file.stream.footer.crc.0, 0);
+                    assert_eq!(file.streams[0].header.level.0, 1);
+                    assert_eq!(file.streams[0].blocks.len(), 0);
+                    assert_eq!(file.streams[0].footer.crc.0, 0);
                 }
 
                 /// Test with a level 9 compressed file.
@@ -657,11 +1007,142 @@ mod tests {
 
                     let file = parser.parse().expect("This should not fail to parse");
 
-                    assert_eq!(file.stream.header.level.0, 9);
-                    assert_eq!(file.stream.blocks.len(), 0);
-                    assert_eq!(file.stream.footer.crc.0, 0);
+                    assert_eq!(file.streams[0].header.level.0, 9);
+                    assert_eq!(file.streams[0].blocks.len(), 0);
+                    assert_eq!(file.streams[0].footer.crc.0, 0);
                 }
             }
+
+            /// The bits that pad out the final byte can be anything, and should be ignored.
+            #[test]
+            fn padding_ones() {
+                // This is "a", compressed with bzip2 -9.
+                let mut input = *b"\x42\x5a\x68\x39\x31\x41\x59\x26\x53\x59\x19\x93\x9b\x6b\x00\x00\x00\x01\x00\x20\x00\x20\x00\x21\x18\x46\x82\xee\x48\xa7\x0a\x12\x03\x32\x73\x6d\x60";
+                let file = Parser::new(&input[..])
+                    .parse()
+                    .expect("This should not fail to parse");
+                let padding_bits = file.streams[0].footer.padding.0.len();
+                assert!(padding_bits > 0);
+                assert!(file.streams[0]
+                    .footer
+                    .padding
+                    .0
+                    .iter()
+                    .all(|bit| *bit == Bit::Zero));
+
+                let last = input.len() - 1;
+                input[last] |= (1 << padding_bits) - 1;
+                let file = Parser::new(&input[..])
+                    .parse()
+                    .expect("This should not fail to parse");
+
+                assert!(file.streams[0]
+                    .footer
+                    .padding
+                    .0
+                    .iter()
+                    .all(|bit| *bit == Bit::One));
+                assert_eq!(file.streams[0].blocks.len(), 1);
+                assert_eq!(file.streams[0].footer.crc.0, 0x1993_9b6b);
+            }
+        }
+    }
+
+    /// Test [`Parser::tree`].
+    mod tree {
+        use super::*;
+
+        /// Parse a tree of `num_symbols` from `fields`, each of which is a value and its width.
+        fn parse(fields: &[(u32, u8)], num_symbols: u16) -> Result<Tree, DecodeError> {
+            let mut bit_writer = BitWriter::new(vec![]);
+            for &(bits, len) in fields {
+                bit_writer.write_bits(bits, len).unwrap();
+            }
+            // Plenty of zeros after, so that running out of bits isn't what stops the parser.
+            bit_writer.write_bits(0, 32).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+
+            Parser::new(&bytes[..]).tree(num_symbols)
+        }
+
+        /// A `11` delta at a length of 1 would take it to 0, and another would underflow.
+        #[test]
+        fn decrement_below_one() {
+            // A start of 1, then 11 11 0 for the first length.
+            let result = parse(&[(1, 5), (0b11, 2), (0b11, 2), (0, 1)], 3);
+
+            assert!(matches!(result, Err(DecodeError::InvalidTree)));
+        }
+
+        /// A start of 0 is already too short, before any deltas.
+        #[test]
+        fn starts_at_zero() {
+            let result = parse(&[(0, 5), (0, 1), (0, 1), (0, 1)], 3);
+
+            assert!(matches!(result, Err(DecodeError::InvalidTree)));
+        }
+
+        /// Lengths past 20 should be rejected, and never reach the tree, where they would overflow
+        /// the shift that builds the codes.
+        #[test]
+        fn too_long() {
+            let mut bit_writer = BitWriter::new(vec![]);
+            write_tree(&mut bit_writer, &[1, 70, 70]).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+
+            let result = Parser::new(&bytes[..]).tree(3);
+
+            assert!(matches!(result, Err(DecodeError::InvalidTree)));
+        }
+
+        /// Write a stream with a single block, whose first tree is written by `first_tree`.
+        fn stream_with_tree<F>(first_tree: F) -> Vec<u8>
+        where
+            F: FnOnce(&mut BitWriter<Vec<u8>>),
+        {
+            let mut bit_writer = BitWriter::new(vec![]);
+            write_stream_header(&mut bit_writer, 9).unwrap();
+            bit_writer.write_bits(0x314159, 24).unwrap();
+            bit_writer.write_bits(0x265359, 24).unwrap();
+            bit_writer.write_bits(0, 32).unwrap();
+            bit_writer.write_bit(Bit::Zero).unwrap();
+            bit_writer.write_bits(0, 24).unwrap();
+            write_used_map(&mut bit_writer, &SymbolStack(vec![b'a'])).unwrap();
+            bit_writer.write_bits(2, 3).unwrap();
+            bit_writer.write_bits(1, 15).unwrap();
+            write_selectors_mtf(&mut bit_writer, &[Selector(0)]).unwrap();
+            first_tree(&mut bit_writer);
+            write_tree(&mut bit_writer, &[1, 2, 2]).unwrap();
+            bit_writer.write_bits(0, 32).unwrap();
+
+            bit_writer.into_inner().unwrap()
+        }
+
+        /// A stream whose tree decrements a length below zero should fail to parse.
+        #[test]
+        fn decrement_below_zero_stream() {
+            let input = stream_with_tree(|bit_writer| {
+                // A start of 0, then 11 for the first length.
+                bit_writer.write_bits(0, 5).unwrap();
+                bit_writer.write_bits(0b11, 2).unwrap();
+                bit_writer.write_bit(Bit::Zero).unwrap();
+            });
+
+            let result = Parser::new(&input[..]).parse();
+
+            assert!(matches!(result, Err(DecodeError::InvalidTree)));
+        }
+
+        /// A stream whose tree has lengths of 70 should fail to parse.
+        #[test]
+        fn too_long_stream() {
+            let input = stream_with_tree(|bit_writer| {
+                write_tree(bit_writer, &[1, 70, 70]).unwrap();
+            });
+
+            let result = Parser::new(&input[..]).parse();
+
+            assert!(matches!(result, Err(DecodeError::InvalidTree)));
         }
     }
 
@@ -709,6 +1190,20 @@ mod tests {
             }
         }
 
+        /// The legacy bzip header.
+        #[test]
+        fn legacy_bzip() {
+            let input = b"BZ09";
+
+            let decoded = decode(input);
+
+            if let Err(DecodeError::UnsupportedFormat) = decoded {
+                // This is the expected outcome
+            } else {
+                panic!("This should have returned an error.");
+            }
+        }
+
         /// Wrong header.
         #[test]
         fn wrong_header() {
@@ -749,32 +1244,400 @@ mod tests {
         }
     }
 
-    /// Test the `selector()`
-    mod selector {
+    /// Test [`write_used_map`] and [`read_used_map`].
+    mod used_map {
+        use super::*;
+
+        /// Write the used map for `symbols`, then read it back.
+        fn roundtrip(symbols: Vec<u8>) {
+            let symbol_stack = SymbolStack(symbols);
+            let mut bit_writer = BitWriter::new(vec![]);
+
+            write_used_map(&mut bit_writer, &symbol_stack).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+            let symbol_map = read_used_map(&mut Bitstream::new(&bytes[..])).unwrap();
+
+            assert_eq!(symbol_map.symbol_stack(), symbol_stack);
+        }
+
+        /// Test the bytes of the example from the PDF.
+        #[test]
+        fn pdf_example() {
+            roundtrip(vec![
+                0x01, 0x20, 0x27, 0x2c, 0x3f, 0x49, 0x50, 0x61, 0x63, 0x64, 0x65, 0x66, 0x68, 0x69,
+                0x6b, 0x6c, 0x6f, 0x70, 0x72, 0x73, 0x74, 0x77,
+            ]);
+        }
+
+        /// The map should match the example in the PDF bit for bit.
+        #[test]
+        fn pdf_example_bits() {
+            let symbol_stack = SymbolStack(vec![
+                0x01, 0x20, 0x27, 0x2c, 0x3f, 0x49, 0x50, 0x61, 0x63, 0x64, 0x65, 0x66, 0x68, 0x69,
+                0x6b, 0x6c, 0x6f, 0x70, 0x72, 0x73, 0x74, 0x77,
+            ]);
+
+            let symbol_map = SymbolMap::new(&symbol_stack);
+
+            assert_eq!(symbol_map.l1, 0xbf00);
+            assert_eq!(
+                symbol_map.l2,
+                [0x4000, 0x8108, 0x0001, 0x0040, 0x8000, 0x5ed9, 0xb900]
+            );
+        }
+
+        /// The first and last bytes sit at either end of the map.
+        #[test]
+        fn extremes() {
+            roundtrip(vec![0x00, 0xff]);
+        }
+
+        /// Every byte is used.
+        #[test]
+        fn all() {
+            roundtrip((0..=255).collect());
+        }
+    }
+
+    /// Test [`read_selectors_mtf`] and [`write_selectors_mtf`].
+    mod selectors_mtf {
         use super::*;
 
+        fn read(bytes: &[u8], num_selectors: u16, num_trees: u8) -> Result<Vec<u8>, DecodeError> {
+            let mut bitstream = Bitstream::new(bytes);
+            let selectors = read_selectors_mtf(&mut bitstream, num_selectors, num_trees)?;
+
+            Ok(selectors.into_iter().map(|selector| selector.0).collect())
+        }
+
         #[test]
         fn zero() {
-            let mut parser = Parser {
-                bitstream: Bitstream::new(&[0x0_u8][..]),
-            };
-            assert_eq!(parser.selector().unwrap().0, 0);
+            assert_eq!(read(&[0x0_u8], 1, 2).unwrap(), [0]);
         }
 
         #[test]
         fn one() {
-            let mut parser = Parser {
-                bitstream: Bitstream::new(&[0x80][..]),
-            };
-            assert_eq!(parser.selector().unwrap().0, 1);
+            assert_eq!(read(&[0x80], 1, 2).unwrap(), [1]);
         }
 
         #[test]
         fn two() {
-            let mut parser = Parser {
-                bitstream: Bitstream::new(&[0xc0][..]),
+            assert_eq!(read(&[0xc0], 1, 3).unwrap(), [2]);
+        }
+
+        /// Six trees allow the longest selector, five one-bits.
+        #[test]
+        fn longest() {
+            assert_eq!(read(&[0xf8], 1, 6).unwrap(), [5]);
+        }
+
+        /// A selector cannot refer to a tree that doesn't exist.
+        #[test]
+        fn too_large() {
+            assert!(matches!(
+                read(&[0xc0], 1, 2),
+                Err(DecodeError::InvalidSelector)
+            ));
+        }
+
+        /// Without any trees, not even a zero selector refers to one.
+        #[test]
+        fn no_trees() {
+            assert!(matches!(
+                read(&[0x00], 1, 0),
+                Err(DecodeError::InvalidSelector)
+            ));
+        }
+
+        /// A block must have at least one selector.
+        #[test]
+        fn none() {
+            assert!(matches!(
+                read(&[0x00], 0, 2),
+                Err(DecodeError::InvalidSelector)
+            ));
+        }
+
+        /// Test the exact bits of a round trip.
+        #[test]
+        fn roundtrip() {
+            let selectors: Vec<Selector> = [0, 0, 1, 2, 1, 0].into_iter().map(Selector).collect();
+            let mut bit_writer = BitWriter::new(vec![]);
+
+            write_selectors_mtf(&mut bit_writer, &selectors).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+
+            // The MTF values are [0, 0, 1, 2, 1, 2], so the bits are 0 0 10 110 10 110.
+            assert_eq!(bytes, [0b0010_1101, 0b0110_0000]);
+            assert_eq!(read(&bytes, 6, 3).unwrap(), [0, 0, 1, 2, 1, 0]);
+        }
+
+        /// The largest number of selectors a block can need should all be read.
+        #[test]
+        fn maximum() {
+            let bytes = vec![0; MAX_SELECTORS.div_ceil(8)];
+
+            let selectors = read(&bytes, MAX_SELECTORS as u16, 2).unwrap();
+
+            assert_eq!(selectors, vec![0; MAX_SELECTORS]);
+        }
+
+        /// Any selectors past the maximum are read, but dropped.
+        #[test]
+        fn beyond_maximum() {
+            let mut bytes = vec![0; 0x7fff_usize.div_ceil(8)];
+            bytes.push(0xa5);
+            let mut bitstream = Bitstream::new(&bytes[..]);
+
+            let selectors = read_selectors_mtf(&mut bitstream, 0x7fff, 2).unwrap();
+
+            assert_eq!(selectors.len(), MAX_SELECTORS);
+            // The bitstream should be just after the last selector.
+            let _padding = bitstream.get_padding();
+            assert_eq!(bitstream.get_integer::<u8>(8).unwrap(), 0xa5);
+        }
+    }
+
+    /// Test [`Parser::block_data`].
+    mod block_data {
+        use super::*;
+
+        /// A block that runs out of selectors before its EOB symbol is corrupt.
+        #[test]
+        fn too_few_selectors() {
+            // With these code lengths, a zero bit is RunA, so the one selector's 50 symbols are all
+            // RunA, and there is no EOB.
+            let trees = BlockTrees {
+                sym_map: SymbolMap::new(&SymbolStack(vec![b'a'])),
+                trees: vec![vec![1, 2, 2].try_into().unwrap()],
+                selectors: vec![Selector(0)],
+            };
+            let mut parser = Parser::new(&[0_u8; 7][..]);
+
+            let data = parser.block_data(&trees);
+
+            assert!(matches!(data, Err(DecodeError::TooFewSelectors)));
+        }
+
+        /// The parser should stop on the EOB symbol, and leave whatever comes after it.
+        #[test]
+        fn stops_at_eob() {
+            let symbols = [
+                crate::rle2::Symbol::RunA,
+                crate::rle2::Symbol::Byte(1),
+                crate::rle2::Symbol::RunB,
+            ];
+            let coded = huffman::encode(&symbols, 4);
+            let trees = BlockTrees {
+                sym_map: SymbolMap::new(&SymbolStack(vec![b'a', b'b'])),
+                trees: vec![coded.tables[0].clone().try_into().unwrap()],
+                selectors: vec![Selector(0)],
+            };
+            let mut bit_writer = BitWriter::new(vec![]);
+            for bit in &coded.blocks[0].bitvec {
+                bit_writer.write_bits((*bit).into(), 1).unwrap();
+            }
+            let num_bits = coded.blocks[0].bitvec.len() as u64;
+            // Anything after the EOB symbol must not be read as part of the block.
+            bit_writer.write_bits(u32::MAX, 32).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+            let mut parser = Parser::new(&bytes[..]);
+
+            let data = parser.block_data(&trees).unwrap();
+
+            assert_eq!(
+                data.0,
+                [Symbol::RunA, Symbol::Byte(1), Symbol::RunB, Symbol::Eob]
+            );
+            assert_eq!(parser.bitstream.position(), num_bits);
+        }
+    }
+
+    /// Test [`StreamReader`].
+    mod stream_reader {
+        use super::*;
+        use crate::rle2;
+
+        /// Make a stream of blocks with the given CRCs.
+        fn stream(crcs: &[u32]) -> Vec<u8> {
+            let blocks: Vec<EncodedBlock> = crcs
+                .iter()
+                .map(|&crc| EncodedBlock {
+                    crc,
+                    origin_pointer: OriginPointer(0),
+                    symbol_stack: SymbolStack(vec![b'a']),
+                    huffman: huffman::encode(&[rle2::Symbol::RunA], 3),
+                })
+                .collect();
+            encode(9, &blocks)
+        }
+
+        /// Read everything from `reader`, as block CRCs and footer CRCs.
+        fn items(mut reader: StreamReader<&[u8]>) -> Vec<(&'static str, u32)> {
+            let mut items = vec![];
+            while let Some(item) = reader.next_item().unwrap() {
+                items.push(match item {
+                    StreamItem::Block(block) => ("block", block.crc()),
+                    StreamItem::Footer(crc) => ("footer", crc.value()),
+                });
+            }
+            // The end of the input should stay the end.
+            assert!(reader.next_item().unwrap().is_none());
+            items
+        }
+
+        /// Each block should come out in turn, and then the footer's CRC.
+        #[test]
+        fn blocks() {
+            let bytes = stream(&[1, 2, 3]);
+
+            // ((1 <<< 1) ^ 2) <<< 1) ^ 3 is 3.
+            assert_eq!(
+                items(StreamReader::new(&bytes[..]).unwrap()),
+                [("block", 1), ("block", 2), ("block", 3), ("footer", 3)]
+            );
+        }
+
+        /// Concatenated streams should come out one after the other.
+        #[test]
+        fn concatenated() {
+            let bytes = [stream(&[5]), stream(&[]), stream(&[6, 7])].concat();
+
+            // (6 <<< 1) ^ 7 is 11.
+            assert_eq!(
+                items(StreamReader::new(&bytes[..]).unwrap()),
+                [
+                    ("block", 5),
+                    ("footer", 5),
+                    ("footer", 0),
+                    ("block", 6),
+                    ("block", 7),
+                    ("footer", 11)
+                ]
+            );
+        }
+
+        /// Anything but another stream after a footer is an error.
+        #[test]
+        fn trailing_data() {
+            let bytes = [stream(&[5]), b"BZ".to_vec()].concat();
+            let mut reader = StreamReader::new(&bytes[..]).unwrap();
+
+            assert!(matches!(reader.next_item(), Ok(Some(StreamItem::Block(_)))));
+            assert!(matches!(
+                reader.next_item(),
+                Ok(Some(StreamItem::Footer(_)))
+            ));
+            assert!(matches!(reader.next_item(), Err(DecodeError::TrailingData)));
+        }
+
+        /// The header should be checked straight away.
+        #[test]
+        fn bad_header() {
+            assert!(matches!(
+                StreamReader::new(&b"BZx9"[..]),
+                Err(DecodeError::InvalidHeader)
+            ));
+        }
+    }
+
+    /// Test [`write_tree`].
+    mod write_tree {
+        use super::*;
+
+        /// Test the exact bits, and that the parser reads the same lengths back.
+        #[test]
+        fn roundtrip() {
+            let mut bit_writer = BitWriter::new(vec![]);
+
+            write_tree(&mut bit_writer, &[1, 2, 2]).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+
+            // A start of 1, then 0 for the first length, 10 0 for the second, and 0 for the third.
+            assert_eq!(bytes, [0b0000_1010, 0b0000_0000]);
+            let mut parser = Parser::new(&bytes[..]);
+            let tree = parser.tree(3).unwrap();
+            assert_eq!(tree.code(&Symbol::RunA), [false]);
+            assert_eq!(tree.code(&Symbol::RunB), [true, false]);
+            assert_eq!(tree.code(&Symbol::Eob), [true, true]);
+        }
+
+        /// Lengths can go down as well as up.
+        #[test]
+        fn decreasing() {
+            let mut bit_writer = BitWriter::new(vec![]);
+
+            write_tree(&mut bit_writer, &[3, 3, 2, 2, 2]).unwrap();
+            let bytes = bit_writer.into_inner().unwrap();
+
+            let mut parser = Parser::new(&bytes[..]);
+            let tree = parser.tree(5).unwrap();
+            assert_eq!(tree.code(&Symbol::RunA).len(), 3);
+            assert_eq!(tree.code(&Symbol::Eob).len(), 2);
+        }
+    }
+
+    /// Test [`encode`].
+    mod encode {
+        use super::*;
+        use crate::rle2;
+
+        /// With no blocks, we should write the canonical empty archive.
+        #[test]
+        fn empty() {
+            assert_eq!(
+                encode(9, &[]),
+                b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00"
+            );
+        }
+
+        /// The footer should hold the CRCs of the blocks, combined.
+        #[test]
+        fn stream_crc() {
+            let blocks: Vec<EncodedBlock> = [0x8000_0001, 0x0000_00ff]
+                .into_iter()
+                .map(|crc| EncodedBlock {
+                    crc,
+                    origin_pointer: OriginPointer(0),
+                    symbol_stack: SymbolStack(vec![b'a']),
+                    huffman: huffman::encode(&[rle2::Symbol::RunA], 3),
+                })
+                .collect();
+
+            let bytes = encode(9, &blocks);
+
+            let crc = decode_streams(&bytes).unwrap()[0].crc();
+            // 0x8000_0001 rotated left by one is 0x0000_0003.
+            assert_eq!(crc.value(), 0x0000_00fc);
+        }
+
+        /// The parser should read back the block that we wrote.
+        #[test]
+        fn roundtrip() {
+            let symbols = [
+                rle2::Symbol::Byte(1),
+                rle2::Symbol::RunA,
+                rle2::Symbol::Byte(2),
+            ];
+            let block = EncodedBlock {
+                crc: 0x1234_5678,
+                origin_pointer: OriginPointer(1),
+                symbol_stack: SymbolStack(vec![b'a', b'b', b'c']),
+                huffman: huffman::encode(&symbols, 5),
             };
-            assert_eq!(parser.selector().unwrap().0, 2);
+
+            let bytes = encode(1, &[block]);
+
+            assert!(bytes.starts_with(b"BZh1"));
+            let blocks = decode(&bytes).unwrap();
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(blocks[0].crc(), 0x1234_5678);
+            assert_eq!(blocks[0].header.orig_ptr.0, 1);
+            assert_eq!(blocks[0].trees.sym_map.symbol_stack().0, b"abc");
+            assert_eq!(
+                blocks[0].symbols(),
+                [Symbol::Byte(1), Symbol::RunA, Symbol::Byte(2), Symbol::Eob]
+            );
         }
     }
 }
@@ -1,6 +1,6 @@
 use std::{
     fmt,
-    io::{self, Read},
+    io::{self, Read, Write},
 };
 
 /// A bit.
@@ -25,6 +25,8 @@ pub(crate) struct Bitstream<R> {
     /// The index of the bit that we are about to handle.
     // This pointer views bit 0 as the rightmost bit and bit 7 as the leftmost bit.
     bit_pointer: u8,
+    /// The number of bits that have been consumed since the start of the stream.
+    position: u64,
 }
 
 impl<R> fmt::Debug for Bitstream<R> {
@@ -33,6 +35,7 @@ impl<R> fmt::Debug for Bitstream<R> {
             .field("buffer_size", &self.buffer_size)
             .field("buffer_pointer", &self.buffer_pointer)
             .field("bit_pointer", &self.bit_pointer)
+            .field("position", &self.position)
             .finish()
     }
 }
@@ -104,9 +107,28 @@ where
             buffer_size: 0,
             buffer_pointer: 0,
             bit_pointer: 7,
+            position: 0,
         }
     }
 
+    /// Return the number of bits that have been consumed since the start of the stream.
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read the next `count` bits as an integer, most significant bit first.
+    ///
+    /// This is the counterpart to [`BitWriter::write_bits`].
+    ///
+    /// # Panics
+    ///
+    /// - If count > 32.
+    pub(crate) fn read_bits(&mut self, count: u8) -> io::Result<u32> {
+        assert!(count <= 32, "count must be at most 32 but was {count}");
+
+        self.get_integer(count)
+    }
+
     pub(super) fn get_integer<T>(&mut self, num_bits_to_read: u8) -> io::Result<T>
     where
         T: FromBits,
@@ -202,6 +224,7 @@ where
 
         self.bit_pointer = peek.new_bit_pointer;
         self.buffer_pointer = peek.new_buffer_pointer;
+        self.position += 1;
 
         Ok(peek.bits[0])
     }
@@ -223,7 +246,8 @@ where
     /// This function reads and returns enough bits out of the bitstream so that there are no
     /// partially consumed bytes in the buffer.
     pub(super) fn get_padding(&mut self) -> Vec<Bit> {
-        let bits_to_read = if self.buffer_pointer == self.buffer_size {
+        // A bit pointer of 7 means that nothing has been read from the current byte yet.
+        let bits_to_read = if self.buffer_pointer == self.buffer_size || self.bit_pointer == 7 {
             0
         } else {
             self.bit_pointer + 1
@@ -274,6 +298,107 @@ where
     }
 }
 
+/// An adapter over a writer that packs bits into bytes, most significant bit first.
+///
+/// This is the counterpart to [`Bitstream`].
+pub(crate) struct BitWriter<W> {
+    /// The writer.
+    inner: W,
+    /// Bits that have been written but that don't fill a whole byte yet, in the low bits.
+    pending: u8,
+    /// The number of bits in `pending`.
+    pending_bits: u8,
+}
+
+impl<W> fmt::Debug for BitWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BitWriter")
+            .field("pending", &self.pending)
+            .field("pending_bits", &self.pending_bits)
+            .finish()
+    }
+}
+
+impl<W> BitWriter<W>
+where
+    W: Write,
+{
+    pub(crate) fn new(inner: W) -> BitWriter<W> {
+        BitWriter {
+            inner,
+            pending: 0,
+            pending_bits: 0,
+        }
+    }
+
+    /// Write a single bit.
+    pub(crate) fn write_bit(&mut self, bit: Bit) -> io::Result<()> {
+        self.pending <<= 1;
+        if bit == Bit::One {
+            self.pending |= 1;
+        }
+        self.pending_bits += 1;
+
+        if self.pending_bits == 8 {
+            self.inner.write_all(&[self.pending])?;
+            self.pending = 0;
+            self.pending_bits = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Write the lowest `count` bits of `value`, most significant bit first.
+    ///
+    /// # Panics
+    ///
+    /// - If count > 32.
+    pub(crate) fn write_bits(&mut self, value: u32, count: u8) -> io::Result<()> {
+        assert!(count <= 32, "count must be at most 32 but was {count}");
+
+        for i in (0..count).rev() {
+            let bit = if (value >> i) & 1 == 0 {
+                Bit::Zero
+            } else {
+                Bit::One
+            };
+            self.write_bit(bit)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pad the final partial byte with zeros, write it out, and flush the underlying writer.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        while self.pending_bits != 0 {
+            self.write_bit(Bit::Zero)?;
+        }
+
+        self.inner.flush()
+    }
+
+    /// Get a reference to the underlying writer.
+    ///
+    /// Any bits that don't fill a whole byte yet have not been written to it.
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying writer.
+    ///
+    /// Any bits that don't fill a whole byte yet have not been written to it.
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Flush the bits and return the underlying writer.
+    pub(crate) fn into_inner(mut self) -> io::Result<W> {
+        self.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -328,6 +453,37 @@ mod tests {
         assert_eq!(bitstream.buffer_pointer, 1);
     }
 
+    /// There is no padding at a byte boundary, even if more bytes follow.
+    #[test]
+    fn get_padding_aligned() {
+        let input: &[u8] = &[10, 20];
+
+        let mut bitstream = Bitstream::new(input);
+        let _: u8 = bitstream.get_integer(8).unwrap();
+
+        assert_eq!(bitstream.get_padding(), &[]);
+        assert_eq!(bitstream.get_integer::<u8>(8).unwrap(), 20);
+    }
+
+    /// The position should count consumed bits, but not peeked ones, across buffer refills.
+    #[test]
+    fn position() {
+        let input = [0xa5; BUFFER_SIZE + 10];
+
+        let mut bitstream = Bitstream::new(&input[..]);
+        assert_eq!(bitstream.position(), 0);
+        let _: u8 = bitstream.get_integer(4).unwrap();
+        let _: u32 = bitstream.peek_integer(32).unwrap();
+        assert_eq!(bitstream.position(), 4);
+        let _ = bitstream.get_padding();
+        assert_eq!(bitstream.position(), 8);
+        for _ in 0..BUFFER_SIZE {
+            let _: u8 = bitstream.get_integer(8).unwrap();
+        }
+
+        assert_eq!(bitstream.position(), 8 * (BUFFER_SIZE as u64 + 1));
+    }
+
     /// Test the shift_and_read() method.
     mod shift_and_read {
         use super::*;
@@ -577,4 +733,96 @@ mod tests {
             );
         }
     }
+
+    /// Test [`BitWriter`].
+    mod bit_writer {
+        use super::*;
+
+        #[test]
+        fn simple() {
+            let mut bw = BitWriter::new(vec![]);
+
+            bw.write_bits(0b101, 3).unwrap();
+            bw.write_bits(0b01010, 5).unwrap();
+            bw.write_bits(0xbeef, 16).unwrap();
+
+            assert_eq!(bw.into_inner().unwrap(), [0xaa, 0xbe, 0xef]);
+        }
+
+        /// The last byte should be padded with zeros.
+        #[test]
+        fn padding() {
+            let mut bw = BitWriter::new(vec![]);
+
+            bw.write_bit(Bit::One).unwrap();
+            bw.write_bits(0b11, 2).unwrap();
+
+            assert_eq!(bw.into_inner().unwrap(), [0xe0]);
+        }
+
+        /// Nothing should be written if no bits were written.
+        #[test]
+        fn empty() {
+            let bw = BitWriter::new(vec![]);
+
+            assert_eq!(bw.into_inner().unwrap(), []);
+        }
+
+        /// What the writer writes, the bitstream should read.
+        #[test]
+        fn roundtrip() {
+            let mut bw = BitWriter::new(vec![]);
+
+            bw.write_bits(0x7, 3).unwrap();
+            bw.write_bits(0x123456, 24).unwrap();
+            bw.write_bits(0x0, 1).unwrap();
+            bw.write_bits(0xdeadbeef, 32).unwrap();
+
+            let bytes = bw.into_inner().unwrap();
+            let mut bs = Bitstream::new(&bytes[..]);
+            assert_eq!(bs.get_integer::<u8>(3).unwrap(), 0x7);
+            assert_eq!(bs.get_integer::<u32>(24).unwrap(), 0x123456);
+            assert_eq!(bs.get_integer::<u8>(1).unwrap(), 0x0);
+            assert_eq!(bs.get_integer::<u32>(32).unwrap(), 0xdeadbeef);
+        }
+
+        /// Every width from 1 to 24 bits, in a shuffled order, should read back as it was
+        /// written, and the zero padding after the last one should be all that is left.
+        #[test]
+        fn every_width() {
+            // 7 is coprime to 24, so this visits every width once, out of order.
+            let widths: Vec<u8> = (0..24).map(|i| (i * 7) % 24 + 1).collect();
+            let value = |width: u8| 0xa5_c3_96 >> (24 - width);
+            let mut bw = BitWriter::new(vec![]);
+
+            for &width in &widths {
+                bw.write_bits(value(width), width).unwrap();
+            }
+            let bytes = bw.into_inner().unwrap();
+
+            // The widths add up to 300 bits, so the last byte has 4 bits of padding.
+            assert_eq!(bytes.len(), 38);
+            let mut bs = Bitstream::new(&bytes[..]);
+            for &width in &widths {
+                assert_eq!(bs.read_bits(width).unwrap(), value(width), "width {width}");
+            }
+            assert_eq!(bs.get_padding(), [Bit::Zero; 4]);
+            assert!(bs.is_empty().unwrap());
+        }
+
+        /// Reading past the end of the padding should be an error.
+        #[test]
+        fn past_padding() {
+            let mut bw = BitWriter::new(vec![]);
+            bw.write_bits(0b1011, 4).unwrap();
+            let bytes = bw.into_inner().unwrap();
+            let mut bs = Bitstream::new(&bytes[..]);
+
+            assert_eq!(bs.read_bits(8).unwrap(), 0b1011_0000);
+            assert_eq!(
+                bs.read_bits(1).unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
 }
@@ -0,0 +1,298 @@
+//! A frame around a `bzip2` stream that records the length and CRC of the data inside.
+//!
+//! This is a format of our own, so nothing else can read it. A frame is:
+//!
+//! - the length of the decompressed data, as a `u64`,
+//! - the CRC of the decompressed data, as a `u32`, using the same CRC-32 as `bzip2`,
+//! - a `bzip2` stream of the data.
+//!
+//! The length and CRC are little-endian unless [`FrameOptions::big_endian`] is set.
+
+use crate::{compress_to_vec, crc, decompress_each_block, CompressError, DecompressError};
+
+/// The length of the fields before the `bzip2` stream.
+const HEADER_LEN: usize = 12;
+
+/// The most that [`decompress_framed_with`] allocates up front from the length in the frame.
+///
+/// The length is only checked once the stream has decompressed, so a corrupt one mustn't be able
+/// to allocate more than this. Anything longer grows as usual from here.
+const MAX_PREALLOCATION: usize = 64 << 20;
+
+/// Settings for [`compress_framed_with`] and [`decompress_framed_with`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FrameOptions {
+    /// Write the length and CRC big-endian, for protocols that use that byte order throughout.
+    ///
+    /// A frame has to be decompressed with the same setting that it was compressed with.
+    pub big_endian: bool,
+}
+
+impl FrameOptions {
+    fn u64_to_bytes(self, value: u64) -> [u8; 8] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u32_to_bytes(self, value: u32) -> [u8; 4] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u64_from_bytes(self, bytes: [u8; 8]) -> u64 {
+        if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+/// Compress `data` into a frame.
+///
+/// See the [module documentation](self) for the format.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::framed::{compress_framed, decompress_framed};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let framed = compress_framed(b"Peter Piper")?;
+///
+/// assert_eq!(&framed[..8], 11_u64.to_le_bytes());
+/// assert_eq!(decompress_framed(&framed)?, b"Peter Piper");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// This can't fail at the moment, since nothing is read from or written to I/O, but returns a
+/// `Result` so that it matches [`compress`](crate::compress).
+pub fn compress_framed(data: &[u8]) -> Result<Vec<u8>, CompressError> {
+    compress_framed_with(data, FrameOptions::default())
+}
+
+/// Compress `data` into a frame, with the given `options`.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::framed::{compress_framed_with, decompress_framed_with, FrameOptions};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let options = FrameOptions { big_endian: true };
+/// let framed = compress_framed_with(b"Peter Piper", options)?;
+///
+/// assert_eq!(&framed[..8], 11_u64.to_be_bytes());
+/// assert_eq!(decompress_framed_with(&framed, options)?, b"Peter Piper");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// This can't fail at the moment, for the same reason as [`compress_framed`].
+pub fn compress_framed_with(data: &[u8], options: FrameOptions) -> Result<Vec<u8>, CompressError> {
+    let mut output = Vec::with_capacity(HEADER_LEN);
+    output.extend_from_slice(&options.u64_to_bytes(data.len() as u64));
+    output.extend_from_slice(&options.u32_to_bytes(crc::checksum(data)));
+    output.extend_from_slice(&compress_to_vec(data)?);
+
+    Ok(output)
+}
+
+/// Decompress a frame from [`compress_framed`], checking its length and CRC.
+///
+/// The output is allocated once, at the length that the frame records, up to 64 MiB.
+///
+/// # Errors
+///
+/// If `data` is too short to hold the length and CRC, this returns
+/// [`DecompressError::FrameTruncated`]. If the stream decompresses to data of a different length
+/// or CRC than the frame records, this returns [`DecompressError::FrameLengthMismatch`] or
+/// [`DecompressError::FrameChecksumMismatch`]. It also fails if the stream isn't valid `bzip2`.
+pub fn decompress_framed(data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    decompress_framed_with(data, FrameOptions::default())
+}
+
+/// Decompress a frame from [`compress_framed_with`], with the same `options` it was compressed
+/// with.
+///
+/// # Errors
+///
+/// The same as [`decompress_framed`]. A frame read with the wrong byte order will almost always
+/// fail with [`DecompressError::FrameLengthMismatch`].
+pub fn decompress_framed_with(
+    data: &[u8],
+    options: FrameOptions,
+) -> Result<Vec<u8>, DecompressError> {
+    let Some((header, stream)) = data.split_first_chunk::<HEADER_LEN>() else {
+        return Err(DecompressError::FrameTruncated);
+    };
+    let (len, crc) = header.split_at(8);
+    let len = options.u64_from_bytes(len.try_into().expect("The length is 8 bytes"));
+    let crc = options.u32_from_bytes(crc.try_into().expect("The CRC is 4 bytes"));
+
+    let mut output = Vec::with_capacity(
+        usize::try_from(len).map_or(MAX_PREALLOCATION, |len| len.min(MAX_PREALLOCATION)),
+    );
+    decompress_each_block(stream, |_, block| output.extend_from_slice(block))?;
+
+    if output.len() as u64 != len {
+        return Err(DecompressError::FrameLengthMismatch {
+            expected: len,
+            found: output.len() as u64,
+        });
+    }
+    let found = crc::checksum(&output);
+    if found != crc {
+        return Err(DecompressError::FrameChecksumMismatch {
+            expected: crc,
+            found,
+        });
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompress_to_vec;
+
+    const DATA: &[u8] = b"If Peter Piper picked a peck of pickled peppers";
+
+    #[test]
+    fn roundtrip() {
+        for data in [&b""[..], DATA, &DATA.repeat(10_000)] {
+            let framed = compress_framed(data).unwrap();
+
+            assert_eq!(&framed[..8], (data.len() as u64).to_le_bytes());
+            assert_eq!(&framed[8..12], crc::checksum(data).to_le_bytes());
+            assert_eq!(decompress_to_vec(&framed[12..]).unwrap(), data);
+            assert_eq!(decompress_framed(&framed).unwrap(), data);
+        }
+    }
+
+    /// Both byte orders should round-trip, and write the fields in that order.
+    #[test]
+    fn endianness() {
+        for big_endian in [false, true] {
+            let options = FrameOptions { big_endian };
+            let framed = compress_framed_with(DATA, options).unwrap();
+
+            let (len, crc) = if big_endian {
+                (47_u64.to_be_bytes(), crc::checksum(DATA).to_be_bytes())
+            } else {
+                (47_u64.to_le_bytes(), crc::checksum(DATA).to_le_bytes())
+            };
+            assert_eq!(&framed[..8], len);
+            assert_eq!(&framed[8..12], crc);
+            assert_eq!(decompress_framed_with(&framed, options).unwrap(), DATA);
+        }
+    }
+
+    /// Reading a frame with the other byte order should fail the length check.
+    #[test]
+    fn endianness_mismatch() {
+        for big_endian in [false, true] {
+            let framed = compress_framed_with(DATA, FrameOptions { big_endian }).unwrap();
+            let options = FrameOptions {
+                big_endian: !big_endian,
+            };
+
+            assert!(matches!(
+                decompress_framed_with(&framed, options),
+                Err(DecompressError::FrameLengthMismatch {
+                    expected: 0x2f00_0000_0000_0000,
+                    found: 47,
+                })
+            ));
+        }
+    }
+
+    /// The output should be allocated once, at exactly the length that the frame records.
+    #[test]
+    fn preallocated() {
+        let data = DATA.repeat(10_000);
+        let framed = compress_framed(&data).unwrap();
+
+        let output = decompress_framed(&framed).unwrap();
+
+        assert_eq!(&framed[..8], (output.len() as u64).to_le_bytes());
+        assert_eq!(output.capacity(), data.len());
+    }
+
+    /// A huge length shouldn't be allocated before it can be checked.
+    #[test]
+    fn preallocation_capped() {
+        let mut framed = compress_framed(DATA).unwrap();
+        framed[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(matches!(
+            decompress_framed(&framed),
+            Err(DecompressError::FrameLengthMismatch {
+                expected: u64::MAX,
+                found: 47,
+            })
+        ));
+    }
+
+    #[test]
+    fn length_tampered() {
+        let mut framed = compress_framed(DATA).unwrap();
+        framed[0] += 1;
+
+        assert!(matches!(
+            decompress_framed(&framed),
+            Err(DecompressError::FrameLengthMismatch {
+                expected: 48,
+                found: 47,
+            })
+        ));
+    }
+
+    #[test]
+    fn checksum_tampered() {
+        let mut framed = compress_framed(DATA).unwrap();
+        framed[8] ^= 0x01;
+
+        assert!(matches!(
+            decompress_framed(&framed),
+            Err(DecompressError::FrameChecksumMismatch { .. })
+        ));
+    }
+
+    /// A frame too short for its header should be an error, however short it is.
+    #[test]
+    fn truncated() {
+        let framed = compress_framed(DATA).unwrap();
+
+        for len in 0..HEADER_LEN {
+            assert!(matches!(
+                decompress_framed(&framed[..len]),
+                Err(DecompressError::FrameTruncated)
+            ));
+        }
+        assert!(matches!(
+            decompress_framed(&framed[..HEADER_LEN]),
+            Err(DecompressError::Parse(_))
+        ));
+    }
+}
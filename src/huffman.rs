@@ -1,6 +1,6 @@
 //! Define the huffman encoding functions.
 
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::HashMap;
 
 use super::rle2;
 use crate::file_format::bitstream::Bitstream;
@@ -17,22 +17,21 @@ pub enum Error {
     TruncatedBitstream,
 }
 
+/// The Huffman coded symbols of a single block.
 #[derive(Debug, Default)]
-#[expect(unused)] // TODO: Should be used when we encode.
 pub(super) struct HuffmanCodedData {
-    /// This is the set of trees used in the encoded data.
-    trees: Vec<tree::Tree>,
-    /// This contains the encoded data.
-    blocks: Vec<HuffmanBlock>,
+    /// The code length of each symbol, for each table used in the encoded data.
+    pub(crate) tables: Vec<Vec<u8>>,
+    /// This contains the encoded data, in groups of 50 symbols.
+    pub(crate) blocks: Vec<HuffmanBlock>,
 }
 
 #[derive(Debug, Default)]
-#[expect(unused)] // TODO: Should be used when we encode.
-struct HuffmanBlock {
-    /// Points into [`HuffmanCodedData::trees`] to indicate which tree was used for this block.
-    tree_index: u8,
-    /// This is the huffman-coded data.
-    bitvec: Vec<u8>,
+pub(crate) struct HuffmanBlock {
+    /// Points into [`HuffmanCodedData::tables`] to indicate which table was used for this block.
+    pub(crate) tree_index: u8,
+    /// This is the huffman-coded data, one bit per item.
+    pub(crate) bitvec: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
@@ -43,6 +42,27 @@ pub(crate) enum Symbol {
     Eob,
 }
 
+impl Symbol {
+    /// The position of this symbol in an alphabet of `alphabet_size` symbols.
+    ///
+    /// This uses the numbering from [`rle2::Symbol::to_index`], with `Eob` always the last symbol.
+    fn index(&self, alphabet_size: usize) -> usize {
+        match self {
+            Symbol::Eob => alphabet_size - 1,
+            symbol => rle2::Symbol::from(symbol).to_index(alphabet_size).into(),
+        }
+    }
+
+    /// The symbol at `index` in an alphabet of `alphabet_size` symbols. The inverse of
+    /// [`Symbol::index`].
+    fn from_index(index: usize, alphabet_size: usize) -> Self {
+        let index = u16::try_from(index).expect("An alphabet has at most 258 symbols");
+
+        rle2::Symbol::from_index(index, alphabet_size)
+            .map_or(Symbol::Eob, |symbol| (&symbol).into())
+    }
+}
+
 impl From<&rle2::Symbol> for Symbol {
     fn from(value: &rle2::Symbol) -> Self {
         match value {
@@ -84,21 +104,6 @@ pub(crate) mod tree {
     #[derive(Copy, Clone, Debug, Eq, PartialEq)]
     struct NodeIdx(usize);
 
-    #[derive(Eq, PartialEq)]
-    struct SymbolWeight(usize, NodeIdx);
-
-    impl Ord for SymbolWeight {
-        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            self.0.cmp(&other.0)
-        }
-    }
-
-    impl PartialOrd for SymbolWeight {
-        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            Some(self.cmp(other))
-        }
-    }
-
     #[derive(Debug)]
     enum Node {
         Branch(NodeIdx, NodeIdx),
@@ -113,58 +118,15 @@ pub(crate) mod tree {
     }
 
     impl Tree {
-        pub(crate) fn new(symbol_weights: HashMap<Symbol, usize>) -> Self {
-            let mut nodes: Vec<Node> = Vec::new();
-            let mut priority_queue: BinaryHeap<_> = symbol_weights
-                .iter()
-                .map(|(symbol, weight)| {
-                    let idx = nodes.len();
-                    nodes.push(Node::Leaf(*symbol));
-                    Reverse(SymbolWeight(*weight, NodeIdx(idx)))
-                })
-                .collect();
-
-            while priority_queue.len() > 1 {
-                let Reverse(SymbolWeight(first_weight, right)) = priority_queue
-                    .pop()
-                    .expect("We asserted that there were two");
-                let Reverse(SymbolWeight(second_weight, left)) = priority_queue
-                    .pop()
-                    .expect("We asserted that there were two");
-                let idx = nodes.len();
-                nodes.push(Node::Branch(left, right));
-                priority_queue.push(Reverse(SymbolWeight(
-                    first_weight + second_weight,
-                    NodeIdx(idx),
-                )));
-            }
-
-            // our priority queue has exactly one item
-            // that means our huffman tree is in our nodes list at index priority_queue.pop()
-            let Reverse(SymbolWeight(_, root)) = priority_queue
-                .pop()
-                .expect("there must be exactly one item in the queue");
-
-            let symbol_bitmap = Self::build_symbol_bitmap(root, &nodes);
-            Self {
-                root,
-                nodes,
-                symbol_bitmap,
-            }
-        }
-
-        pub(crate) fn encode(&self, symbols: &[rle2::Symbol]) -> Vec<u8> {
-            let mut bitvec = Vec::new();
-
-            for symbol in symbols {
-                let path = self
-                    .symbol_bitmap
-                    .get(&symbol.into())
-                    .expect("All symbols should be in the tree");
-                bitvec.extend(path.iter().map(|b| if *b { 1 } else { 0 }));
-            }
-
-            bitvec
+        /// Return the code for `symbol`.
+        ///
+        /// # Panics
+        ///
+        /// If `symbol` is not in the tree.
+        pub(crate) fn code(&self, symbol: &Symbol) -> &[bool] {
+            self.symbol_bitmap
+                .get(symbol)
+                .expect("All symbols should be in the tree")
         }
 
         /// Decode one `Symbol` from the `bitstream`.
@@ -191,31 +153,6 @@ pub(crate) mod tree {
                 }
             }
         }
-
-        fn build_symbol_bitmap(root: NodeIdx, nodes: &[Node]) -> SymbolBitMap {
-            let mut symbol_bitmap = HashMap::new();
-
-            // Depth-first search
-            let mut worklist: Vec<(NodeIdx, Vec<bool>)> = vec![(root, Vec::new())];
-            while let Some((NodeIdx(idx), path)) = worklist.pop() {
-                let node = &nodes[idx];
-                match *node {
-                    Node::Branch(left, right) => {
-                        let mut right_path = path.clone();
-                        right_path.push(true);
-                        worklist.push((right, right_path));
-                        let mut left_path = path;
-                        left_path.push(false);
-                        worklist.push((left, left_path));
-                    }
-                    Node::Leaf(symbol) => {
-                        symbol_bitmap.insert(symbol, path);
-                    }
-                }
-            }
-
-            symbol_bitmap
-        }
     }
 
     #[derive(Debug)]
@@ -358,12 +295,7 @@ pub(crate) mod tree {
         for (symbol, length) in symbol_lengths.iter().enumerate() {
             let entry = code_map.get_mut(length).expect("We just built this");
 
-            let symbol = match symbol {
-                0 => Symbol::RunA,
-                1 => Symbol::RunB,
-                idx if idx < symbol_lengths.len() - 1 => Symbol::Byte((idx - 1) as u16),
-                _ => Symbol::Eob,
-            };
+            let symbol = Symbol::from_index(symbol, symbol_lengths.len());
 
             symbol_bit_map.insert(symbol, entry.remove(0));
         }
@@ -520,6 +452,78 @@ pub(crate) mod tree {
             }
         }
 
+        /// Check the decode tables against an independent canonical encoder, for many random
+        /// code length tables.
+        mod test_random_tables {
+            use super::*;
+
+            /// Assign canonical codes the straightforward way: in order of length, and then of
+            /// symbol index, counting up and shifting left whenever the length grows.
+            fn reference_codes(lengths: &[u8]) -> Vec<Vec<bool>> {
+                let mut order: Vec<usize> = (0..lengths.len()).collect();
+                order.sort_by_key(|&symbol| (lengths[symbol], symbol));
+
+                let mut codes = vec![vec![]; lengths.len()];
+                let mut code: u64 = 0;
+                let mut previous_length = lengths[order[0]];
+                for (i, &symbol) in order.iter().enumerate() {
+                    if i > 0 {
+                        code = (code + 1) << (lengths[symbol] - previous_length);
+                    }
+                    previous_length = lengths[symbol];
+                    codes[symbol] = (0..lengths[symbol])
+                        .rev()
+                        .map(|bit| code >> bit & 1 == 1)
+                        .collect();
+                }
+
+                codes
+            }
+
+            #[test]
+            fn decode_every_symbol() {
+                // xorshift64* is plenty for test data.
+                let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+                let mut next = || {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32
+                };
+
+                let mut longest = 0;
+                for _ in 0..200 {
+                    let num_symbols = 3 + (next() % 256) as usize;
+                    // Spreading the frequencies over many orders of magnitude gives long codes.
+                    let frequencies: Vec<u32> =
+                        (0..num_symbols).map(|_| 1 << (next() % 24)).collect();
+                    let lengths = build_code_lengths(&frequencies, MAX_CODE_LENGTH);
+                    let codes = reference_codes(&lengths);
+                    longest = longest.max(*lengths.iter().max().unwrap());
+
+                    let mut bits: Vec<bool> = codes.iter().flatten().copied().collect();
+                    bits.resize(bits.len().next_multiple_of(8), false);
+                    let bytes: Vec<u8> = bits
+                        .chunks(8)
+                        .map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | *bit as u8))
+                        .collect();
+
+                    let tree: Tree = lengths.clone().try_into().unwrap();
+                    let mut bitstream = Bitstream::new(&bytes[..]);
+                    for index in 0..num_symbols {
+                        assert_eq!(
+                            tree.decode(&mut bitstream).unwrap(),
+                            Symbol::from_index(index, num_symbols),
+                            "lengths: {lengths:?}"
+                        );
+                    }
+                }
+
+                // Make sure the harness actually reached the length limit.
+                assert_eq!(longest, MAX_CODE_LENGTH);
+            }
+        }
+
         /// Test [`code_to_bitvec`].
         mod test_code_to_bitvec {
             use super::*;
@@ -567,44 +571,700 @@ pub(crate) mod tree {
     }
 }
 
+/// The longest Huffman code that bzip2 permits.
+pub(crate) const MAX_CODE_LENGTH: u8 = 20;
+
+/// The longest Huffman code that we write, which is shorter than [`MAX_CODE_LENGTH`] because bzip2
+/// itself never writes codes longer than this.
+const ENCODE_MAX_CODE_LENGTH: u8 = 17;
+
+/// Choose how many Huffman tables to use for a block of `num_symbols` symbols.
+///
+/// Each table costs header space, so small blocks use the minimum of 2 tables and large blocks
+/// use the maximum of 6. These are the same thresholds that bzip2 uses.
+pub(crate) fn num_tables(num_symbols: usize) -> u8 {
+    match num_symbols {
+        0..200 => 2,
+        200..600 => 3,
+        600..1200 => 4,
+        1200..2400 => 5,
+        _ => 6,
+    }
+}
+
+/// Build length-limited Huffman code lengths from the given symbol frequencies.
+///
+/// The returned `Vec` has one length per entry in `frequencies`, so every table built this way
+/// covers the full alphabet. A symbol can have a frequency of zero if the block's alphabet includes
+/// it but the group this table is used for never emits it. bzip2 still gives such symbols a valid
+/// code, so we treat their frequency as one, which gives them the longest lengths in the table.
+///
+/// If any code would be longer than `max_length`, the frequencies are flattened and the code
+/// lengths are rebuilt until they fit, which is the same approach bzip2 takes.
+pub(crate) fn build_code_lengths(frequencies: &[u32], max_length: u8) -> Vec<u8> {
+    debug_assert!(
+        !frequencies.is_empty(),
+        "frequencies must have at least one item"
+    );
+    // A single symbol still needs a code of at least one bit to be written into the stream.
+    if frequencies.len() == 1 {
+        return vec![1];
+    }
+
+    // Each weight holds the frequency above its lowest 8 bits, and the depth of its subtree in
+    // them, so that ties between equal frequencies go to the shallower subtree.
+    let mut weights: Vec<u64> = frequencies
+        .iter()
+        .map(|&f| u64::from(f.max(1)) << 8)
+        .collect();
+
+    loop {
+        let lengths = huffman_code_lengths(&weights);
+
+        if lengths.iter().all(|&length| length <= max_length) {
+            return lengths;
+        }
+
+        for weight in weights.iter_mut() {
+            *weight = (1 + (*weight >> 8) / 2) << 8;
+        }
+    }
+}
+
+/// Compute unrestricted Huffman code lengths for the given (non-zero) leaf weights.
+///
+/// This is a port of the binary heap in bzip2's `BZ2_hbMakeCodeLengths`, down to the order it
+/// pops equal weights in, so that we pick the same lengths that bzip2 does.
+fn huffman_code_lengths(weights: &[u64]) -> Vec<u8> {
+    /// Move the node at `position` up the heap until its parent is no heavier.
+    fn up_heap(heap: &mut [usize], weight: &[u64], mut position: usize) {
+        let node = heap[position];
+        while weight[node] < weight[heap[position >> 1]] {
+            heap[position] = heap[position >> 1];
+            position >>= 1;
+        }
+        heap[position] = node;
+    }
+
+    /// Move the node at `position` down the heap until neither child is lighter.
+    fn down_heap(heap: &mut [usize], weight: &[u64], mut position: usize) {
+        let last = heap.len() - 1;
+        let node = heap[position];
+        loop {
+            let mut child = position << 1;
+            if child > last {
+                break;
+            }
+            if child < last && weight[heap[child + 1]] < weight[heap[child]] {
+                child += 1;
+            }
+            if weight[node] < weight[heap[child]] {
+                break;
+            }
+            heap[position] = heap[child];
+            position = child;
+        }
+        heap[position] = node;
+    }
+
+    /// Take the lightest node off the heap.
+    fn pop(heap: &mut Vec<usize>, weight: &[u64]) -> usize {
+        let top = heap[1];
+        let last = heap.pop().expect("the heap is not empty");
+        if heap.len() > 1 {
+            heap[1] = last;
+            down_heap(heap, weight, 1);
+        }
+        top
+    }
+
+    // Node 0 is a sentinel that weighs nothing, and the heap's root sits above it at index 1.
+    // Leaves are nodes 1 to `weights.len()`, and branches are appended after them.
+    let mut weight = vec![0];
+    weight.extend_from_slice(weights);
+    let mut parents: Vec<Option<usize>> = vec![None; weight.len()];
+    let mut heap = vec![0];
+    for leaf in 1..weight.len() {
+        heap.push(leaf);
+        let position = heap.len() - 1;
+        up_heap(&mut heap, &weight, position);
+    }
+
+    while heap.len() > 2 {
+        let first = pop(&mut heap, &weight);
+        let second = pop(&mut heap, &weight);
+        let idx = weight.len();
+        parents.push(None);
+        parents[first] = Some(idx);
+        parents[second] = Some(idx);
+        let depth = (weight[first] & 0xff).max(weight[second] & 0xff);
+        weight.push(((weight[first] & !0xff) + (weight[second] & !0xff)) | (1 + depth));
+        heap.push(idx);
+        let position = heap.len() - 1;
+        up_heap(&mut heap, &weight, position);
+    }
+
+    (1..=weights.len())
+        .map(|leaf| {
+            let mut length = 0;
+            let mut node = leaf;
+            while let Some(parent) = parents[node] {
+                length += 1;
+                node = parent;
+            }
+            length
+        })
+        .collect()
+}
+
+/// The number of symbols coded with each selector.
+pub(crate) const GROUP_SIZE: usize = 50;
+
+/// How many times [`encode`] refines its tables, which is the same as bzip2.
+const REFINE_ITERATIONS: usize = 4;
+
 /// Encode RLE `Symbol`s into Huffman tables.
-pub(super) fn encode(data: &[rle2::Symbol]) -> HuffmanCodedData {
-    let mut huffman_symbols: Vec<Symbol> = Vec::new();
-    let mut symbol_weights: HashMap<Symbol, usize> = HashMap::new();
-    for symbol in data {
-        let symbol = symbol.into();
-        huffman_symbols.push(symbol);
-        symbol_weights
-            .entry(symbol)
-            .and_modify(|v| *v += 1)
-            .or_insert(1);
+///
+/// `alphabet_size` is the number of symbols in the block's alphabet, which is `RunA`, `RunB`, one
+/// `Byte` for every move-to-front value other than 0, and `Eob`. The `Eob` symbol is appended to
+/// the end of the data.
+///
+/// The symbols are coded in groups of [`GROUP_SIZE`], and each group picks whichever of
+/// [`num_tables`] tables codes it in the fewest bits. The tables are found the same way bzip2 does
+/// it: each one starts out favouring a different slice of the alphabet, and then each group is
+/// given to its cheapest table and each table is rebuilt from the groups it was given, a few times
+/// over.
+pub(super) fn encode(data: &[rle2::Symbol], alphabet_size: usize) -> HuffmanCodedData {
+    let mut huffman_symbols: Vec<Symbol> = data.iter().map(Into::into).collect();
+    huffman_symbols.push(Symbol::Eob);
+    let groups: Vec<Vec<usize>> = huffman_symbols
+        .chunks(GROUP_SIZE)
+        .map(|group| {
+            group
+                .iter()
+                .map(|symbol| symbol.index(alphabet_size))
+                .collect()
+        })
+        .collect();
+
+    let mut tables = initial_tables(&groups, alphabet_size, num_tables(huffman_symbols.len()));
+    let mut selectors = vec![0; groups.len()];
+    for _ in 0..REFINE_ITERATIONS {
+        let mut frequencies = vec![vec![0; alphabet_size]; tables.len()];
+        for (group, selector) in groups.iter().zip(&mut selectors) {
+            *selector = cheapest_table(&tables, group);
+            for &index in group {
+                frequencies[*selector][index] += 1;
+            }
+        }
+        tables = frequencies
+            .iter()
+            .map(|frequencies| build_code_lengths(frequencies, ENCODE_MAX_CODE_LENGTH))
+            .collect();
     }
+
+    code_groups(&huffman_symbols, tables, selectors)
+}
+
+/// Like [`encode`], but with a single table built from the whole block, which is much faster.
+///
+/// bzip2 requires at least two tables, so the table is written twice, and every group uses the
+/// first.
+pub(super) fn encode_single_table(data: &[rle2::Symbol], alphabet_size: usize) -> HuffmanCodedData {
+    let mut huffman_symbols: Vec<Symbol> = data.iter().map(Into::into).collect();
     huffman_symbols.push(Symbol::Eob);
-    symbol_weights.insert(Symbol::Eob, 1);
 
-    let tree = tree::Tree::new(symbol_weights);
+    let mut frequencies = vec![0; alphabet_size];
+    for symbol in &huffman_symbols {
+        frequencies[symbol.index(alphabet_size)] += 1;
+    }
+    let table = build_code_lengths(&frequencies, ENCODE_MAX_CODE_LENGTH);
+    let selectors = vec![0; huffman_symbols.len().div_ceil(GROUP_SIZE)];
 
-    let bits = tree.encode(data);
-    let block = HuffmanBlock {
-        tree_index: 0,
-        bitvec: bits,
-    };
+    code_groups(&huffman_symbols, vec![table.clone(), table], selectors)
+}
 
-    HuffmanCodedData {
-        trees: vec![tree],
-        blocks: vec![block],
+/// Code each group of [`GROUP_SIZE`] of `huffman_symbols` with the table its selector picks.
+fn code_groups(
+    huffman_symbols: &[Symbol],
+    tables: Vec<Vec<u8>>,
+    selectors: Vec<usize>,
+) -> HuffmanCodedData {
+    // Build the same trees that the decoder will, so that the codes are sure to agree.
+    let trees: Vec<tree::Tree> = tables
+        .iter()
+        .map(|table| {
+            tree::Tree::try_from(table.clone())
+                .expect("Code lengths from build_code_lengths should make a complete tree")
+        })
+        .collect();
+    let blocks = huffman_symbols
+        .chunks(GROUP_SIZE)
+        .zip(selectors)
+        .map(|(group, selector)| HuffmanBlock {
+            // There are at most 6 tables, so this always fits.
+            tree_index: selector as u8,
+            bitvec: group
+                .iter()
+                .flat_map(|symbol| trees[selector].code(symbol))
+                .map(|bit| *bit as u8)
+                .collect(),
+        })
+        .collect();
+
+    HuffmanCodedData { tables, blocks }
+}
+
+/// Make `num_tables` rough tables to start refining from.
+///
+/// The alphabet is split into `num_tables` slices with about the same number of symbols in each,
+/// and each table makes the symbols in its slice free and every other symbol expensive. These are
+/// only used to find which table each group should start with, so they don't need to be real
+/// code lengths.
+///
+/// This follows bzip2 exactly, including filling the tables from the last to the first and giving
+/// every other slice after the first one symbol fewer, so that we pick the same tables it does.
+fn initial_tables(groups: &[Vec<usize>], alphabet_size: usize, num_tables: u8) -> Vec<Vec<u8>> {
+    const EXPENSIVE: u8 = 15;
+
+    let mut frequencies = vec![0; alphabet_size];
+    for &index in groups.iter().flatten() {
+        frequencies[index] += 1;
+    }
+
+    let num_tables = usize::from(num_tables);
+    let mut tables = vec![vec![]; num_tables];
+    let mut remaining: usize = frequencies.iter().sum();
+    let mut low = 0;
+    for table in (0..num_tables).rev() {
+        let parts_left = table + 1;
+        let target = remaining / parts_left;
+        let mut high = low;
+        let mut slice_frequency = 0;
+        while slice_frequency < target && high < alphabet_size {
+            slice_frequency += frequencies[high];
+            high += 1;
+        }
+        if high > low + 1
+            && parts_left != num_tables
+            && parts_left != 1
+            && (num_tables - parts_left) % 2 == 1
+        {
+            high -= 1;
+            slice_frequency -= frequencies[high];
+        }
+        remaining -= slice_frequency;
+
+        tables[table] = (0..alphabet_size)
+            .map(|index| {
+                if (low..high).contains(&index) {
+                    0
+                } else {
+                    EXPENSIVE
+                }
+            })
+            .collect();
+        low = high;
     }
+
+    tables
+}
+
+/// Find the table that codes `group` in the fewest bits, preferring the first on a tie.
+fn cheapest_table(tables: &[Vec<u8>], group: &[usize]) -> usize {
+    let costs = tables.iter().map(|table| {
+        group
+            .iter()
+            .map(|&index| u32::from(table[index]))
+            .sum::<u32>()
+    });
+
+    (0..)
+        .zip(costs)
+        .min_by_key(|&(_, cost)| cost)
+        .map(|(table, _)| table)
+        .expect("there are always at least two tables")
 }
 
 /// Decode the `Symbol`s back to bytes.
+///
+/// The block ends at the first `Eob`, so anything after it is ignored.
 pub(super) fn decode(data: &[Symbol]) -> Vec<rle2::Symbol> {
     data.iter()
-        .filter_map(|symbol| {
-            if *symbol != Symbol::Eob {
-                Some(symbol.into())
-            } else {
-                None
-            }
-        })
+        .take_while(|symbol| **symbol != Symbol::Eob)
+        .map(Into::into)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test [`encode`].
+    mod test_encode {
+        use super::*;
+
+        /// Decode every group of `coded` with its table.
+        fn decode_groups(coded: &HuffmanCodedData, num_symbols: usize) -> Vec<Symbol> {
+            let mut symbols = vec![];
+            for block in &coded.blocks {
+                let tree: tree::Tree = coded.tables[block.tree_index as usize]
+                    .clone()
+                    .try_into()
+                    .unwrap();
+                let mut bits = block.bitvec.clone();
+                bits.resize(bits.len().next_multiple_of(8), 0);
+                let bytes: Vec<u8> = bits
+                    .chunks(8)
+                    .map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | bit))
+                    .collect();
+                let mut bitstream = Bitstream::new(&bytes[..]);
+                for _ in 0..GROUP_SIZE.min(num_symbols - symbols.len()) {
+                    symbols.push(tree.decode(&mut bitstream).unwrap());
+                }
+            }
+            symbols
+        }
+
+        /// A single table should be written twice, and code every group.
+        #[test]
+        fn single_table() {
+            let data: Vec<rle2::Symbol> = (0..1_000)
+                .map(|i| rle2::Symbol::from_index(i % 7, 9).unwrap())
+                .collect();
+
+            let coded = encode_single_table(&data, 9);
+
+            assert_eq!(coded.tables.len(), 2);
+            assert_eq!(coded.tables[0], coded.tables[1]);
+            assert_eq!(coded.blocks.len(), 21);
+            assert!(coded.blocks.iter().all(|block| block.tree_index == 0));
+            let expected: Vec<Symbol> = data.iter().map(Into::into).chain([Symbol::Eob]).collect();
+            assert_eq!(decode_groups(&coded, expected.len()), expected);
+        }
+
+        /// The smallest real block has one run and the end-of-block marker. It still needs two
+        /// tables, even though only one of them is used.
+        #[test]
+        fn two_symbols() {
+            let coded = encode(&[rle2::Symbol::RunA], 3);
+
+            assert_eq!(coded.tables.len(), 2);
+            assert_eq!(coded.blocks.len(), 1);
+            assert_eq!(decode_groups(&coded, 2), [Symbol::RunA, Symbol::Eob]);
+        }
+
+        /// The average number of bits per symbol once `data` is coded.
+        fn average_length(data: &[rle2::Symbol], alphabet_size: usize) -> f64 {
+            let coded = encode(data, alphabet_size);
+            let bits: usize = coded.blocks.iter().map(|block| block.bitvec.len()).sum();
+
+            bits as f64 / (data.len() + 1) as f64
+        }
+
+        /// Mostly `RunA` should code to fewer bits per symbol than every symbol equally often.
+        #[test]
+        fn skewed_is_shorter() {
+            let symbol = |i| rle2::Symbol::from_index(i, 11).unwrap();
+            let skewed: Vec<rle2::Symbol> = (0..1000)
+                .map(|i| {
+                    if i % 10 == 0 {
+                        symbol(i % 9 + 1)
+                    } else {
+                        symbol(0)
+                    }
+                })
+                .collect();
+            let uniform: Vec<rle2::Symbol> = (0..1000).map(|i| symbol(i % 10)).collect();
+
+            let skewed_length = average_length(&skewed, 11);
+            let uniform_length = average_length(&uniform, 11);
+
+            assert!(skewed_length < 2.0, "skewed averaged {skewed_length} bits");
+            assert!(
+                uniform_length > 3.0,
+                "uniform averaged {uniform_length} bits"
+            );
+        }
+
+        /// The table should give `Eob` the last code length, which is where the decoder looks for
+        /// it.
+        #[test]
+        fn eob_length() {
+            // RunA and Eob are the rarest, so they get the longest codes, and Byte(1) gets the
+            // shortest.
+            let data: Vec<rle2::Symbol> = (0..10)
+                .map(|i| match i {
+                    0 => rle2::Symbol::RunA,
+                    1..=3 => rle2::Symbol::RunB,
+                    _ => rle2::Symbol::Byte(1),
+                })
+                .collect();
+
+            let coded = encode(&data, 4);
+
+            let table = &coded.tables[usize::from(coded.blocks[0].tree_index)];
+            assert_eq!(table.len(), 4);
+            assert_eq!(table[3], 3);
+            assert_eq!(table[2], 1);
+            let tree: tree::Tree = table.clone().try_into().unwrap();
+            assert_eq!(tree.code(&Symbol::Eob).len(), 3);
+            assert_eq!(tree.code(&Symbol::Byte(1)).len(), 1);
+        }
+
+        /// The symbols should be split into groups of 50, and decode back to the input.
+        #[test]
+        fn groups() {
+            let data: Vec<rle2::Symbol> = (0..120)
+                .map(|i| match i % 4 {
+                    0 => rle2::Symbol::RunA,
+                    1 => rle2::Symbol::RunB,
+                    _ => rle2::Symbol::Byte((i % 7 + 1) as u8),
+                })
+                .collect();
+
+            let coded = encode(&data, 10);
+
+            assert_eq!(coded.blocks.len(), 3);
+            let mut expected: Vec<Symbol> = data.iter().map(Into::into).collect();
+            expected.push(Symbol::Eob);
+            assert_eq!(decode_groups(&coded, expected.len()), expected);
+        }
+
+        /// Data made of two very different halves should use different tables for each half, and
+        /// still decode back to the input.
+        #[test]
+        fn two_regions() {
+            // Mostly runs in the first half, and a spread of high bytes in the second.
+            let data: Vec<rle2::Symbol> = (0..2000)
+                .map(|i| match (i < 1000, i % 5) {
+                    (true, 0) => rle2::Symbol::Byte(1),
+                    (true, 1 | 2) => rle2::Symbol::RunB,
+                    (true, _) => rle2::Symbol::RunA,
+                    (false, _) => rle2::Symbol::Byte((i % 13 + 20) as u8),
+                })
+                .collect();
+
+            let coded = encode(&data, 40);
+
+            assert_eq!(coded.tables.len(), usize::from(num_tables(2001)));
+            // Each group is entirely in one half, and no table should be used by both.
+            let tables_used = |blocks: &[HuffmanBlock]| -> Vec<u8> {
+                let mut used: Vec<u8> = blocks.iter().map(|block| block.tree_index).collect();
+                used.sort();
+                used.dedup();
+                used
+            };
+            let first = tables_used(&coded.blocks[..20]);
+            let second = tables_used(&coded.blocks[20..]);
+            assert!(first.iter().all(|table| !second.contains(table)));
+            let mut expected: Vec<Symbol> = data.iter().map(Into::into).collect();
+            expected.push(Symbol::Eob);
+            assert_eq!(decode_groups(&coded, expected.len()), expected);
+        }
+
+        /// Using more than one table should code mixed data in fewer bits than one table could.
+        #[test]
+        fn more_tables_are_shorter() {
+            let data: Vec<rle2::Symbol> = (0..3000)
+                .map(|i| match i / 500 % 2 {
+                    0 => rle2::Symbol::Byte((i % 3 + 1) as u8),
+                    _ => rle2::Symbol::Byte((i % 7 + 10) as u8),
+                })
+                .collect();
+            let mut frequencies = vec![0; 20];
+            for symbol in &data {
+                frequencies[Symbol::from(symbol).index(20)] += 1;
+            }
+            frequencies[19] += 1;
+            let single_table = build_code_lengths(&frequencies, MAX_CODE_LENGTH);
+            let single_bits: u32 = frequencies
+                .iter()
+                .zip(&single_table)
+                .map(|(frequency, length)| frequency * u32::from(*length))
+                .sum();
+
+            let coded = encode(&data, 20);
+            let bits: usize = coded.blocks.iter().map(|block| block.bitvec.len()).sum();
+
+            assert!(bits < single_bits as usize, "{bits} >= {single_bits}");
+        }
+    }
+
+    /// Test [`decode`].
+    mod test_decode {
+        use super::*;
+
+        /// The symbols that [`encode`] codes should end with `Eob`, and decode back to the data.
+        #[test]
+        fn ends_with_eob() {
+            let data = [
+                rle2::Symbol::Byte(2),
+                rle2::Symbol::RunA,
+                rle2::Symbol::RunB,
+            ];
+            let mut symbols: Vec<Symbol> = data.iter().map(Into::into).collect();
+            symbols.push(Symbol::Eob);
+            let coded = encode(&data, 5);
+            let tree: tree::Tree = coded.tables[usize::from(coded.blocks[0].tree_index)]
+                .clone()
+                .try_into()
+                .unwrap();
+            let eob: Vec<u8> = tree
+                .code(&Symbol::Eob)
+                .iter()
+                .map(|bit| *bit as u8)
+                .collect();
+
+            assert!(coded.blocks[0].bitvec.ends_with(&eob));
+            assert_eq!(decode(&symbols), data);
+        }
+
+        /// Anything after `Eob` is not part of the block.
+        #[test]
+        fn ignores_after_eob() {
+            let symbols = [Symbol::RunB, Symbol::Eob, Symbol::Byte(1), Symbol::RunA];
+
+            assert_eq!(decode(&symbols), [rle2::Symbol::RunB]);
+        }
+    }
+
+    /// Test [`Symbol::index`] and [`Symbol::from_index`].
+    mod test_index {
+        use super::*;
+
+        /// `Eob` should be the highest symbol, above every `Byte`.
+        #[test]
+        fn eob_is_last() {
+            assert_eq!(Symbol::RunA.index(258), 0);
+            assert_eq!(Symbol::RunB.index(258), 1);
+            assert_eq!(Symbol::Byte(1).index(258), 2);
+            assert_eq!(Symbol::Byte(255).index(258), 256);
+            assert_eq!(Symbol::Eob.index(258), 257);
+        }
+
+        /// Every index should map to a symbol and back.
+        #[test]
+        fn roundtrip() {
+            for index in 0..258 {
+                assert_eq!(Symbol::from_index(index, 258).index(258), index);
+            }
+            assert_eq!(Symbol::from_index(3, 4), Symbol::Eob);
+        }
+
+        /// A `Byte` that would land on `Eob` means the alphabet is too small.
+        #[test]
+        #[should_panic]
+        fn byte_on_eob() {
+            let _index = Symbol::Byte(2).index(4);
+        }
+    }
+
+    /// Test [`num_tables`].
+    mod test_num_tables {
+        use super::*;
+
+        /// A very small block should use the fewest tables.
+        #[test]
+        fn small() {
+            assert_eq!(num_tables(3), 2);
+        }
+
+        /// A large block should use the most tables.
+        #[test]
+        fn large() {
+            assert_eq!(num_tables(900_000), 6);
+        }
+
+        /// Check each threshold.
+        #[test]
+        fn thresholds() {
+            let boundaries = [
+                (199, 2),
+                (200, 3),
+                (599, 3),
+                (600, 4),
+                (1199, 4),
+                (1200, 5),
+                (2399, 5),
+                (2400, 6),
+            ];
+
+            for (num_symbols, expected) in boundaries {
+                assert_eq!(num_tables(num_symbols), expected, "{num_symbols} symbols");
+            }
+        }
+    }
+
+    /// Test [`build_code_lengths`].
+    mod test_build_code_lengths {
+        use super::*;
+
+        /// Assert that the lengths describe a complete prefix code, i.e. that the Kraft sum is 1.
+        fn assert_complete(lengths: &[u8], max_length: u8) {
+            let kraft_sum: u64 = lengths
+                .iter()
+                .map(|&length| 1 << (max_length - length))
+                .sum();
+
+            assert_eq!(kraft_sum, 1 << max_length);
+        }
+
+        /// Symbols that never appear still get a valid code, and it is one of the longest.
+        #[test]
+        fn zero_frequencies() {
+            let frequencies = [10, 0, 7, 0, 0, 3, 25, 0];
+
+            let lengths = build_code_lengths(&frequencies, MAX_CODE_LENGTH);
+
+            assert_eq!(lengths.len(), frequencies.len());
+            assert!(lengths
+                .iter()
+                .all(|&length| (1..=MAX_CODE_LENGTH).contains(&length)));
+            assert_complete(&lengths, MAX_CODE_LENGTH);
+            let longest_used = frequencies
+                .iter()
+                .zip(&lengths)
+                .filter(|&(&frequency, _)| frequency != 0)
+                .map(|(_, &length)| length)
+                .max()
+                .unwrap();
+            assert!(frequencies
+                .iter()
+                .zip(&lengths)
+                .filter(|&(&frequency, _)| frequency == 0)
+                .all(|(_, &length)| length >= longest_used));
+        }
+
+        /// A table where no symbol is used at all is still a complete code.
+        #[test]
+        fn all_zero() {
+            let lengths = build_code_lengths(&[0; 258], MAX_CODE_LENGTH);
+
+            assert_complete(&lengths, MAX_CODE_LENGTH);
+        }
+
+        /// Fibonacci frequencies produce the deepest possible tree, so they must be limited.
+        #[test]
+        fn length_limited() {
+            let mut frequencies = vec![1_u32, 1];
+            while frequencies.len() < 40 {
+                let next = frequencies[frequencies.len() - 1] + frequencies[frequencies.len() - 2];
+                frequencies.push(next);
+            }
+
+            let lengths = build_code_lengths(&frequencies, MAX_CODE_LENGTH);
+
+            assert!(lengths.iter().all(|&length| length <= MAX_CODE_LENGTH));
+            assert_complete(&lengths, MAX_CODE_LENGTH);
+        }
+
+        /// Test with two symbols.
+        #[test]
+        fn two_symbols() {
+            let lengths = build_code_lengths(&[5, 0], MAX_CODE_LENGTH);
+
+            assert_eq!(lengths, [1, 1]);
+        }
+    }
+}
@@ -35,25 +35,83 @@
 //!     .expect("Cannot read decompressed data");
 //! assert_eq!(std::str::from_utf8(&buffer).unwrap(), peter_piper);
 //! ```
-use std::io::{self, Cursor, Read};
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+// Without `std`, only the transforms use the stages, so parts of them go unused.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
 
-use crate::burrows_wheeler::BwtEncoded;
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use std::{
+    convert::Infallible,
+    fs::File,
+    io::{self, BufReader, Cursor, Read, Seek, Write},
+    num::NonZeroUsize,
+    path::Path,
+};
 
 mod burrows_wheeler;
+#[cfg(feature = "std")]
+mod compressor;
+#[cfg(feature = "std")]
+mod crc;
+#[cfg(feature = "std")]
+mod decompressor;
+#[cfg(feature = "std")]
 mod file_format;
+#[cfg(feature = "framed")]
+pub mod framed;
+#[cfg(feature = "std")]
 mod huffman;
 mod move_to_front;
 mod rle1;
 mod rle2;
+#[cfg(feature = "std")]
+mod self_test;
+pub mod transforms;
+
+#[cfg(feature = "std")]
+pub use compressor::Compressor;
+#[cfg(feature = "std")]
+pub use decompressor::Decompressor;
+#[cfg(feature = "std")]
+pub use self_test::{self_test, SelfTestError};
 
 /// These are the possible errors that can occur during compression.
+#[cfg(feature = "std")]
 #[derive(Debug, thiserror::Error)]
 pub enum CompressError {
     /// An IO error occurred.
     #[error("I/O error: {0}")]
     IOError(io::Error),
+    /// A block boundary is out of order, or not inside the data.
+    #[error("Block boundary {index} is not after the one before it and inside the data")]
+    InvalidBoundary {
+        /// The position of the boundary in the list of boundaries.
+        index: usize,
+    },
+    /// The data between two block boundaries is too long to fit in a block.
+    #[error("Block {index} is {len} bytes after run-length encoding, but the limit is {max_len}")]
+    BlockTooLarge {
+        /// Which block is too long.
+        index: usize,
+        /// How long the block is after the first run-length encoding.
+        len: usize,
+        /// The longest a block can be at the chosen [`BlockSize`].
+        max_len: usize,
+    },
+    /// The compressed output would be longer than the limit it was given.
+    #[error("The compressed output reached {produced} bytes, but the limit is {cap}")]
+    OutputTooLarge {
+        /// How much output there was when compression stopped. This is at least `cap + 1`, but
+        /// less than the whole of the output would have been if compression had gone on.
+        produced: usize,
+        /// The limit.
+        cap: usize,
+    },
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for CompressError {
     fn from(value: io::Error) -> Self {
         CompressError::IOError(value)
@@ -61,195 +119,3632 @@ impl From<io::Error> for CompressError {
 }
 
 /// These are the possible errors that can occur during decompression.
+///
+/// Without the `std` feature, the variants that wrap I/O and parsing errors are left out, since
+/// only the [`transforms`] are there to return the others.
 #[derive(Debug, thiserror::Error)]
 pub enum DecompressError {
     /// An IO error occurred.
+    #[cfg(feature = "std")]
     #[error("I/O error: {0}")]
     IOError(io::Error),
     /// Unable to parse the bzip2 stream.
+    #[cfg(feature = "std")]
     #[error("Unable to parse the bzip2 stream: {0}")]
-    Parse(#[from] file_format::DecodeError),
+    Parse(file_format::DecodeError),
+    /// The data is in the legacy `bzip` format (with a `BZ0` header), which is not supported.
+    #[error("The data uses the legacy bzip format, which is not supported")]
+    UnsupportedFormat,
+    /// The data does not start with the `BZh` magic, or is missing the magic that marks the end of
+    /// the stream, so it is probably not `bzip2` data at all.
+    #[error("The data is missing a bzip2 magic number")]
+    MissingMagic,
     /// The runlength decoder encountered an invalid input.
-    #[error("Failed to decode at a runlength step")]
-    RunLengthDecode,
+    #[error("Failed to decode at a runlength step, {offset} bytes into its output")]
+    RunLengthDecode {
+        /// How many bytes of the block the failing stage had decoded before the bad run.
+        offset: usize,
+    },
+    /// The move-to-front decoder found an index that isn't in the block's alphabet.
+    #[error("Failed to decode at a move-to-front step, {offset} bytes into its output")]
+    MoveToFrontDecode {
+        /// How many bytes of the block the move-to-front decoder had decoded before the bad index.
+        offset: usize,
+    },
+    /// A block decoded to more bytes than the block size in its stream header allows, so it is
+    /// corrupt.
+    ///
+    /// This is checked before the first run-length decoding, as `bzip2` does, so decoding stops
+    /// before a corrupt block can use more memory than a valid one.
+    #[error("The block is longer than its stream's block size allows, after {offset} bytes")]
+    BlockTooLong {
+        /// How many bytes of the block had been decoded before the run that went past the limit.
+        offset: usize,
+    },
     /// The burrows-wheeler decoder encountered an invalid input.
     #[error("Failed to decode at a burrows-wheeler step")]
     BurrowsWheelerDecode(#[from] burrows_wheeler::DecodeError),
     /// The huffman decoder encountered an invalid input.
     #[error("Failed to decode at a huffman code step")]
     HuffmanDecode,
+    /// A block decoded to data whose CRC is not the one stored in its header, so the data is
+    /// corrupt.
+    #[error("The block CRC was {found:#010x}, but the header said {expected:#010x}")]
+    ChecksumMismatch {
+        /// The CRC stored in the block header.
+        expected: u32,
+        /// The CRC of the data that the block decoded to.
+        found: u32,
+    },
+    /// The block CRCs, combined, are not the CRC stored in the stream footer.
+    #[error("The combined CRC was {found:#010x}, but the footer said {expected:#010x}")]
+    StreamChecksumMismatch {
+        /// The CRC stored in the stream footer.
+        expected: u32,
+        /// The combined CRC of the blocks in the stream.
+        found: u32,
+    },
+    /// There is more data after the end of the stream.
+    #[error("There is data after the end of the bzip2 stream")]
+    TrailingData,
+    /// A [`framed`] input is too short to hold the length and CRC before the stream.
+    #[cfg(feature = "framed")]
+    #[error("The frame is too short to hold its length and CRC")]
+    FrameTruncated,
+    /// A [`framed`] stream decompressed to a different length than its frame records.
+    #[cfg(feature = "framed")]
+    #[error("The frame decompressed to {found} bytes, but it said {expected}")]
+    FrameLengthMismatch {
+        /// The length stored in the frame.
+        expected: u64,
+        /// The length of the data that the stream decompressed to.
+        found: u64,
+    },
+    /// A [`framed`] stream decompressed to data whose CRC is not the one its frame records.
+    #[cfg(feature = "framed")]
+    #[error("The frame CRC was {found:#010x}, but the frame said {expected:#010x}")]
+    FrameChecksumMismatch {
+        /// The CRC stored in the frame.
+        expected: u32,
+        /// The CRC of the data that the stream decompressed to.
+        found: u32,
+    },
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for DecompressError {
     fn from(value: io::Error) -> Self {
         DecompressError::IOError(value)
     }
 }
 
+#[cfg(feature = "std")]
+impl From<file_format::DecodeError> for DecompressError {
+    fn from(value: file_format::DecodeError) -> Self {
+        match value {
+            file_format::DecodeError::UnsupportedFormat => DecompressError::UnsupportedFormat,
+            file_format::DecodeError::InvalidHeader
+            | file_format::DecodeError::InvalidFooterMagic => DecompressError::MissingMagic,
+            file_format::DecodeError::TrailingData => DecompressError::TrailingData,
+            value => DecompressError::Parse(value),
+        }
+    }
+}
+
 impl From<rle1::Error> for DecompressError {
     fn from(value: rle1::Error) -> Self {
         match value {
-            rle1::Error::RunLengthInvalid(_) => DecompressError::RunLengthDecode,
-            rle1::Error::RunLengthTruncated => DecompressError::RunLengthDecode,
+            rle1::Error::RunLengthTruncated { offset } => {
+                DecompressError::RunLengthDecode { offset }
+            }
+            #[cfg(feature = "std")]
+            rle1::Error::Io(error) => DecompressError::IOError(error),
+        }
+    }
+}
+
+impl From<rle2::DecodeError> for DecompressError {
+    fn from(value: rle2::DecodeError) -> Self {
+        match value.error {
+            rle2::Error::RunTooLong | rle2::Error::ZeroByte => DecompressError::RunLengthDecode {
+                offset: value.offset,
+            },
+            rle2::Error::BlockTooLong => DecompressError::BlockTooLong {
+                offset: value.offset,
+            },
+        }
+    }
+}
+
+impl From<move_to_front::DecodeError> for DecompressError {
+    fn from(value: move_to_front::DecodeError) -> Self {
+        DecompressError::MoveToFrontDecode {
+            offset: value.offset,
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<huffman::Error> for DecompressError {
     fn from(_value: huffman::Error) -> Self {
         Self::HuffmanDecode
     }
 }
 
+/// The size of the blocks that the data is split into before it is compressed.
+///
+/// Larger blocks usually compress better, but take more memory to compress and decompress.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BlockSize {
+    /// 100 kB blocks, like `bzip2 -1`.
+    K100,
+    /// 200 kB blocks, like `bzip2 -2`.
+    K200,
+    /// 300 kB blocks, like `bzip2 -3`.
+    K300,
+    /// 400 kB blocks, like `bzip2 -4`.
+    K400,
+    /// 500 kB blocks, like `bzip2 -5`.
+    K500,
+    /// 600 kB blocks, like `bzip2 -6`.
+    K600,
+    /// 700 kB blocks, like `bzip2 -7`.
+    K700,
+    /// 800 kB blocks, like `bzip2 -8`.
+    K800,
+    /// 900 kB blocks, like `bzip2 -9`.
+    #[default]
+    K900,
+}
+
+#[cfg(feature = "std")]
+impl BlockSize {
+    /// The level that goes in the stream header, from 1 to 9.
+    pub fn level(self) -> u8 {
+        self as u8 + 1
+    }
+
+    fn from_level(level: u8) -> Option<Self> {
+        let block_size = match level {
+            1 => BlockSize::K100,
+            2 => BlockSize::K200,
+            3 => BlockSize::K300,
+            4 => BlockSize::K400,
+            5 => BlockSize::K500,
+            6 => BlockSize::K600,
+            7 => BlockSize::K700,
+            8 => BlockSize::K800,
+            9 => BlockSize::K900,
+            _ => return None,
+        };
+
+        Some(block_size)
+    }
+
+    /// How many bytes a block may reach after the first run-length stage before it is cut.
+    ///
+    /// This is a little under the nominal size, with the same margin that libbzip2 leaves, so the
+    /// run that is added as a block reaches it still fits.
+    fn max_block_len(self) -> usize {
+        usize::from(self.level()) * 100_000 - 19
+    }
+}
+
+/// Settings for [`compress_with`].
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompressOptions {
+    /// The size of the blocks to split the data into.
+    pub block_size: BlockSize,
+    /// The most bytes to buffer for a block, if that should be less than `block_size`.
+    ///
+    /// Blocks are cut once they reach this many bytes after the first run-length encoding, which
+    /// keeps less in memory at once, at the cost of a worse ratio. The stream header still declares
+    /// `block_size`, which is no problem for decoders, since every block is smaller than that. This
+    /// is clamped to at least 5 bytes, which is the longest a single run can be.
+    pub max_block_buffer: Option<usize>,
+    /// Code each block with a single Huffman table, rather than choosing between several.
+    ///
+    /// This is much faster, since the tables aren't refined over several passes, but the output is
+    /// usually larger. bzip2 needs at least two tables, so the table is written twice.
+    pub single_table: bool,
+}
+
+#[cfg(feature = "std")]
+impl CompressOptions {
+    /// How many bytes a block may reach after the first run-length stage, with these options.
+    fn max_block_len(self) -> usize {
+        let max_len = self.block_size.max_block_len();
+
+        self.max_block_buffer
+            .map_or(max_len, |cap| cap.clamp(rle1::MAX_RUN_LENGTH, max_len))
+    }
+}
+
 /// Compress the given data.
-pub fn compress<R>(mut data: R) -> Result<impl Read, CompressError>
+///
+/// This uses the default [`CompressOptions`], which split the data into 900 kB blocks.
+#[cfg(feature = "std")]
+pub fn compress<R>(data: R) -> Result<impl Read, CompressError>
 where
     R: Read,
 {
-    let mut all_data = vec![];
-    data.read_to_end(&mut all_data)?;
+    compress_with(data, CompressOptions::default())
+}
 
-    let rle_data = rle1::encode(&all_data);
-    // TODO: Origin pointer is unused here. When we write out the file in the correct format, we
-    // will use it then.
-    let burrows_wheeler_data = burrows_wheeler::encode(&rle_data);
-    let move_to_front_data = move_to_front::encode(&burrows_wheeler_data.data);
-    let rle2_data = rle2::encode(&move_to_front_data);
-    let _huffman_data = huffman::encode(&rle2_data);
-    // This is a stub to satisfy the return type. We need to put something here that can turn
-    // huffman_data into the bzip2 file format.
-    let file_data = Vec::new();
+/// Compress the given data with the given `options`.
+#[cfg(feature = "std")]
+pub fn compress_with<R>(data: R, options: CompressOptions) -> Result<impl Read, CompressError>
+where
+    R: Read,
+{
+    let (compressed, _stats) = compress_with_stats(data, options)?;
 
-    let cursor = Cursor::new(file_data);
+    Ok(compressed)
+}
 
-    Ok(cursor)
+/// Statistics about a compression, as returned by [`compress_with_stats`].
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompressStats {
+    /// The number of bytes that were read from the input.
+    pub input_len: u64,
+    /// The number of bytes of compressed output.
+    pub output_len: u64,
+    /// The number of blocks that the input was split into.
+    pub block_count: u32,
 }
 
-/// Decompress the given data.
+/// Compress the given data with the given `options`, and report how much was read and written.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{compress_with_stats, CompressOptions};
+///
+/// let data = vec![b'z'; 10_000];
+/// let (_compressed, stats) = compress_with_stats(&data[..], CompressOptions::default()).unwrap();
+///
+/// assert_eq!(stats.input_len, 10_000);
+/// assert!(stats.output_len < 100);
+/// assert_eq!(stats.block_count, 1);
+/// ```
+#[cfg(feature = "std")]
+pub fn compress_with_stats<R>(
+    mut data: R,
+    options: CompressOptions,
+) -> Result<(impl Read, CompressStats), CompressError>
+where
+    R: Read,
+{
+    let mut compressor = Compressor::new(vec![], options);
+    let input_len = io::copy(&mut data, &mut compressor)?;
+    let (file_data, block_count) = compressor.finish_with_block_count()?;
+
+    let stats = CompressStats {
+        input_len,
+        output_len: file_data.len() as u64,
+        block_count,
+    };
+
+    Ok((Cursor::new(file_data), stats))
+}
+
+/// What compressing some data would do, as worked out by [`validate_compress`].
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompressPlan {
+    /// The number of blocks the data would be split into.
+    pub block_count: u32,
+    /// The most Huffman tables each block would use, from 2 to 6.
+    pub tables: Vec<u8>,
+    /// The bytes of output that would go to the stream header and footer and the block headers.
+    ///
+    /// This leaves out each block's symbol map, Huffman tables and selectors, since their size
+    /// depends on the data.
+    pub header_overhead: u64,
+}
+
+/// Work out what compressing `data_len` bytes with the given `options` would do, without
+/// compressing anything.
+///
+/// This assumes that the first run-length encoding leaves the length of the data alone, and that
+/// every block is filled right up, which is the case for data with no byte twice in a row. Runs of
+/// five or more of the same byte shrink, so data with them can need fewer blocks, and fewer tables
+/// for each, than this says. Runs of exactly four grow by a byte, and a run is never split between
+/// two blocks, so data that only just fits can need another block.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{validate_compress, BlockSize, CompressOptions};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let options = CompressOptions {
+///     block_size: BlockSize::K100,
+///     ..Default::default()
+/// };
+/// let plan = validate_compress(250_000, &options)?;
+///
+/// assert_eq!(plan.block_count, 3);
+/// assert_eq!(plan.tables, [6, 6, 6]);
+/// # Ok(())
+/// # }
+/// ```
 ///
 /// # Errors
 ///
-/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
-pub fn decompress<R>(mut data: R) -> Result<impl Read, DecompressError>
+/// Every length and set of options can be compressed, so this can't fail at the moment, but
+/// returns a `Result` so that options that can't be met may be rejected in future.
+#[cfg(feature = "std")]
+pub fn validate_compress(
+    data_len: usize,
+    options: &CompressOptions,
+) -> Result<CompressPlan, CompressError> {
+    let max_len = options.max_block_len();
+    let block_count = data_len.div_ceil(max_len);
+
+    // Each byte of a block becomes at most one symbol, and there is an end of block symbol too.
+    let tables = (0..block_count)
+        .map(|block| {
+            let len = max_len.min(data_len - block * max_len);
+            huffman::num_tables(len + 1)
+        })
+        .collect();
+
+    // The stream header is 4 bytes and the footer 10. Each block header has a 48 bit magic, a 32
+    // bit CRC, a randomized bit and a 24 bit origin pointer.
+    let bits = 32 + 80 + block_count as u64 * (48 + 32 + 1 + 24);
+
+    Ok(CompressPlan {
+        block_count: block_count as u32,
+        tables,
+        header_overhead: bits.div_ceil(8),
+    })
+}
+
+/// Compress the given data with the given `options`, encoding up to `threads` blocks at once.
+///
+/// The blocks in a stream don't depend on each other, so they can be encoded on separate threads
+/// and then written out in order, like `pbzip2` does. The output is the same as
+/// [`compress_with`] gives for the same `options`. At most `threads` blocks are held in memory at
+/// a time.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{io::Read, num::NonZeroUsize};
+///
+/// use beeziptoo::{compress_parallel, compress_with, CompressOptions};
+///
+/// let data = b"If Peter Piper picked a peck of pickled peppers".repeat(100);
+/// let threads = NonZeroUsize::new(4).unwrap();
+///
+/// let mut parallel = vec![];
+/// compress_parallel(&data[..], CompressOptions::default(), threads)
+///     .unwrap()
+///     .read_to_end(&mut parallel)
+///     .unwrap();
+/// let mut serial = vec![];
+/// compress_with(&data[..], CompressOptions::default())
+///     .unwrap()
+///     .read_to_end(&mut serial)
+///     .unwrap();
+/// assert_eq!(parallel, serial);
+/// ```
+#[cfg(feature = "std")]
+pub fn compress_parallel<R>(
+    mut data: R,
+    options: CompressOptions,
+    threads: NonZeroUsize,
+) -> Result<impl Read, CompressError>
 where
     R: Read,
 {
-    let mut all_data = vec![];
-    let mut decompressed_data = vec![];
-    data.read_to_end(&mut all_data)?;
+    let mut output = file_format::bitstream::BitWriter::new(vec![]);
+    file_format::write_stream_header(&mut output, options.block_size.level())?;
+    let mut stream_crc = file_format::StreamCrc::default();
+    let mut scratches: Vec<EncodeScratch> = (0..threads.get())
+        .map(|_| EncodeScratch::default())
+        .collect();
+    let mut pending = Vec::with_capacity(threads.get());
+    let mut add_block = |rle_data, crc| {
+        pending.push((rle_data, crc));
+        if pending.len() == threads.get() {
+            write_blocks(
+                &mut output,
+                &mut stream_crc,
+                &mut scratches,
+                &pending,
+                options.single_table,
+            )?;
+            pending.clear();
+        }
+        Ok::<_, io::Error>(())
+    };
 
-    let un_file_data = file_format::decode(&all_data)?;
+    let mut encoder = rle1::BlockEncoder::new(options.max_block_len());
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let len = match data.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+        encoder.write(&buffer[..len], &mut add_block)?;
+    }
+    encoder.finish(&mut add_block)?;
+    write_blocks(
+        &mut output,
+        &mut stream_crc,
+        &mut scratches,
+        &pending,
+        options.single_table,
+    )?;
+    file_format::write_stream_footer(&mut output, stream_crc)?;
 
-    for block in &un_file_data {
-        let un_huffman_data = huffman::decode(block.symbols());
-        let un_rle2 = rle2::decode(&un_huffman_data);
-        let un_move_to_front_data = move_to_front::decode(&un_rle2, block.symbol_stack());
-        let un_burrows_wheeler_data = burrows_wheeler::decode(&BwtEncoded::new(
-            un_move_to_front_data,
-            block.origin_pointer(),
-        ))?;
-        let mut un_rle_data = rle1::decode(&un_burrows_wheeler_data)?;
-        decompressed_data.append(&mut un_rle_data);
+    Ok(Cursor::new(output.into_inner()?))
+}
+
+/// Compress the given data with the given `options`, starting a new block at each of
+/// `boundaries`.
+///
+/// Each boundary is a position in `data`, so there is one block more than there are boundaries,
+/// unless `data` is empty. This is for containers that want blocks to line up with their own
+/// entries, so that a block never holds bytes from two entries. Blocks are only ever cut at the
+/// boundaries, so each stretch of data between them has to fit in one block.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{block_bit_ranges, compress_with_boundaries, decompress_to_vec};
+/// use beeziptoo::CompressOptions;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let data = b"Peter Piper picked a peck";
+/// let compressed = compress_with_boundaries(data, &[6, 13], CompressOptions::default())?;
+///
+/// assert_eq!(block_bit_ranges(&compressed[..])?.len(), 3);
+/// assert_eq!(decompress_to_vec(&compressed)?, data);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// If the boundaries are not in ascending order, or any of them is 0 or not before the end of
+/// `data`, this returns [`CompressError::InvalidBoundary`]. If the data between two boundaries
+/// doesn't fit in a block, this returns [`CompressError::BlockTooLarge`].
+#[cfg(feature = "std")]
+pub fn compress_with_boundaries(
+    data: &[u8],
+    boundaries: &[usize],
+    options: CompressOptions,
+) -> Result<Vec<u8>, CompressError> {
+    let mut previous = 0;
+    for (index, &boundary) in boundaries.iter().enumerate() {
+        if boundary <= previous || boundary >= data.len() {
+            return Err(CompressError::InvalidBoundary { index });
+        }
+        previous = boundary;
     }
 
-    let cursor = Cursor::new(decompressed_data);
+    let starts = core::iter::once(0).chain(boundaries.iter().copied());
+    let ends = boundaries
+        .iter()
+        .copied()
+        .chain(core::iter::once(data.len()));
+    let max_len = options.max_block_len();
+    let mut blocks = vec![];
+    for (index, (start, end)) in starts.zip(ends).enumerate() {
+        let segment = &data[start..end];
+        if segment.is_empty() {
+            continue;
+        }
+        let rle_data = rle1::encode(segment);
+        if rle_data.len() > max_len {
+            return Err(CompressError::BlockTooLarge {
+                index,
+                len: rle_data.len(),
+                max_len,
+            });
+        }
+        blocks.push((rle_data, crc::checksum(segment)));
+    }
 
-    Ok(cursor)
+    let mut output = file_format::bitstream::BitWriter::new(vec![]);
+    file_format::write_stream_header(&mut output, options.block_size.level())?;
+    let mut stream_crc = file_format::StreamCrc::default();
+    let mut scratch = EncodeScratch::default();
+    for (rle_data, crc) in &blocks {
+        let (block, _sizes) = scratch.encode_block(rle_data, *crc, options.single_table);
+        file_format::write_block(&mut output, &block)?;
+        stream_crc.combine(*crc);
+    }
+    file_format::write_stream_footer(&mut output, stream_crc)?;
+
+    Ok(output.into_inner()?)
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        io::Write as _,
-        process::{Command, Stdio},
-    };
+/// Encode each of `blocks` on its own thread, with one of the `scratches` each, and then write
+/// them to `output` in order.
+#[cfg(feature = "std")]
+fn write_blocks<W>(
+    output: &mut file_format::bitstream::BitWriter<W>,
+    stream_crc: &mut file_format::StreamCrc,
+    scratches: &mut [EncodeScratch],
+    blocks: &[(Vec<u8>, u32)],
+    single_table: bool,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    debug_assert!(blocks.len() <= scratches.len());
 
-    use super::*;
+    let encoded: Vec<file_format::EncodedBlock> = std::thread::scope(|scope| {
+        let handles: Vec<_> = blocks
+            .iter()
+            .zip(scratches)
+            .map(|((rle_data, crc), scratch)| {
+                scope.spawn(move || scratch.encode_block(rle_data, *crc, single_table).0)
+            })
+            .collect();
 
-    #[test]
-    fn can_we_read() {
-        let peter_piper = "If Peter Piper picked a peck of pickled peppers, where's the peck of pickled peppers Peter Piper picked?????";
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Encoding a block should not panic"))
+            .collect()
+    });
+    for (block, (_rle_data, crc)) in encoded.iter().zip(blocks) {
+        file_format::write_block(output, block)?;
+        stream_crc.combine(*crc);
+    }
 
-        for level in 1..=9 {
-            let mut child = Command::new("bzip2")
-                .arg("-c")
-                .arg(format!("-{level}"))
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .unwrap();
-            {
-                let mut stdin = child.stdin.take().unwrap();
-                stdin.write_all(peter_piper.as_bytes()).unwrap();
-            }
-            let bytes = child.wait_with_output().unwrap().stdout;
+    Ok(())
+}
 
-            let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+/// Compress `data` straight into a `Vec`.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{compress_to_vec, decompress_to_vec};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// assert_eq!(decompress_to_vec(&compress_to_vec(b"hi")?)?, b"hi");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// This can't fail at the moment, since nothing is read from or written to I/O, but returns a
+/// `Result` so that it matches [`compress`].
+#[cfg(feature = "std")]
+pub fn compress_to_vec(data: &[u8]) -> Result<Vec<u8>, CompressError> {
+    let mut compressor = Compressor::new(vec![], CompressOptions::default());
+    compressor.write_all(data)?;
 
-            let mut buffer = vec![];
-            let _num_bytes = data
-                .read_to_end(&mut buffer)
-                .expect("Cannot read decompressed data");
-            assert_eq!(std::str::from_utf8(&buffer).unwrap(), peter_piper);
+    Ok(compressor.finish()?)
+}
+
+/// Compress the given data with the given `options`, failing if the output would be longer than
+/// `cap` bytes.
+///
+/// This is for when the output has to fit somewhere with a hard limit, and a partial stream is no
+/// use. The output is checked as each block is written, so compression stops soon after the limit
+/// is passed, rather than compressing everything first.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{compress_capped, CompressError, CompressOptions};
+///
+/// let data = vec![b'z'; 10_000];
+/// assert!(compress_capped(&data[..], CompressOptions::default(), 100).is_ok());
+/// assert!(matches!(
+///     compress_capped(&data[..], CompressOptions::default(), 10),
+///     Err(CompressError::OutputTooLarge { cap: 10, .. })
+/// ));
+/// ```
+///
+/// # Errors
+///
+/// If the output would be longer than `cap`, this returns [`CompressError::OutputTooLarge`]. It
+/// also fails if reading from `data` fails.
+#[cfg(feature = "std")]
+pub fn compress_capped<R>(
+    mut data: R,
+    options: CompressOptions,
+    cap: usize,
+) -> Result<Vec<u8>, CompressError>
+where
+    R: Read,
+{
+    let check = |produced| {
+        if produced > cap {
+            return Err(CompressError::OutputTooLarge { produced, cap });
         }
+        Ok(())
+    };
+
+    let mut compressor = Compressor::new(vec![], options);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let len = match data.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+        compressor.write_all(&buffer[..len])?;
+        check(compressor.get_ref().len())?;
     }
+    let output = compressor.finish()?;
+    check(output.len())?;
 
-    // This was found by the fuzzer.
-    #[test]
-    fn four_bytes() {
-        let sample = [0x04, 0x00, 0x00, 0x00];
+    Ok(output)
+}
 
-        let mut child = Command::new("bzip2")
-            .arg("-c")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        {
-            let mut stdin = child.stdin.take().unwrap();
-            stdin.write_all(&sample).unwrap();
-        }
-        let bytes = child.wait_with_output().unwrap().stdout;
+/// Compress the given data with the given `options`, and hand the output to `f` by reference.
+///
+/// This is for when only part of the output is wanted, or it is going somewhere else anyway, so
+/// that the caller doesn't have to hold on to a `Vec` of it. The output is freed once `f` returns,
+/// and whatever `f` returns is passed back.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{with_compressed, CompressOptions};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let len = with_compressed(&b"Peter Piper"[..], CompressOptions::default(), <[u8]>::len)?;
+///
+/// assert!(len > 0);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// This function is failable since reading from `data` can fail.
+#[cfg(feature = "std")]
+pub fn with_compressed<R, F, T>(
+    mut data: R,
+    options: CompressOptions,
+    f: F,
+) -> Result<T, CompressError>
+where
+    R: Read,
+    F: FnOnce(&[u8]) -> T,
+{
+    let mut compressor = Compressor::new(vec![], options);
+    io::copy(&mut data, &mut compressor)?;
+    let output = compressor.finish()?;
 
-        let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+    Ok(f(&output))
+}
 
-        let mut buffer = vec![];
-        let _bytes = data
-            .read_to_end(&mut buffer)
-            .expect("Cannot read decompressed data");
-        assert_eq!(buffer, sample);
+/// Compress the given data with the given `options` into `output`, starting wherever `output`'s
+/// position is, and return how many bytes were written.
+///
+/// This is for rewriting a compressed region in the middle of a larger file: nothing before the
+/// current position is touched, and the returned length says where the stream ends.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::{Cursor, Seek, SeekFrom};
+///
+/// use beeziptoo::{compress_to_seekable, decompress_to_vec, CompressOptions};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut file = Cursor::new(vec![0; 10]);
+/// file.seek(SeekFrom::Start(10))?;
+/// let len = compress_to_seekable(&b"Peter Piper"[..], &mut file, CompressOptions::default())?;
+///
+/// let file = file.into_inner();
+/// assert_eq!(file.len() as u64, 10 + len);
+/// assert_eq!(decompress_to_vec(&file[10..])?, b"Peter Piper");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// If reading from `data`, or writing to or seeking in `output`, fails.
+#[cfg(feature = "std")]
+pub fn compress_to_seekable<R, W>(
+    mut data: R,
+    mut output: W,
+    options: CompressOptions,
+) -> Result<u64, CompressError>
+where
+    R: Read,
+    W: Write + Seek,
+{
+    let start = output.stream_position()?;
+    let mut compressor = Compressor::new(&mut output, options);
+    io::copy(&mut data, &mut compressor)?;
+    compressor.finish()?;
+
+    Ok(output.stream_position()? - start)
+}
+
+/// Compress each of `inputs` into a stream of its own, one after the other.
+///
+/// Each stream can be decompressed without the others, and [`decompress`] reads concatenated
+/// streams one after another, so decompressing the whole output gives every input joined together.
+/// This is the same as what `bzip2` gives for each file on its own, concatenated.
+///
+/// With no inputs there are no streams, and so no output, which isn't a valid `bzip2` file.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{compress_multi, decompress_to_vec, CompressOptions};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let compressed = compress_multi(["Peter ", "Piper"], CompressOptions::default())?;
+///
+/// assert_eq!(decompress_to_vec(&compressed)?, b"Peter Piper");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// This can't fail at the moment, since nothing is read from or written to I/O, but returns a
+/// `Result` so that it matches [`compress`].
+#[cfg(feature = "std")]
+pub fn compress_multi<I>(inputs: I, options: CompressOptions) -> Result<Vec<u8>, CompressError>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut output = vec![];
+    for input in inputs {
+        let mut compressor = Compressor::new(output, options);
+        compressor.write_all(input.as_ref())?;
+        output = compressor.finish()?;
     }
 
-    // This was found by the fuzzer.
-    //
-    // The root cause was failing to do the MTF decode after parsing the list of tree selector
-    // indices.
-    #[test]
-    fn parsing_selectors() {
-        let sample = [
-            0, 255, 255, 72, 255, 255, 255, 255, 0, 5, 2, 255, 7, 255, 0, 0, 0, 0, 1, 0, 2, 255, 0,
-            255, 255, 255, 255, 196, 251, 255, 183, 0, 0, 0, 182, 5, 0, 0, 255, 183, 0, 0, 0, 182,
-            5, 0, 0, 0,
-        ];
+    Ok(output)
+}
 
-        let mut child = Command::new("bzip2")
-            .arg("-c")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        {
-            let mut stdin = child.stdin.take().unwrap();
-            stdin.write_all(&sample).unwrap();
-        }
-        let bytes = child.wait_with_output().unwrap().stdout;
+/// Compress the file at `path` with the given `options`.
+///
+/// The file is read a piece at a time, so only the output and the block being filled are held in
+/// memory, rather than the whole file as well.
+///
+/// # Errors
+///
+/// If the file can't be opened or read.
+#[cfg(feature = "std")]
+pub fn compress_file(path: &Path, options: CompressOptions) -> Result<Vec<u8>, CompressError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut compressor = Compressor::new(vec![], options);
+    io::copy(&mut file, &mut compressor)?;
 
-        let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+    Ok(compressor.finish()?)
+}
 
-        let mut buffer = vec![];
-        let _bytes = data
-            .read_to_end(&mut buffer)
-            .expect("Cannot read decompressed data");
-        assert_eq!(buffer, sample);
+/// Reusable buffers for compression.
+///
+/// Like [`DecodeScratch`], this holds on to a buffer for each stage of encoding so that they can
+/// be reused from block to block. It carries no state between blocks other than the allocations
+/// themselves.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+struct EncodeScratch {
+    /// The block repeated twice, which the rotations are sorted through.
+    text: Vec<usize>,
+    /// The index that each sorted rotation starts at.
+    rotations: Vec<usize>,
+    /// The output of the Burrows-Wheeler transform.
+    burrows_wheeler: Vec<u8>,
+    /// The output of the move-to-front transform.
+    move_to_front: Vec<u8>,
+    /// The output of the run-length (stage 2) encoder.
+    rle2: Vec<rle2::Symbol>,
+}
+
+#[cfg(feature = "std")]
+impl EncodeScratch {
+    /// Run a single block through the rest of the stages of compression.
+    ///
+    /// `rle_data` is the block after the first run-length encoding, and `crc` is the CRC of the
+    /// block before it. If `single_table`, the block is coded with a single Huffman table, as for
+    /// [`CompressOptions::single_table`]. This also returns the size of the block after each stage.
+    fn encode_block(
+        &mut self,
+        rle_data: &[u8],
+        crc: u32,
+        single_table: bool,
+    ) -> (file_format::EncodedBlock, StageSizes) {
+        let origin_pointer = burrows_wheeler::encode_into(
+            rle_data,
+            &mut self.text,
+            &mut self.rotations,
+            &mut self.burrows_wheeler,
+        );
+        // Only the bytes that are in the block go in the move-to-front alphabet, which keeps the
+        // move-to-front values, and so the Huffman alphabet, small.
+        let symbol_stack = move_to_front::used_symbols(&self.burrows_wheeler);
+        self.move_to_front.clear();
+        move_to_front::encode_with_alphabet_into(
+            &self.burrows_wheeler,
+            symbol_stack.clone(),
+            &mut self.move_to_front,
+        )
+        .expect("Every byte in the block is in its own used symbols");
+        self.rle2.clear();
+        rle2::encode_into(&self.move_to_front, &mut self.rle2);
+        // The alphabet is RunA, RunB, a Byte for every move-to-front value but 0, and Eob.
+        let alphabet_size = symbol_stack.0.len() + 2;
+        let huffman_data = if single_table {
+            huffman::encode_single_table(&self.rle2, alphabet_size)
+        } else {
+            huffman::encode(&self.rle2, alphabet_size)
+        };
+        let huffman_bits: usize = huffman_data
+            .blocks
+            .iter()
+            .map(|block| block.bitvec.len())
+            .sum();
+
+        let sizes = StageSizes {
+            rle1: rle_data.len(),
+            burrows_wheeler: self.burrows_wheeler.len(),
+            move_to_front: self.move_to_front.len(),
+            rle2: self.rle2.len(),
+            huffman: huffman_bits.div_ceil(8),
+        };
+        let block = file_format::EncodedBlock {
+            crc,
+            origin_pointer,
+            symbol_stack,
+            huffman: huffman_data,
+        };
+
+        (block, sizes)
+    }
+}
+
+/// The size of the data after each stage of compression, as reported by [`stage_sizes`].
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StageSizes {
+    /// The number of bytes after the first run-length encoding.
+    pub rle1: usize,
+    /// The number of bytes after the Burrows-Wheeler transform.
+    pub burrows_wheeler: usize,
+    /// The number of bytes after the move-to-front transform.
+    pub move_to_front: usize,
+    /// The number of symbols after the second run-length encoding.
+    pub rle2: usize,
+    /// The number of bytes of Huffman coded data, including the end-of-block symbols, with each
+    /// block rounded up to a whole byte. This does not count the block headers or the Huffman
+    /// tables.
+    pub huffman: usize,
+}
+
+/// Measure how big the data is after each stage of compressing it at the given `level`.
+///
+/// This runs the same stages as [`compress`], so it shows where the space is saved for a given
+/// input. The sizes are summed over every block.
+///
+/// # Panics
+///
+/// If `level` is not between 1 and 9.
+#[cfg(feature = "std")]
+pub fn stage_sizes(data: &[u8], level: u8) -> StageSizes {
+    let block_size =
+        BlockSize::from_level(level).unwrap_or_else(|| panic!("level must be 1-9 but was {level}"));
+
+    let mut sizes = StageSizes::default();
+    let mut scratch = EncodeScratch::default();
+    let mut add_block = |rle_data: Vec<u8>, crc| {
+        let (_block, block_sizes) = scratch.encode_block(&rle_data, crc, false);
+        sizes.rle1 += block_sizes.rle1;
+        sizes.burrows_wheeler += block_sizes.burrows_wheeler;
+        sizes.move_to_front += block_sizes.move_to_front;
+        sizes.rle2 += block_sizes.rle2;
+        sizes.huffman += block_sizes.huffman;
+        Ok::<_, Infallible>(())
+    };
+
+    let mut encoder = rle1::BlockEncoder::new(block_size.max_block_len());
+    let Ok(()) = encoder.write(data, &mut add_block);
+    let Ok(()) = encoder.finish(&mut add_block);
+
+    sizes
+}
+
+/// Decompress the given data.
+///
+/// Like `bzip2`, this accepts several streams concatenated together, and decompresses each of
+/// them in turn.
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn decompress<R>(data: R) -> Result<impl Read, DecompressError>
+where
+    R: Read,
+{
+    let mut decompressed_data = vec![];
+
+    decompress_each_block(data, |_, block| decompressed_data.extend_from_slice(block))?;
+
+    let cursor = Cursor::new(decompressed_data);
+
+    Ok(cursor)
+}
+
+/// What [`decompress_with`] does when a block or stream CRC doesn't match.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CrcMode {
+    /// Fail with [`DecompressError::ChecksumMismatch`] or
+    /// [`DecompressError::StreamChecksumMismatch`] at the first mismatch.
+    #[default]
+    Enforce,
+    /// Keep going, and record the mismatch in the [`CrcReport`].
+    ///
+    /// This is for recovering what can be recovered from a damaged archive: the data from a block
+    /// that fails its CRC is returned as it decoded, even though it is probably corrupt.
+    Report,
+}
+
+/// Settings for [`decompress_with`].
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct DecompressOptions {
+    /// What to do when a CRC doesn't match.
+    pub crc_mode: CrcMode,
+    /// The CRC-32 to check each block's data with, for sharing an implementation that is
+    /// already in use elsewhere. This defaults to the crate's own.
+    ///
+    /// It must compute exactly the variant that `bzip2` stores, sometimes called CRC-32/BZIP2:
+    /// polynomial `0x04c11db7`, each byte fed in most significant bit first with no reflection of
+    /// the input or output, starting from `0xffffffff`, and with the result inverted. That is not
+    /// the reflected CRC-32 of zlib, PNG and `crc32fast`, which gives different values for the same
+    /// data. Any other variant makes every block fail its check. The check value for `b"123456789"`
+    /// is `0xfc891918`.
+    pub crc_impl: fn(&[u8]) -> u32,
+}
+
+#[cfg(feature = "std")]
+impl Default for DecompressOptions {
+    fn default() -> Self {
+        DecompressOptions {
+            crc_mode: CrcMode::default(),
+            crc_impl: crc::checksum,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl DecompressOptions {
+    /// Options that return a block's data even when it fails its CRC, recording the mismatch in
+    /// the [`CrcReport`] instead of failing.
+    ///
+    /// This is the same as setting [`DecompressOptions::crc_mode`] to [`CrcMode::Report`], and
+    /// stands in for the `ignore_checksums: bool` field this setting used to be.
+    #[must_use]
+    pub fn ignore_checksums() -> Self {
+        DecompressOptions {
+            crc_mode: CrcMode::Report,
+            ..Default::default()
+        }
+    }
+}
+
+/// The CRC stored in a block's header, and the CRC of the data it decoded to.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlockCrcInfo {
+    /// The index of the block, counted as in [`decompress_each_block`].
+    pub index: u32,
+    /// The CRC stored in the block header.
+    pub stored: u32,
+    /// The CRC of the data that the block decoded to.
+    pub computed: u32,
+}
+
+#[cfg(feature = "std")]
+impl BlockCrcInfo {
+    /// Whether the block's data matched the CRC in its header.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.stored == self.computed
+    }
+}
+
+/// The CRCs that were checked, from [`decompress_with`].
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CrcReport {
+    /// The stored and computed CRC of each block, in order.
+    pub blocks: Vec<BlockCrcInfo>,
+    /// Whether the CRCs of each stream's blocks, combined, matched the CRC in its footer.
+    pub streams: Vec<bool>,
+}
+
+#[cfg(feature = "std")]
+impl CrcReport {
+    /// Whether every block and stream CRC matched.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.blocks.iter().all(BlockCrcInfo::is_ok) && self.streams.iter().all(|&ok| ok)
+    }
+}
+
+/// Decompress the given data with the given `options`.
+///
+/// Along with the data, this returns a [`CrcReport`] with the stored and computed CRC of every
+/// block, which helps to tell which blocks of a damaged archive are intact. Every CRC matches
+/// unless [`DecompressOptions::crc_mode`] is [`CrcMode::Report`], since otherwise the first
+/// mismatch is returned as an error.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Read;
+///
+/// use beeziptoo::{compress_to_vec, decompress_with, CrcMode, DecompressOptions};
+///
+/// let mut compressed = compress_to_vec(b"If Peter Piper picked a peck").unwrap();
+/// // Break the CRC in the block header, which follows the stream header and the block magic.
+/// compressed[13] ^= 0x01;
+/// let options = DecompressOptions {
+///     crc_mode: CrcMode::Report,
+///     ..Default::default()
+/// };
+///
+/// let (mut decompressed, report) = decompress_with(&compressed[..], options).unwrap();
+/// let mut buffer = vec![];
+/// decompressed.read_to_end(&mut buffer).unwrap();
+///
+/// assert_eq!(buffer, b"If Peter Piper picked a peck");
+/// assert_eq!(report.blocks.len(), 1);
+/// assert_ne!(report.blocks[0].stored, report.blocks[0].computed);
+/// // The stream CRC is built from the block CRCs, so it doesn't match either.
+/// assert_eq!(report.streams, [false]);
+/// ```
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn decompress_with<R>(
+    mut data: R,
+    options: DecompressOptions,
+) -> Result<(impl Read, CrcReport), DecompressError>
+where
+    R: Read,
+{
+    let mut scratch = DecodeScratch::default();
+    data.read_to_end(&mut scratch.input)?;
+
+    let streams = file_format::decode_streams(&scratch.input)?;
+
+    let report_only = options.crc_mode == CrcMode::Report;
+    let mut report = CrcReport::default();
+    let mut index = 0;
+    for stream in &streams {
+        for block in stream.blocks() {
+            let computed = match scratch.decode_block_with(block, options.crc_impl) {
+                Ok(_) => block.crc(),
+                Err(DecompressError::ChecksumMismatch { found, .. }) if report_only => found,
+                Err(error) => return Err(error),
+            };
+            report.blocks.push(BlockCrcInfo {
+                index,
+                stored: block.crc(),
+                computed,
+            });
+            scratch.output.extend_from_slice(&scratch.block);
+            index += 1;
+        }
+        let stream_ok = match check_stream_crc(stream.blocks(), stream.crc()) {
+            Ok(()) => true,
+            Err(DecompressError::StreamChecksumMismatch { .. }) if report_only => false,
+            Err(error) => return Err(error),
+        };
+        report.streams.push(stream_ok);
+    }
+
+    Ok((Cursor::new(scratch.output), report))
+}
+
+/// Decompress `data` straight into a `Vec`.
+///
+/// See [`compress_to_vec`] for an example.
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn decompress_to_vec(data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    let mut decompressed_data = vec![];
+
+    decompress_each_block(data, |_, block| decompressed_data.extend_from_slice(block))?;
+
+    Ok(decompressed_data)
+}
+
+/// Decompress the given data, handing each decoded block to `f` as soon as it is ready.
+///
+/// `f` receives the index of the block within the stream, starting at 0, and the block's
+/// decompressed bytes. Concatenating the bytes from every call gives the same output as
+/// [`decompress`]. Each block's CRC is checked before the block is passed to `f`, so `f` never
+/// sees the bytes of a block that fails its check.
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+/// Any blocks that were decoded before the error was found will already have been passed to `f`.
+#[cfg(feature = "std")]
+pub fn decompress_each_block<R, F>(mut data: R, mut f: F) -> Result<(), DecompressError>
+where
+    R: Read,
+    F: FnMut(u32, &[u8]),
+{
+    let mut scratch = DecodeScratch::default();
+    data.read_to_end(&mut scratch.input)?;
+
+    let streams = file_format::decode_streams(&scratch.input)?;
+
+    let mut index = 0;
+    for stream in &streams {
+        for block in stream.blocks() {
+            f(index, scratch.decode_block(block)?);
+            index += 1;
+        }
+        check_stream_crc(stream.blocks(), stream.crc())?;
+    }
+
+    Ok(())
+}
+
+/// Decompress `data` and compress it again with the given `options`, such as to change its block
+/// size.
+///
+/// Each block is compressed again as soon as it is decoded, so the whole of the decompressed data
+/// is never held at once. Every stream in `data` ends up in the one new stream.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{compress_to_vec, decompress_to_vec, peek_block_size, recompress};
+/// use beeziptoo::{BlockSize, CompressOptions};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let compressed = compress_to_vec(b"Peter Piper")?;
+/// let options = CompressOptions {
+///     block_size: BlockSize::K100,
+///     ..Default::default()
+/// };
+///
+/// let recompressed = recompress(&compressed, options)?;
+///
+/// assert_eq!(peek_block_size(&recompressed[..])?, BlockSize::K100);
+/// assert_eq!(decompress_to_vec(&recompressed)?, b"Peter Piper");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+/// Compressing can't fail, since nothing is written to I/O.
+#[cfg(feature = "std")]
+pub fn recompress(data: &[u8], options: CompressOptions) -> Result<Vec<u8>, DecompressError> {
+    let mut compressor = Compressor::new(vec![], options);
+
+    let mut written = Ok(());
+    decompress_each_block(data, |_, block| {
+        if written.is_ok() {
+            written = compressor.write_all(block);
+        }
+    })?;
+    written?;
+
+    Ok(compressor.finish()?)
+}
+
+/// Decompress the file at `path`.
+///
+/// The file is read a block at a time with a [`Decompressor`], so only the output and the block
+/// being decoded are held in memory, rather than the whole file as well.
+///
+/// # Errors
+///
+/// If the file can't be opened or read, or isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn decompress_file(path: &Path) -> Result<Vec<u8>, DecompressError> {
+    let file = BufReader::new(File::open(path)?);
+    let mut output = vec![];
+    Decompressor::new(file)?
+        .read_to_end(&mut output)
+        .map_err(decompressor::from_io_error)?;
+
+    Ok(output)
+}
+
+/// Decompress the given data using buffers from `scratch`, returning the decompressed bytes.
+///
+/// This is useful when decompressing many archives, since reusing the same [`DecodeScratch`] for
+/// each call avoids allocating fresh buffers every time. The returned slice borrows from
+/// `scratch`, and is replaced by the next call.
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn decompress_with_scratch<R>(
+    mut data: R,
+    scratch: &mut DecodeScratch,
+) -> Result<&[u8], DecompressError>
+where
+    R: Read,
+{
+    scratch.input.clear();
+    scratch.output.clear();
+    data.read_to_end(&mut scratch.input)?;
+
+    let streams = file_format::decode_streams(&scratch.input)?;
+
+    for stream in &streams {
+        for block in stream.blocks() {
+            scratch.decode_block(block)?;
+            scratch.output.extend_from_slice(&scratch.block);
+        }
+        check_stream_crc(stream.blocks(), stream.crc())?;
+    }
+
+    Ok(&scratch.output)
+}
+
+/// Check the CRCs of `blocks`, combined, against the CRC from the stream footer.
+///
+/// Each block's data is checked against the CRC in its header as it is decoded, so this only
+/// needs the header CRCs.
+#[cfg(feature = "std")]
+fn check_stream_crc(
+    blocks: &[file_format::StreamBlock],
+    expected: file_format::StreamCrc,
+) -> Result<(), DecompressError> {
+    let mut found = file_format::StreamCrc::default();
+    for block in blocks {
+        found.combine(block.crc());
+    }
+
+    if found != expected {
+        return Err(DecompressError::StreamChecksumMismatch {
+            expected: expected.value(),
+            found: found.value(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Find the size of the given data once decompressed.
+///
+/// This is cheaper than [`decompress`], since the decompressed bytes are counted rather than kept.
+/// Every block still needs to go through the inverse Burrows-Wheeler transform, though, because
+/// the runs that the final stage expands are only known after it.
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn decompressed_size<R>(mut data: R) -> Result<u64, DecompressError>
+where
+    R: Read,
+{
+    let mut scratch = DecodeScratch::default();
+    data.read_to_end(&mut scratch.input)?;
+
+    let un_file_data = file_format::decode(&scratch.input)?;
+
+    let mut size = 0;
+    for block in &un_file_data {
+        size += scratch.decoded_block_len(block)? as u64;
+    }
+
+    Ok(size)
+}
+
+/// Find where each block's data sits within the given stream.
+///
+/// Blocks are not byte-aligned, so each range is a `(start, end)` pair of bit offsets from the
+/// start of the stream. A range starts at the block's magic number and ends just after its
+/// end-of-block symbol, with `end` exclusive.
+///
+/// # Errors
+///
+/// This function is failable since it is possible the given data isn't a valid `bzip2` archive.
+#[cfg(feature = "std")]
+pub fn block_bit_ranges<R>(mut data: R) -> Result<Vec<(u64, u64)>, DecompressError>
+where
+    R: Read,
+{
+    let mut input = vec![];
+    data.read_to_end(&mut input)?;
+
+    let un_file_data = file_format::decode(&input)?;
+
+    Ok(un_file_data
+        .iter()
+        .map(|block| {
+            let range = block.bit_range();
+            (range.start, range.end)
+        })
+        .collect())
+}
+
+/// Find the block size that the given data was compressed with, from its stream header.
+///
+/// Only the four bytes of the header are read, so nothing is decompressed and the rest of `data`
+/// isn't checked at all.
+///
+/// # Example
+///
+/// ```rust
+/// use beeziptoo::{peek_block_size, BlockSize};
+///
+/// assert_eq!(peek_block_size(&b"BZh4"[..]).unwrap(), BlockSize::K400);
+/// ```
+///
+/// # Errors
+///
+/// If `data` doesn't start with a valid `bzip2` stream header.
+#[cfg(feature = "std")]
+pub fn peek_block_size<R>(data: R) -> Result<BlockSize, DecompressError>
+where
+    R: Read,
+{
+    let level = file_format::read_level(data)?;
+
+    Ok(BlockSize::from_level(level).expect("The header only allows levels 1 to 9"))
+}
+
+/// Reusable buffers for decompression.
+///
+/// Each stage of decoding needs a buffer that is as large as a block. A `DecodeScratch` holds on
+/// to those buffers so that they can be reused from block to block, and from one call to
+/// [`decompress_with_scratch`] to the next. It carries no state between calls other than the
+/// allocations themselves.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct DecodeScratch {
+    /// The compressed input.
+    input: Vec<u8>,
+    /// The output of the run-length (stage 2) decoder.
+    un_rle2: Vec<u8>,
+    /// The output of the move-to-front decoder.
+    un_move_to_front: Vec<u8>,
+    /// The transform vector used to invert the Burrows-Wheeler transform.
+    permutation: Vec<usize>,
+    /// The output of the Burrows-Wheeler decoder.
+    un_burrows_wheeler: Vec<u8>,
+    /// The decompressed bytes of the most recently decoded block.
+    block: Vec<u8>,
+    /// The decompressed bytes of every block so far.
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl DecodeScratch {
+    /// Run a single block through every decoding stage, returning the decompressed bytes.
+    fn decode_block(&mut self, block: &file_format::StreamBlock) -> Result<&[u8], DecompressError> {
+        self.decode_block_with(block, crc::checksum)
+    }
+
+    /// Like [`decode_block`](Self::decode_block), but checking the CRC with `crc_impl`.
+    fn decode_block_with(
+        &mut self,
+        block: &file_format::StreamBlock,
+        crc_impl: fn(&[u8]) -> u32,
+    ) -> Result<&[u8], DecompressError> {
+        self.undo_burrows_wheeler(block)?;
+
+        self.block.clear();
+        rle1::decode_to(&self.un_burrows_wheeler[..], &mut self.block)?;
+
+        let found = crc_impl(&self.block);
+        if found != block.crc() {
+            return Err(DecompressError::ChecksumMismatch {
+                expected: block.crc(),
+                found,
+            });
+        }
+
+        Ok(&self.block)
+    }
+
+    /// Find the decompressed length of a single block without writing out its bytes.
+    fn decoded_block_len(
+        &mut self,
+        block: &file_format::StreamBlock,
+    ) -> Result<usize, DecompressError> {
+        self.undo_burrows_wheeler(block)?;
+
+        let len = rle1::decode_to(&self.un_burrows_wheeler[..], io::sink())?;
+
+        Ok(len)
+    }
+
+    /// Run a single block through every decoding stage up to and including the Burrows-Wheeler
+    /// transform, leaving the result in `un_burrows_wheeler`.
+    fn undo_burrows_wheeler(
+        &mut self,
+        block: &file_format::StreamBlock,
+    ) -> Result<(), DecompressError> {
+        let un_huffman_data = huffman::decode(block.symbols());
+
+        self.un_rle2.clear();
+        rle2::decode_into(&un_huffman_data, &mut self.un_rle2, block.max_len())?;
+
+        self.un_move_to_front.clear();
+        move_to_front::decode_into(
+            &self.un_rle2,
+            block.symbol_stack(),
+            &mut self.un_move_to_front,
+        )?;
+
+        // The encoder never writes a block without data, so a block whose symbols decode to
+        // nothing has been corrupted. There is no valid origin pointer into an empty block.
+        if self.un_move_to_front.is_empty() {
+            return Err(burrows_wheeler::DecodeError::EmptyBlock.into());
+        }
+
+        burrows_wheeler::decode_into(
+            &self.un_move_to_front,
+            block.origin_pointer(),
+            &mut self.permutation,
+            &mut self.un_burrows_wheeler,
+        )?;
+        if block.randomized() {
+            burrows_wheeler::derandomize(&mut self.un_burrows_wheeler);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write as _,
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+
+    /// Compress `data` with the system's `bzip2` at the given level.
+    fn bzip2(data: &[u8], level: u8) -> Vec<u8> {
+        let mut child = Command::new("bzip2")
+            .arg("-c")
+            .arg(format!("-{level}"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        // bzip2 may fill the stdout pipe before we've finished writing, so write from another
+        // thread.
+        std::thread::scope(|s| {
+            s.spawn(move || stdin.write_all(data).unwrap());
+            child.wait_with_output().unwrap().stdout
+        })
+    }
+
+    /// Decompress `compressed` with the system's `bzip2`, checking that it accepts it.
+    fn bzip2_decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut child = Command::new("bzip2")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let output = std::thread::scope(|s| {
+            s.spawn(move || stdin.write_all(compressed).unwrap());
+            child.wait_with_output().unwrap()
+        });
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    /// Generate `len` bytes that are unlikely to compress well.
+    fn noise(len: usize) -> Vec<u8> {
+        // A simple linear congruential generator is plenty here.
+        let mut state: u32 = 0x2545_f491;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn can_we_read() {
+        let peter_piper = "If Peter Piper picked a peck of pickled peppers, where's the peck of pickled peppers Peter Piper picked?????";
+
+        for level in 1..=9 {
+            let mut child = Command::new("bzip2")
+                .arg("-c")
+                .arg(format!("-{level}"))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            {
+                let mut stdin = child.stdin.take().unwrap();
+                stdin.write_all(peter_piper.as_bytes()).unwrap();
+            }
+            let bytes = child.wait_with_output().unwrap().stdout;
+
+            let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+
+            let mut buffer = vec![];
+            let _num_bytes = data
+                .read_to_end(&mut buffer)
+                .expect("Cannot read decompressed data");
+            assert_eq!(std::str::from_utf8(&buffer).unwrap(), peter_piper);
+        }
+    }
+
+    /// A stream with a header and a footer but no blocks is the canonical empty archive.
+    #[test]
+    fn no_blocks() {
+        let input = b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00";
+
+        let mut data = decompress(&input[..]).expect("Cannot decompress test data");
+
+        let mut buffer = vec![];
+        let _num_bytes = data
+            .read_to_end(&mut buffer)
+            .expect("Cannot read decompressed data");
+        assert!(buffer.is_empty());
+        assert_eq!(bzip2(b"", 9), input);
+    }
+
+    /// The legacy bzip format should be reported as unsupported.
+    #[test]
+    fn legacy_bzip() {
+        let input = b"BZ09\x31\x41\x59\x26\x53\x59";
+
+        let result = decompress(&input[..]);
+
+        assert!(matches!(result, Err(DecompressError::UnsupportedFormat)));
+    }
+
+    /// Data that isn't a bzip2 stream should be reported as such.
+    #[test]
+    fn missing_header_magic() {
+        let input = b"PK\x03\x04\x14\x00\x00\x00";
+
+        let result = decompress(&input[..]);
+
+        assert!(matches!(result, Err(DecompressError::MissingMagic)));
+    }
+
+    /// A stream that stops after its header, with no block magic or footer magic, is missing its
+    /// end-of-stream marker.
+    #[test]
+    fn missing_footer_magic() {
+        let input = b"BZh9\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+        let result = decompress(&input[..]);
+
+        assert!(matches!(result, Err(DecompressError::MissingMagic)));
+    }
+
+    /// Anything after the end of the stream should be an error, not ignored.
+    #[test]
+    fn trailing_data() {
+        let mut input = bzip2(b"If Peter Piper picked a peck of pickled peppers", 9);
+        input.extend_from_slice(b"junk");
+
+        let result = decompress(&input[..]);
+
+        assert!(matches!(result, Err(DecompressError::TrailingData)));
+    }
+
+    /// Even zero bytes after the end of the stream are more than padding.
+    #[test]
+    fn trailing_zeros() {
+        let mut input = bzip2(b"If Peter Piper picked a peck of pickled peppers", 9);
+        input.push(0);
+
+        let result = decompress(&input[..]);
+
+        assert!(matches!(result, Err(DecompressError::TrailingData)));
+    }
+
+    /// Concatenated streams decompress to their data one after the other.
+    mod concatenated {
+        use super::*;
+
+        #[test]
+        fn foobar() {
+            let input = [
+                compress_to_vec(b"foo").unwrap(),
+                compress_to_vec(b"bar").unwrap(),
+            ]
+            .concat();
+
+            assert_eq!(decompress_to_vec(&input).unwrap(), b"foobar");
+        }
+
+        /// Streams that `bzip2` wrote, with different block sizes and more than one block, should
+        /// work too.
+        #[test]
+        fn bzip2_streams() {
+            let first = noise(150_000);
+            let second = b"If Peter Piper picked a peck of pickled peppers";
+            let input = [bzip2(&first, 1), bzip2(b"", 9), bzip2(second, 5)].concat();
+            let mut indices = vec![];
+
+            decompress_each_block(&input[..], |index, _| indices.push(index)).unwrap();
+            let decompressed = decompress_to_vec(&input).unwrap();
+
+            assert_eq!(indices, [0, 1, 2]);
+            assert_eq!(decompressed, [&first[..], second].concat());
+        }
+
+        /// Each stream's footer should be checked against its own blocks.
+        #[test]
+        fn second_stream_checksum_mismatch() {
+            let mut second = compress_to_vec(b"bar").unwrap();
+            let len = second.len();
+            second[len - 2] ^= 0x01;
+            let input = [compress_to_vec(b"foo").unwrap(), second].concat();
+
+            assert!(matches!(
+                decompress_to_vec(&input),
+                Err(DecompressError::StreamChecksumMismatch { .. })
+            ));
+        }
+
+        /// Junk after a complete stream is still an error.
+        #[test]
+        fn junk_after_streams() {
+            let input = [
+                compress_to_vec(b"foo").unwrap(),
+                compress_to_vec(b"bar").unwrap(),
+                b"BZ".to_vec(),
+            ]
+            .concat();
+
+            assert!(matches!(
+                decompress_to_vec(&input),
+                Err(DecompressError::TrailingData)
+            ));
+        }
+    }
+
+    /// An origin pointer past the end of the block in the block header should be caught.
+    #[test]
+    fn origin_pointer_out_of_range() {
+        let mut input = bzip2(b"If Peter Piper picked a peck of pickled peppers", 9);
+        // The 24-bit origin pointer follows the stream header, the block magic, the block CRC and
+        // the randomised bit.
+        input[14] |= 0x7f;
+        input[15] = 0xff;
+        input[16] = 0xff;
+        input[17] |= 0x80;
+
+        let result = decompress(&input[..]);
+
+        assert!(matches!(
+            result,
+            Err(DecompressError::BurrowsWheelerDecode(
+                burrows_wheeler::DecodeError::InvalidOriginPointer
+            ))
+        ));
+    }
+
+    /// A block whose first run-length encoding stops where a count should be should say how much
+    /// of the block was decoded first.
+    #[test]
+    fn run_length_truncated_offset() {
+        let mut rle_data = rle1::encode(b"If Peter Piper picked a peck");
+        rle_data.extend_from_slice(b"eeee");
+        let (block, _sizes) = EncodeScratch::default().encode_block(&rle_data, 0, false);
+        let bytes = file_format::encode(9, &[block]);
+
+        let result = decompress(&bytes[..]);
+
+        assert!(matches!(
+            result,
+            Err(DecompressError::RunLengthDecode { offset: 28 })
+        ));
+    }
+
+    /// Test that a block can't decode to more than its stream's block size.
+    mod block_too_long {
+        use super::*;
+
+        /// A block of `len` bytes that the first run-length encoding leaves as they are, in a
+        /// stream that declares `level`.
+        fn stream(len: usize, level: u8) -> (Vec<u8>, Vec<u8>) {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let (block, _sizes) =
+                EncodeScratch::default().encode_block(&data, crc::checksum(&data), false);
+
+            (file_format::encode(level, &[block]), data)
+        }
+
+        /// A block of exactly the block size is fine, as it is for `bzip2`.
+        #[test]
+        fn exact() {
+            let (bytes, data) = stream(100_000, 1);
+
+            assert_eq!(decompress_to_vec(&bytes).unwrap(), data);
+            assert_eq!(bzip2_decompress(&bytes), data);
+        }
+
+        /// One byte more is only too long if the header says so.
+        #[test]
+        fn one_more() {
+            let (bytes, _data) = stream(100_001, 1);
+            assert!(matches!(
+                decompress_to_vec(&bytes),
+                Err(DecompressError::BlockTooLong { .. })
+            ));
+
+            let (bytes, data) = stream(100_001, 2);
+            assert_eq!(decompress_to_vec(&bytes).unwrap(), data);
+        }
+
+        /// Each of these runs is 2^25 - 2 zeros, so without the limit a few hundred bytes of
+        /// input would ask for gigabytes of output. It should stop at the first run instead.
+        #[test]
+        fn oversized_runs() {
+            let symbols: Vec<rle2::Symbol> = (0..1_000)
+                .flat_map(|_| {
+                    (0..24)
+                        .map(|_| rle2::Symbol::RunB)
+                        .chain([rle2::Symbol::Byte(1)])
+                })
+                .collect();
+            let block = file_format::EncodedBlock {
+                crc: 0,
+                origin_pointer: burrows_wheeler::OriginPointer(0),
+                symbol_stack: move_to_front::SymbolStack(vec![b'a', b'b']),
+                huffman: huffman::encode(&symbols, 4),
+            };
+            let bytes = file_format::encode(1, &[block]);
+
+            assert!(matches!(
+                decompress_to_vec(&bytes),
+                Err(DecompressError::BlockTooLong { offset: 0 })
+            ));
+            let mut decompressor = Decompressor::new(&bytes[..]).unwrap();
+            let error = decompressor.read_to_end(&mut vec![]).unwrap_err();
+            assert!(matches!(
+                error
+                    .into_inner()
+                    .unwrap()
+                    .downcast::<DecompressError>()
+                    .as_deref(),
+                Ok(DecompressError::BlockTooLong { offset: 0 })
+            ));
+        }
+    }
+
+    /// The output should be framed like any other bzip2 stream.
+    #[test]
+    fn compress_framing() {
+        let mut compressed = vec![];
+        compress(&b"hello"[..])
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        assert_eq!(&compressed[..4], b"BZh9");
+        assert_eq!(&compressed[4..10], b"\x31\x41\x59\x26\x53\x59");
+        assert_eq!(block_bit_ranges(&compressed[..]).unwrap().len(), 1);
+    }
+
+    /// A block randomized by an old version of bzip2 should be derandomized after the inverse
+    /// Burrows-Wheeler transform.
+    #[test]
+    fn randomized_block() {
+        let data = noise(3_000);
+        let mut rle_data = rle1::encode(&data);
+        burrows_wheeler::derandomize(&mut rle_data);
+        let (block, _sizes) =
+            EncodeScratch::default().encode_block(&rle_data, crc::checksum(&data), false);
+        let mut bytes = file_format::encode(9, &[block]);
+        assert!(matches!(
+            decompress(&bytes[..]),
+            Err(DecompressError::ChecksumMismatch { .. })
+        ));
+        // The randomized bit follows the stream header, the block magic and the block CRC.
+        bytes[14] |= 0x80;
+
+        let mut decompressed = vec![];
+        decompress(&bytes[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(decompressed, data);
+    }
+
+    /// Test the block CRC.
+    mod block_crc {
+        use super::*;
+
+        const DATA: &[u8] = b"If Peter Piper picked a peck of pickled peppers";
+
+        /// The CRC in our block header should be the one `bzip2` writes for the same data.
+        #[test]
+        fn matches_bzip2() {
+            let mut compressed = vec![];
+            compress(DATA)
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+
+            // The first block's CRC is byte aligned, just after the stream header and block magic.
+            assert_eq!(compressed[10..14], bzip2(DATA, 9)[10..14]);
+        }
+
+        /// A block whose data doesn't match its CRC is corrupt.
+        #[test]
+        fn mismatch() {
+            let mut bytes = bzip2(DATA, 9);
+            bytes[13] ^= 0x01;
+
+            let result = decompress(&bytes[..]);
+
+            assert!(matches!(
+                result,
+                Err(DecompressError::ChecksumMismatch {
+                    expected: 0x5a0f_6f59,
+                    found: 0x5a0f_6f58,
+                })
+            ));
+        }
+    }
+
+    /// Test the combined CRC in the stream footer.
+    mod stream_crc {
+        use super::*;
+
+        /// Combining the block CRCs of a stream that `bzip2` wrote should give its footer CRC.
+        #[test]
+        fn matches_bzip2() {
+            let bytes = bzip2(&noise(150_000), 1);
+
+            let streams = file_format::decode_streams(&bytes).unwrap();
+
+            assert_eq!(streams.len(), 1);
+            assert_eq!(streams[0].blocks().len(), 2);
+            let mut combined = file_format::StreamCrc::default();
+            for block in streams[0].blocks() {
+                combined.combine(block.crc());
+            }
+            assert_eq!(combined, streams[0].crc());
+        }
+
+        /// Our footer should hold the same CRC that `bzip2` writes.
+        #[test]
+        fn compress_matches_bzip2() {
+            let data = b"If Peter Piper picked a peck of pickled peppers";
+            let mut compressed = vec![];
+            compress(&data[..])
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+
+            let crc = file_format::decode_streams(&compressed).unwrap()[0].crc();
+            let expected = file_format::decode_streams(&bzip2(data, 9)).unwrap()[0].crc();
+            assert_eq!(crc, expected);
+        }
+
+        /// A footer CRC that doesn't match the blocks means the stream is corrupt.
+        #[test]
+        fn mismatch() {
+            let input = b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x01";
+
+            let result = decompress(&input[..]);
+
+            assert!(matches!(
+                result,
+                Err(DecompressError::StreamChecksumMismatch {
+                    expected: 1,
+                    found: 0,
+                })
+            ));
+        }
+    }
+
+    /// The smallest blocks hit edge cases in every stage at once.
+    mod tiny_inputs {
+        use super::*;
+
+        const INPUTS: [&[u8]; 3] = [b"a", b"ab", b"aba"];
+
+        #[test]
+        fn decompress_bzip2() {
+            for input in INPUTS {
+                let bytes = bzip2(input, 9);
+
+                let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+
+                let mut buffer = vec![];
+                let _num_bytes = data
+                    .read_to_end(&mut buffer)
+                    .expect("Cannot read decompressed data");
+                assert_eq!(buffer, input);
+            }
+        }
+
+        /// Run each input through every encoding stage and back again.
+        #[test]
+        fn stages() {
+            for input in INPUTS {
+                let rle1_data = rle1::encode(input);
+                let burrows_wheeler_data = burrows_wheeler::encode(&rle1_data);
+                let move_to_front_data = move_to_front::encode(&burrows_wheeler_data.data);
+                let rle2_data = rle2::encode(&move_to_front_data);
+                let huffman_symbols: Vec<huffman::Symbol> = rle2_data
+                    .iter()
+                    .map(Into::into)
+                    .chain([huffman::Symbol::Eob])
+                    .collect();
+
+                let un_huffman_data = huffman::decode(&huffman_symbols);
+                let un_rle2_data = rle2::decode(&un_huffman_data).unwrap();
+                let un_move_to_front_data = move_to_front::decode(
+                    &un_rle2_data,
+                    file_format::SymbolStack((0..=255).collect()),
+                )
+                .unwrap();
+                let un_burrows_wheeler_data =
+                    burrows_wheeler::decode(&burrows_wheeler::BwtEncoded {
+                        data: un_move_to_front_data,
+                        origin_pointer: burrows_wheeler_data.origin_pointer,
+                    })
+                    .unwrap();
+                let output = rle1::decode(&un_burrows_wheeler_data).unwrap();
+
+                assert_eq!(output, input);
+            }
+        }
+    }
+
+    /// Decompressing should not assume that the stream starts at offset 0 of the reader.
+    #[test]
+    fn region_of_seekable() {
+        use std::io::{Seek as _, SeekFrom};
+
+        let data = b"If Peter Piper picked a peck of pickled peppers";
+        let bytes = bzip2(data, 9);
+        let mut file = Cursor::new(vec![0xaa; 100]);
+        file.seek(SeekFrom::Start(40)).unwrap();
+        file.write_all(&bytes).unwrap();
+        file.write_all(&[0xbb; 40]).unwrap();
+
+        file.seek(SeekFrom::Start(40)).unwrap();
+        let mut buffer = vec![];
+        let _num_bytes = decompress((&mut file).take(bytes.len() as u64))
+            .expect("Cannot decompress test data")
+            .read_to_end(&mut buffer)
+            .expect("Cannot read decompressed data");
+
+        assert_eq!(buffer, data);
+        assert_eq!(file.stream_position().unwrap(), 40 + bytes.len() as u64);
+    }
+
+    /// Compressing should not assume that the stream starts at offset 0 of the writer either.
+    #[test]
+    fn compress_to_seekable() {
+        use std::io::{Seek as _, SeekFrom};
+
+        let data = noise(150_000);
+        let mut file = Cursor::new(vec![0xaa; 100]);
+        file.seek(SeekFrom::Start(40)).unwrap();
+
+        let len = super::compress_to_seekable(&data[..], &mut file, CompressOptions::default())
+            .expect("Cannot compress test data");
+        file.write_all(&[0xbb; 40]).unwrap();
+
+        assert_eq!(file.stream_position().unwrap(), 40 + len + 40);
+        let file = file.into_inner();
+        assert_eq!(file[..40], [0xaa; 40]);
+        assert_eq!(file[40 + len as usize..], [0xbb; 40]);
+        let region = &file[40..40 + len as usize];
+        assert_eq!(region, compress_to_vec(&data).unwrap());
+        assert_eq!(decompress_to_vec(region).unwrap(), data);
+    }
+
+    /// A block that is the same all the way through can have every group coded with the first
+    /// tree, so its selectors are all zero.
+    #[test]
+    fn same_tree_for_every_group() {
+        let data: Vec<u8> = noise(500).into_iter().map(|byte| b'a' + byte % 8).collect();
+        let bytes = bzip2(&data, 9);
+        let blocks = file_format::decode(&bytes).expect("Cannot parse test data");
+        let selectors = blocks[0].selectors();
+        assert!(selectors.len() > 1);
+        assert!(selectors.iter().all(|&selector| selector == 0));
+
+        let mut buffer = vec![];
+        let _num_bytes = decompress(&bytes[..])
+            .expect("Cannot decompress test data")
+            .read_to_end(&mut buffer)
+            .expect("Cannot read decompressed data");
+
+        assert_eq!(buffer, data);
+    }
+
+    /// A block with only one distinct byte has nothing but zeros after move-to-front, so its
+    /// symbols are all runs with no `Byte` symbols at all.
+    #[test]
+    fn only_runs() {
+        for input in [&b"a"[..], b"aaa", b"\0\0\0"] {
+            let bytes = bzip2(input, 9);
+            let blocks = file_format::decode(&bytes).expect("Cannot parse test data");
+            assert!(blocks[0].symbols().iter().all(|symbol| matches!(
+                symbol,
+                huffman::Symbol::RunA | huffman::Symbol::RunB | huffman::Symbol::Eob
+            )));
+
+            let mut buffer = vec![];
+            let _num_bytes = decompress(&bytes[..])
+                .expect("Cannot decompress test data")
+                .read_to_end(&mut buffer)
+                .expect("Cannot read decompressed data");
+
+            assert_eq!(buffer, input);
+        }
+    }
+
+    /// A block whose only symbol is the end-of-block marker has no data, and should be rejected.
+    #[test]
+    fn empty_block() {
+        use file_format::bitstream::BitWriter;
+
+        let mut writer = BitWriter::new(vec![]);
+        // The stream header.
+        writer.write_bits(u32::from_be_bytes(*b"BZh9"), 32).unwrap();
+        // The block magic, CRC, randomized flag, and origin pointer.
+        writer.write_bits(0x314159, 24).unwrap();
+        writer.write_bits(0x265359, 24).unwrap();
+        writer.write_bits(0, 32).unwrap();
+        writer.write_bits(0, 1).unwrap();
+        writer.write_bits(0, 24).unwrap();
+        // A single used byte gives the symbols RunA, RunB, and EOB.
+        file_format::write_used_map(&mut writer, &file_format::SymbolStack(vec![b'a'])).unwrap();
+        // Two trees, one selector for the first tree.
+        writer.write_bits(2, 3).unwrap();
+        writer.write_bits(1, 15).unwrap();
+        writer.write_bits(0b0, 1).unwrap();
+        // Both trees have the code lengths 1, 2, 2, so EOB is 0b11.
+        for _ in 0..2 {
+            // Start at length 1 and keep it, then add 1 and keep it, then keep it again.
+            writer.write_bits(1, 5).unwrap();
+            writer.write_bits(0b0, 1).unwrap();
+            writer.write_bits(0b100, 3).unwrap();
+            writer.write_bits(0b0, 1).unwrap();
+        }
+        writer.write_bits(0b11, 2).unwrap();
+        // The stream footer.
+        writer.write_bits(0x177245, 24).unwrap();
+        writer.write_bits(0x385090, 24).unwrap();
+        writer.write_bits(0, 32).unwrap();
+        let bytes = writer.into_inner().unwrap();
+        let blocks = file_format::decode(&bytes).expect("Cannot parse test data");
+        assert_eq!(blocks[0].symbols(), [huffman::Symbol::Eob]);
+
+        let result = decompress(&bytes[..]);
+
+        assert!(matches!(
+            result,
+            Err(DecompressError::BurrowsWheelerDecode(
+                burrows_wheeler::DecodeError::EmptyBlock
+            ))
+        ));
+    }
+
+    /// Empty input and a single zero byte are both minimal, but take different paths.
+    mod minimal {
+        use super::*;
+
+        /// Empty input has no blocks at all.
+        #[test]
+        fn empty() {
+            let bytes = bzip2(b"", 9);
+            let blocks = file_format::decode(&bytes).expect("Cannot parse test data");
+            assert!(blocks.is_empty());
+
+            let mut buffer = vec![];
+            let _num_bytes = decompress(&bytes[..])
+                .expect("Cannot decompress test data")
+                .read_to_end(&mut buffer)
+                .expect("Cannot read decompressed data");
+
+            assert!(buffer.is_empty());
+            assert!(rle1::encode(b"").is_empty());
+            assert!(burrows_wheeler::encode(b"").data.is_empty());
+        }
+
+        /// Compressing empty input should skip every stage and give the same header and footer
+        /// that `bzip2` writes, however it is compressed.
+        #[test]
+        fn compress_empty() {
+            let compressed = compress_to_vec(b"").unwrap();
+            let mut parallel = vec![];
+            compress_parallel(&b""[..], CompressOptions::default(), NonZeroUsize::MIN)
+                .unwrap()
+                .read_to_end(&mut parallel)
+                .unwrap();
+
+            assert!(compressed.starts_with(b"BZh"));
+            assert_eq!(compressed, bzip2(b"", 9));
+            assert_eq!(parallel, compressed);
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), b"");
+        }
+
+        /// A single zero byte is one block, holding a single run of one zero.
+        #[test]
+        fn single_zero() {
+            let bytes = bzip2(b"\0", 9);
+            let blocks = file_format::decode(&bytes).expect("Cannot parse test data");
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(
+                blocks[0].symbols(),
+                [huffman::Symbol::RunA, huffman::Symbol::Eob]
+            );
+
+            let mut buffer = vec![];
+            let _num_bytes = decompress(&bytes[..])
+                .expect("Cannot decompress test data")
+                .read_to_end(&mut buffer)
+                .expect("Cannot read decompressed data");
+
+            assert_eq!(buffer, b"\0");
+            let burrows_wheeler_data = burrows_wheeler::encode(&rle1::encode(b"\0"));
+            assert_eq!(burrows_wheeler_data.data, b"\0");
+            let move_to_front_data = move_to_front::encode(&burrows_wheeler_data.data);
+            assert_eq!(move_to_front_data, [0]);
+            assert_eq!(rle2::encode(&move_to_front_data), [rle2::Symbol::RunA]);
+        }
+    }
+
+    /// A run-length encoded run holds at most 4 + 251 bytes, so that its count byte is never more
+    /// than 251. `bzip2` splits longer runs in the same place.
+    #[test]
+    fn rle1_matches_bzip2() {
+        for len in [251, 252, 255, 256, 259, 600] {
+            let mut data = vec![b'a'; len];
+            data.push(b'b');
+            let blocks = file_format::decode(&bzip2(&data, 9)).expect("Cannot parse test data");
+            let mut scratch = DecodeScratch::default();
+
+            scratch.undo_burrows_wheeler(&blocks[0]).unwrap();
+
+            assert_eq!(
+                scratch.un_burrows_wheeler,
+                rle1::encode(&data),
+                "run of {len}"
+            );
+            assert!(scratch.un_burrows_wheeler.iter().all(|&byte| byte <= 251));
+        }
+    }
+
+    /// Data that fits in one level 9 block should still decode as the several blocks that a lower
+    /// level split it into.
+    #[test]
+    fn smaller_level_than_needed() {
+        let data = noise(250_000);
+        let level_9 = bzip2(&data, 9);
+        let level_1 = bzip2(&data, 1);
+        assert_eq!(file_format::decode(&level_9).unwrap().len(), 1);
+        assert_eq!(file_format::decode(&level_1).unwrap().len(), 3);
+
+        for bytes in [level_9, level_1] {
+            let mut buffer = vec![];
+            let _num_bytes = decompress(&bytes[..])
+                .expect("Cannot decompress test data")
+                .read_to_end(&mut buffer)
+                .expect("Cannot read decompressed data");
+
+            assert_eq!(buffer, data);
+        }
+    }
+
+    /// Test [`decompressed_size`].
+    mod size {
+        use super::*;
+
+        /// The size should match the number of bytes that [`decompress`] produces.
+        #[test]
+        fn matches_decompress() {
+            let mut data = noise(150_000);
+            data.extend([b'z'; 10_000]);
+            let bytes = bzip2(&data, 1);
+
+            let size = decompressed_size(&bytes[..]).expect("Cannot decompress test data");
+
+            let mut buffer = vec![];
+            let _num_bytes = decompress(&bytes[..])
+                .expect("Cannot decompress test data")
+                .read_to_end(&mut buffer)
+                .expect("Cannot read decompressed data");
+            assert_eq!(size, buffer.len() as u64);
+            assert_eq!(size, data.len() as u64);
+        }
+    }
+
+    /// Compressing and decompressing on several threads at once should not share any state
+    /// between them, and the types that hold the state should be able to move between threads.
+    #[test]
+    fn threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Compressor<Vec<u8>>>();
+        assert_send_sync::<Decompressor<&[u8]>>();
+        assert_send_sync::<EncodeScratch>();
+        assert_send_sync::<DecodeScratch>();
+
+        let inputs: Vec<Vec<u8>> = (0..8_u8)
+            .map(|i| {
+                let mut data = noise(10_000 + 1_000 * i as usize);
+                data.extend(vec![i; 1_000 * i as usize]);
+                data
+            })
+            .collect();
+        let compressed: Vec<Vec<u8>> = inputs.iter().map(|data| bzip2(data, 1)).collect();
+
+        std::thread::scope(|s| {
+            let handles: Vec<_> = compressed
+                .iter()
+                .map(|bytes| {
+                    s.spawn(|| {
+                        let mut buffer = vec![];
+                        let _num_bytes = decompress(&bytes[..])
+                            .expect("Cannot decompress test data")
+                            .read_to_end(&mut buffer)
+                            .expect("Cannot read decompressed data");
+                        let mut scratch = DecodeScratch::default();
+                        let output = decompress_with_scratch(&bytes[..], &mut scratch)
+                            .expect("Cannot decompress test data");
+                        assert_eq!(output, buffer);
+                        buffer
+                    })
+                })
+                .collect();
+
+            for (handle, data) in handles.into_iter().zip(&inputs) {
+                assert_eq!(&handle.join().unwrap(), data);
+            }
+        });
+
+        std::thread::scope(|s| {
+            let handles: Vec<_> = inputs
+                .iter()
+                .map(|data| s.spawn(|| compress_to_vec(data).expect("Cannot compress test data")))
+                .collect();
+
+            for (handle, data) in handles.into_iter().zip(&inputs) {
+                let compressed = handle.join().unwrap();
+                assert_eq!(compressed, compress_to_vec(data).unwrap());
+                assert_eq!(&decompress_to_vec(&compressed).unwrap(), data);
+            }
+        });
+    }
+
+    /// Test [`block_bit_ranges`].
+    mod bit_ranges {
+        use super::*;
+
+        /// The ranges should be in order, not overlap, and sit between the stream header and
+        /// footer.
+        #[test]
+        fn two_blocks() {
+            let bytes = bzip2(&noise(150_000), 1);
+
+            let ranges = block_bit_ranges(&bytes[..]).expect("Cannot parse test data");
+
+            assert_eq!(ranges.len(), 2);
+            // The stream header is 32 bits long.
+            assert_eq!(ranges[0].0, 32);
+            assert!(ranges[0].0 < ranges[0].1);
+            assert!(ranges[0].1 <= ranges[1].0);
+            assert!(ranges[1].0 < ranges[1].1);
+            // The footer is 80 bits long, followed by padding to the end of the byte.
+            let footer_start = ranges[1].1;
+            assert_eq!((footer_start + 80).div_ceil(8), bytes.len() as u64);
+        }
+    }
+
+    /// Test [`peek_block_size`].
+    mod peek_block_size {
+        use super::*;
+
+        /// The block size should be the one the data was compressed with.
+        #[test]
+        fn k300() {
+            let mut compressed = vec![];
+            compress_with(
+                &b"If Peter Piper picked a peck of pickled peppers"[..],
+                CompressOptions {
+                    block_size: BlockSize::K300,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+            assert_eq!(
+                super::peek_block_size(&compressed[..]).unwrap(),
+                BlockSize::K300
+            );
+        }
+
+        /// Data that isn't `bzip2` should be an error.
+        #[test]
+        fn missing_magic() {
+            assert!(matches!(
+                super::peek_block_size(&b"not bzip2"[..]),
+                Err(DecompressError::MissingMagic)
+            ));
+            assert!(matches!(
+                super::peek_block_size(&b"BZh0"[..]),
+                Err(DecompressError::Parse(
+                    file_format::DecodeError::InvalidBlockSize
+                ))
+            ));
+        }
+    }
+
+    /// Test [`decompress_each_block`].
+    mod each_block {
+        use super::*;
+
+        /// Each block should be passed to the callback in order.
+        #[test]
+        fn three_blocks() {
+            let data = noise(250_000);
+            let bytes = bzip2(&data, 1);
+            let mut indices = vec![];
+            let mut output = vec![];
+
+            decompress_each_block(&bytes[..], |index, block| {
+                indices.push(index);
+                output.extend_from_slice(block);
+            })
+            .expect("Cannot decompress test data");
+
+            assert_eq!(indices, [0, 1, 2]);
+            assert_eq!(output, data);
+        }
+
+        /// A block that fails its CRC should not be passed to the callback, though the blocks
+        /// before it are.
+        #[test]
+        fn corrupt_block() {
+            let mut bytes = bzip2(&noise(250_000), 1);
+            let start = block_bit_ranges(&bytes[..]).unwrap()[1].0;
+            // The block CRC comes straight after the 48 bit block magic.
+            let bit = start + 48;
+            bytes[(bit / 8) as usize] ^= 0x80 >> (bit % 8);
+            let mut indices = vec![];
+
+            let result = decompress_each_block(&bytes[..], |index, _block| indices.push(index));
+
+            assert!(matches!(
+                result,
+                Err(DecompressError::ChecksumMismatch { .. })
+            ));
+            assert_eq!(indices, [0]);
+        }
+    }
+
+    /// Test [`decompress_with_scratch`].
+    mod with_scratch {
+        use super::*;
+
+        /// Reusing the scratch space should not leak anything from one call into the next.
+        #[test]
+        fn reuse() {
+            let first = noise(150_000);
+            let second = b"If Peter Piper picked a peck of pickled peppers";
+            let mut scratch = DecodeScratch::default();
+
+            let output = decompress_with_scratch(&bzip2(&first, 1)[..], &mut scratch)
+                .expect("Cannot decompress test data");
+            assert_eq!(output, first);
+
+            let output = decompress_with_scratch(&bzip2(second, 9)[..], &mut scratch)
+                .expect("Cannot decompress test data");
+            assert_eq!(output, second);
+        }
+    }
+
+    /// Test [`decompress_with`].
+    mod decompress_with {
+        use super::*;
+
+        const DATA: &[u8] = b"If Peter Piper picked a peck of pickled peppers";
+
+        /// Our compressed `DATA` with one bit of its Huffman coded data flipped, so that it still
+        /// decodes, but to the wrong bytes.
+        fn corrupted() -> Vec<u8> {
+            let mut compressed = compress_to_vec(DATA).unwrap();
+            compressed[44] ^= 0x80;
+            compressed
+        }
+
+        /// By default the corrupted block should be an error.
+        #[test]
+        fn strict() {
+            let result =
+                decompress_with(&corrupted()[..], DecompressOptions::default()).map(|_| ());
+
+            assert!(matches!(
+                result,
+                Err(DecompressError::ChecksumMismatch { .. })
+            ));
+        }
+
+        /// Reporting checksums should give back the corrupted data, and record the mismatch.
+        #[test]
+        fn lenient() {
+            let options = DecompressOptions {
+                crc_mode: CrcMode::Report,
+                ..Default::default()
+            };
+
+            let compressed = corrupted();
+
+            let (mut decompressed, report) = decompress_with(&compressed[..], options).unwrap();
+            let mut buffer = vec![];
+            decompressed.read_to_end(&mut buffer).unwrap();
+
+            assert_eq!(buffer.len(), DATA.len());
+            assert_ne!(buffer, DATA);
+            assert_eq!(
+                report.blocks,
+                [BlockCrcInfo {
+                    index: 0,
+                    stored: crc::checksum(DATA),
+                    computed: crc::checksum(&buffer),
+                }]
+            );
+            assert!(!report.blocks[0].is_ok());
+            // The stream CRC is combined from the CRCs stored with each block, so it still matches.
+            assert_eq!(report.streams, [true]);
+            assert!(!report.is_ok());
+        }
+
+        /// Ignoring checksums should be the same as reporting them.
+        #[test]
+        fn ignore_checksums() {
+            let options = DecompressOptions::ignore_checksums();
+            assert_eq!(options.crc_mode, CrcMode::Report);
+
+            let compressed = corrupted();
+
+            let (mut decompressed, report) = decompress_with(&compressed[..], options).unwrap();
+            let mut buffer = vec![];
+            decompressed.read_to_end(&mut buffer).unwrap();
+
+            assert_eq!(buffer.len(), DATA.len());
+            assert!(!report.is_ok());
+        }
+
+        /// Only the corrupt block of several should be recorded as a mismatch.
+        #[test]
+        fn one_of_three() {
+            let data = noise(250_000);
+            let mut compressed = bzip2(&data, 1);
+            let bit = block_bit_ranges(&compressed[..]).unwrap()[1].0 + 48;
+            compressed[(bit / 8) as usize] ^= 0x80 >> (bit % 8);
+            let options = DecompressOptions {
+                crc_mode: CrcMode::Report,
+                ..Default::default()
+            };
+
+            let (mut decompressed, report) = decompress_with(&compressed[..], options).unwrap();
+            let mut buffer = vec![];
+            decompressed.read_to_end(&mut buffer).unwrap();
+
+            assert_eq!(buffer, data);
+            let indices: Vec<u32> = report.blocks.iter().map(|info| info.index).collect();
+            assert_eq!(indices, [0, 1, 2]);
+            let ok: Vec<bool> = report.blocks.iter().map(BlockCrcInfo::is_ok).collect();
+            assert_eq!(ok, [true, false, true]);
+            assert_eq!(report.streams, [false]);
+        }
+
+        /// CRC-32/BZIP2 a bit at a time, written independently of [`crc`].
+        fn bitwise_crc(data: &[u8]) -> u32 {
+            let mut crc = u32::MAX;
+            for &byte in data {
+                crc ^= u32::from(byte) << 24;
+                for _ in 0..8 {
+                    crc = if crc & 0x8000_0000 != 0 {
+                        (crc << 1) ^ 0x04c1_1db7
+                    } else {
+                        crc << 1
+                    };
+                }
+            }
+            !crc
+        }
+
+        /// A custom CRC of the same variant should give the same data and report as our own.
+        #[test]
+        fn crc_impl() {
+            for compressed in [compress_to_vec(DATA).unwrap(), corrupted()] {
+                let builtin = DecompressOptions {
+                    crc_mode: CrcMode::Report,
+                    ..Default::default()
+                };
+                let custom = DecompressOptions {
+                    crc_impl: bitwise_crc,
+                    ..builtin
+                };
+
+                let (mut decompressed, report) = decompress_with(&compressed[..], builtin).unwrap();
+                let mut expected = vec![];
+                decompressed.read_to_end(&mut expected).unwrap();
+                let (mut decompressed, custom_report) =
+                    decompress_with(&compressed[..], custom).unwrap();
+                let mut buffer = vec![];
+                decompressed.read_to_end(&mut buffer).unwrap();
+
+                assert_eq!(buffer, expected);
+                assert_eq!(custom_report, report);
+            }
+        }
+
+        /// A CRC of some other variant should fail the first block.
+        #[test]
+        fn crc_impl_wrong_variant() {
+            let options = DecompressOptions {
+                crc_impl: |data| !crc::checksum(data),
+                ..Default::default()
+            };
+
+            let result = decompress_with(&compress_to_vec(DATA).unwrap()[..], options).map(|_| ());
+
+            assert!(matches!(
+                result,
+                Err(DecompressError::ChecksumMismatch { .. })
+            ));
+        }
+
+        /// Undamaged data should decompress the same either way, with every CRC matching.
+        #[test]
+        fn undamaged() {
+            let data = noise(250_000);
+            let compressed = bzip2(&data, 1);
+            let mut block_crcs = vec![];
+            decompress_each_block(&compressed[..], |_, block| {
+                block_crcs.push(crc::checksum(block));
+            })
+            .unwrap();
+            assert_eq!(block_crcs.len(), 3);
+
+            for crc_mode in [CrcMode::Enforce, CrcMode::Report] {
+                let options = DecompressOptions {
+                    crc_mode,
+                    ..Default::default()
+                };
+
+                let (mut decompressed, report) = decompress_with(&compressed[..], options).unwrap();
+                let mut buffer = vec![];
+                decompressed.read_to_end(&mut buffer).unwrap();
+
+                assert_eq!(buffer, data);
+                let expected: Vec<BlockCrcInfo> = (0..)
+                    .zip(&block_crcs)
+                    .map(|(index, &crc)| BlockCrcInfo {
+                        index,
+                        stored: crc,
+                        computed: crc,
+                    })
+                    .collect();
+                assert_eq!(report.blocks, expected);
+                assert_eq!(report.streams, [true]);
+                assert!(report.is_ok());
+            }
+        }
+    }
+
+    /// Test [`EncodeScratch`].
+    mod encode_scratch {
+        use super::*;
+
+        /// Reusing the scratch space should give the same blocks as starting afresh each time.
+        #[test]
+        fn matches_fresh() {
+            let inputs = [
+                noise(20_000),
+                vec![b'a'; 30_000],
+                b"abc".repeat(500),
+                noise(10),
+            ];
+            let mut scratch = EncodeScratch::default();
+
+            for input in inputs {
+                let rle_data = rle1::encode(&input);
+                let crc = crc::checksum(&input);
+
+                let (reused, reused_sizes) = scratch.encode_block(&rle_data, crc, false);
+                let (fresh, fresh_sizes) =
+                    EncodeScratch::default().encode_block(&rle_data, crc, false);
+
+                assert_eq!(reused_sizes, fresh_sizes);
+                assert_eq!(
+                    file_format::encode(9, &[reused]),
+                    file_format::encode(9, &[fresh])
+                );
+            }
+        }
+
+        /// A second block that is no bigger than the first shouldn't need any of the buffers to
+        /// grow.
+        #[test]
+        fn no_reallocation() {
+            let mut scratch = EncodeScratch::default();
+            let buffers = |scratch: &EncodeScratch| {
+                [
+                    scratch.text.as_ptr() as usize,
+                    scratch.rotations.as_ptr() as usize,
+                    scratch.burrows_wheeler.as_ptr() as usize,
+                    scratch.move_to_front.as_ptr() as usize,
+                    scratch.rle2.as_ptr() as usize,
+                ]
+            };
+
+            scratch.encode_block(&rle1::encode(&noise(20_000)), 0, false);
+            let first = buffers(&scratch);
+            scratch.encode_block(&rle1::encode(&noise(15_000)), 0, false);
+
+            assert_eq!(buffers(&scratch), first);
+        }
+    }
+
+    // This was found by the fuzzer.
+    #[test]
+    fn four_bytes() {
+        let sample = [0x04, 0x00, 0x00, 0x00];
+
+        let mut child = Command::new("bzip2")
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(&sample).unwrap();
+        }
+        let bytes = child.wait_with_output().unwrap().stdout;
+
+        let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+
+        let mut buffer = vec![];
+        let _bytes = data
+            .read_to_end(&mut buffer)
+            .expect("Cannot read decompressed data");
+        assert_eq!(buffer, sample);
+    }
+
+    // This was found by the fuzzer.
+    //
+    // The root cause was failing to do the MTF decode after parsing the list of tree selector
+    // indices.
+    #[test]
+    fn parsing_selectors() {
+        let sample = [
+            0, 255, 255, 72, 255, 255, 255, 255, 0, 5, 2, 255, 7, 255, 0, 0, 0, 0, 1, 0, 2, 255, 0,
+            255, 255, 255, 255, 196, 251, 255, 183, 0, 0, 0, 182, 5, 0, 0, 255, 183, 0, 0, 0, 182,
+            5, 0, 0, 0,
+        ];
+
+        let mut child = Command::new("bzip2")
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(&sample).unwrap();
+        }
+        let bytes = child.wait_with_output().unwrap().stdout;
+
+        let mut data = decompress(&bytes[..]).expect("Cannot decompress test data");
+
+        let mut buffer = vec![];
+        let _bytes = data
+            .read_to_end(&mut buffer)
+            .expect("Cannot read decompressed data");
+        assert_eq!(buffer, sample);
+    }
+
+    /// Test [`stage_sizes`].
+    mod stage_sizes {
+        use super::*;
+
+        /// Ten bytes of `a` become `aaaa` and a count of 6 in the first stage. After the
+        /// Burrows-Wheeler transform that is `aaaa\x06`. The only bytes used are 6 and `a`, so
+        /// move-to-front makes that 1, 0, 0, 0, 1, and the three zeros become RunA RunA.
+        #[test]
+        fn known_data() {
+            let sizes = super::stage_sizes(b"aaaaaaaaaa", 9);
+
+            assert_eq!(sizes.rle1, 5);
+            assert_eq!(sizes.burrows_wheeler, 5);
+            assert_eq!(sizes.move_to_front, 5);
+            assert_eq!(sizes.rle2, 4);
+            // The alphabet only has 4 symbols, so every code is at most 3 bits long.
+            assert_eq!(sizes.huffman, 2);
+        }
+
+        #[test]
+        fn empty() {
+            assert_eq!(super::stage_sizes(b"", 1), StageSizes::default());
+        }
+
+        #[test]
+        #[should_panic]
+        fn invalid_level() {
+            let _sizes = super::stage_sizes(b"a", 10);
+        }
+    }
+
+    /// Test [`compress_parallel`].
+    mod compress_parallel {
+        use super::*;
+
+        fn compress_parallel_k100(data: &[u8], threads: usize) -> Vec<u8> {
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+            let mut compressed = vec![];
+            compress_parallel(data, options, NonZeroUsize::new(threads).unwrap())
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+            compressed
+        }
+
+        /// 8 MB on 4 threads should give exactly what the serial path gives, and decompress back
+        /// to the data.
+        #[test]
+        fn matches_serial() {
+            // Only 16 different bytes, so that the blocks sort quickly but are still full.
+            let data: Vec<u8> = (0..8_000_000_u32)
+                .map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8)
+                .collect();
+            let mut serial = vec![];
+            compress_with(
+                &data[..],
+                CompressOptions {
+                    block_size: BlockSize::K100,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .read_to_end(&mut serial)
+            .unwrap();
+
+            let parallel = compress_parallel_k100(&data, 4);
+
+            assert_eq!(block_bit_ranges(&parallel[..]).unwrap().len(), 81);
+            assert!(parallel == serial);
+            assert!(decompress_to_vec(&parallel).unwrap() == data);
+        }
+
+        /// A partly filled last batch, a single thread, and no data at all should all match the
+        /// serial path too.
+        #[test]
+        fn small() {
+            let data = noise(250_000);
+
+            for (data, threads) in [(&data[..], 3), (&data[..], 1), (&b""[..], 2)] {
+                let mut serial = vec![];
+                compress_with(
+                    data,
+                    CompressOptions {
+                        block_size: BlockSize::K100,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .read_to_end(&mut serial)
+                .unwrap();
+
+                assert_eq!(compress_parallel_k100(data, threads), serial);
+            }
+        }
+    }
+
+    /// Test [`compress_with_stats`].
+    mod compress_with_stats {
+        use super::*;
+
+        /// 1 MB in 100 kB blocks needs several blocks, and the stats should say so.
+        #[test]
+        fn many_blocks() {
+            let data: Vec<u8> = noise(1_000_000)
+                .into_iter()
+                .map(|byte| b'a' + byte % 16)
+                .collect();
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+
+            let (mut compressed, stats) = super::compress_with_stats(&data[..], options).unwrap();
+
+            let mut bytes = vec![];
+            compressed.read_to_end(&mut bytes).unwrap();
+            assert_eq!(stats.input_len, 1_000_000);
+            assert_eq!(stats.output_len, bytes.len() as u64);
+            assert!(stats.block_count > 1);
+            assert_eq!(
+                stats.block_count as usize,
+                block_bit_ranges(&bytes[..]).unwrap().len()
+            );
+        }
+
+        /// Empty input has no blocks, but still writes a stream header and footer.
+        #[test]
+        fn empty() {
+            let (_compressed, stats) =
+                super::compress_with_stats(&b""[..], CompressOptions::default()).unwrap();
+
+            assert_eq!(
+                stats,
+                CompressStats {
+                    input_len: 0,
+                    output_len: 14,
+                    block_count: 0,
+                }
+            );
+        }
+    }
+
+    /// Test [`compress_with`].
+    mod compress_with {
+        use super::*;
+
+        /// Generate `len` bytes from only 16 different values, which sort quickly but still need
+        /// every block to be full.
+        fn small_alphabet_noise(len: usize) -> Vec<u8> {
+            noise(len)
+                .into_iter()
+                .map(|byte| b'a' + byte % 16)
+                .collect()
+        }
+
+        fn compress_with(data: &[u8], block_size: BlockSize) -> Vec<u8> {
+            let mut compressed = vec![];
+            super::compress_with(
+                data,
+                CompressOptions {
+                    block_size,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+            compressed
+        }
+
+        /// Data bigger than the block size should be split over many blocks.
+        #[test]
+        fn many_blocks() {
+            let data = small_alphabet_noise(2_000_000);
+
+            let compressed = compress_with(&data, BlockSize::K100);
+
+            assert_eq!(&compressed[..4], b"BZh1");
+            assert_eq!(block_bit_ranges(&compressed[..]).unwrap().len(), 21);
+            let mut decompressed = vec![];
+            decompress(&compressed[..])
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, data);
+        }
+
+        /// A block is full when its first run-length encoding is, so data that has a lot of runs
+        /// fits more bytes into each block.
+        #[test]
+        fn runs() {
+            let data = vec![b'a'; 2_000_000];
+
+            let compressed = compress_with(&data, BlockSize::K100);
+
+            assert_eq!(block_bit_ranges(&compressed[..]).unwrap().len(), 1);
+        }
+
+        /// A last block that is shorter than the block size should decode to just its own bytes,
+        /// whether we or `bzip2` wrote it.
+        #[test]
+        fn short_last_block() {
+            let data = noise(150_000);
+
+            for compressed in [compress_with(&data, BlockSize::K100), bzip2(&data, 1)] {
+                let mut block_lens = vec![];
+                decompress_each_block(&compressed[..], |_, block| block_lens.push(block.len()))
+                    .unwrap();
+                let mut decompressed = vec![];
+                Decompressor::new(&compressed[..])
+                    .unwrap()
+                    .read_to_end(&mut decompressed)
+                    .unwrap();
+
+                assert_eq!(block_lens.len(), 2);
+                assert!(block_lens[1] < 51_000, "{block_lens:?}");
+                assert_eq!(block_lens.iter().sum::<usize>(), data.len());
+                assert_eq!(decompressed, data);
+            }
+        }
+
+        /// Each block size should go in the header, and `bzip2` should be happy with the output.
+        #[test]
+        fn every_block_size() {
+            let data = small_alphabet_noise(5_000);
+
+            for level in 1..=9 {
+                let block_size = BlockSize::from_level(level).unwrap();
+                assert_eq!(block_size.level(), level);
+
+                let compressed = compress_with(&data, block_size);
+
+                assert_eq!(compressed[3], b'0' + level);
+                let mut child = Command::new("bzip2")
+                    .arg("-dc")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                child.stdin.take().unwrap().write_all(&compressed).unwrap();
+                let output = child.wait_with_output().unwrap();
+                assert!(output.status.success(), "level {level}");
+                assert_eq!(output.stdout, data);
+            }
+        }
+
+        /// compress() should be the same as the largest block size.
+        #[test]
+        fn default() {
+            let data = small_alphabet_noise(1_000);
+            let mut compressed = vec![];
+
+            compress(&data[..])
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+
+            assert_eq!(compressed, compress_with(&data, BlockSize::K900));
+        }
+    }
+
+    /// Test [`compress_multi`].
+    mod compress_multi {
+        use super::*;
+
+        /// Each input should get a stream of its own, which decompress to all of the inputs
+        /// together.
+        #[test]
+        fn three_inputs() {
+            let inputs = [noise(150_000), b"".to_vec(), vec![b'z'; 10_000]];
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+
+            let compressed = super::compress_multi(inputs.clone(), options).unwrap();
+
+            let streams = file_format::decode_streams(&compressed).unwrap();
+            assert_eq!(streams.len(), 3);
+            assert_eq!(
+                streams.iter().map(|s| s.blocks().len()).collect::<Vec<_>>(),
+                [2, 0, 1]
+            );
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), inputs.concat());
+            let mut separately = vec![];
+            for input in &inputs {
+                compress_with(&input[..], options)
+                    .unwrap()
+                    .read_to_end(&mut separately)
+                    .unwrap();
+            }
+            assert_eq!(compressed, separately);
+        }
+
+        #[test]
+        fn no_inputs() {
+            let inputs: [&[u8]; 0] = [];
+
+            let compressed = super::compress_multi(inputs, CompressOptions::default()).unwrap();
+
+            assert!(compressed.is_empty());
+        }
+    }
+
+    /// Test [`validate_compress`].
+    mod validate_compress {
+        use super::*;
+
+        /// The plan should match what compressing data without runs of the same length does.
+        #[test]
+        fn matches_compression() {
+            for (len, level) in [(0, 9), (1, 9), (250_000, 1), (899_981, 9), (899_982, 9)] {
+                let options = CompressOptions {
+                    block_size: BlockSize::from_level(level).unwrap(),
+                    ..Default::default()
+                };
+                let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+                let plan = super::validate_compress(len, &options).unwrap();
+
+                let (mut compressed, stats) = compress_with_stats(&data[..], options).unwrap();
+                let mut buffer = vec![];
+                compressed.read_to_end(&mut buffer).unwrap();
+                assert_eq!(
+                    plan.block_count, stats.block_count,
+                    "{len} at level {level}"
+                );
+                assert_eq!(plan.tables.len(), plan.block_count as usize);
+                assert!(plan.header_overhead <= buffer.len() as u64);
+            }
+        }
+
+        #[test]
+        fn plan() {
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+
+            let plan = super::validate_compress(200_000, &options).unwrap();
+
+            assert_eq!(
+                plan,
+                CompressPlan {
+                    block_count: 3,
+                    tables: vec![6, 6, 2],
+                    // 32 + 80 + 3 * 105 = 427 bits.
+                    header_overhead: 54,
+                }
+            );
+        }
+
+        /// Only the last block can be short enough for fewer tables.
+        #[test]
+        fn small_last_block() {
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                max_block_buffer: Some(1_000),
+                ..Default::default()
+            };
+
+            let plan = super::validate_compress(2_500, &options).unwrap();
+
+            assert_eq!(plan.tables, [4, 4, 3]);
+        }
+    }
+
+    /// Test [`CompressOptions::max_block_buffer`].
+    mod max_block_buffer {
+        use super::*;
+
+        /// A small buffer should cut the data into many blocks, while the header still declares
+        /// the block size.
+        #[test]
+        fn small_buffer() {
+            let data = noise(50_000);
+            let options = CompressOptions {
+                block_size: BlockSize::K900,
+                max_block_buffer: Some(4_096),
+                ..Default::default()
+            };
+
+            let mut compressed = vec![];
+            compress_with(&data[..], options)
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+
+            assert_eq!(peek_block_size(&compressed[..]).unwrap(), BlockSize::K900);
+            let mut lengths = vec![];
+            decompress_each_block(&compressed[..], |_, block| lengths.push(block.len())).unwrap();
+            assert_eq!(lengths.len(), 13);
+            assert!(lengths.iter().all(|&len| len <= 4_096), "{lengths:?}");
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), data);
+            assert_eq!(bzip2_decompress(&compressed), data);
+        }
+
+        /// Every way of compressing should cut blocks at the same places.
+        #[test]
+        fn parallel() {
+            let data = noise(50_000);
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                max_block_buffer: Some(10_000),
+                ..Default::default()
+            };
+
+            let mut serial = vec![];
+            compress_with(&data[..], options)
+                .unwrap()
+                .read_to_end(&mut serial)
+                .unwrap();
+            let mut parallel = vec![];
+            compress_parallel(&data[..], options, NonZeroUsize::new(2).unwrap())
+                .unwrap()
+                .read_to_end(&mut parallel)
+                .unwrap();
+
+            assert_eq!(parallel, serial);
+            assert_eq!(block_bit_ranges(&serial[..]).unwrap().len(), 5);
+        }
+
+        /// A buffer too small for a run, or larger than a block, should be clamped.
+        #[test]
+        fn clamped() {
+            let data = vec![b'a'; 1_000];
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                max_block_buffer: Some(0),
+                ..Default::default()
+            };
+
+            let mut compressed = vec![];
+            compress_with(&data[..], options)
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+
+            // Each block holds a single run of 4 bytes and a count, for up to 259 bytes.
+            assert_eq!(block_bit_ranges(&compressed[..]).unwrap().len(), 4);
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), data);
+
+            let large = CompressOptions {
+                block_size: BlockSize::K100,
+                max_block_buffer: Some(usize::MAX),
+                ..Default::default()
+            };
+            assert_eq!(large.max_block_len(), BlockSize::K100.max_block_len());
+        }
+    }
+
+    /// Test [`CompressOptions::single_table`].
+    mod single_table {
+        use super::*;
+
+        /// Every block should have two tables that are the same, and all its groups should use
+        /// the first. `bzip2` should read the result too.
+        #[test]
+        fn decodes() {
+            let data = [
+                noise(150_000),
+                b"If Peter Piper picked a peck of pickled peppers".repeat(1_000),
+            ]
+            .concat();
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                single_table: true,
+                ..Default::default()
+            };
+
+            let compressed = compress_multi([&data], options).unwrap();
+
+            let blocks = file_format::decode(&compressed).unwrap();
+            assert_eq!(blocks.len(), 2);
+            for block in &blocks {
+                assert!(block.selectors().iter().all(|&selector| selector == 0));
+            }
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), data);
+            assert_eq!(bzip2_decompress(&compressed), data);
+        }
+
+        /// Compressing in parallel should give the same output.
+        #[test]
+        fn parallel() {
+            let data = noise(250_000);
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                single_table: true,
+                ..Default::default()
+            };
+
+            let mut parallel = vec![];
+            compress_parallel(&data[..], options, NonZeroUsize::new(3).unwrap())
+                .unwrap()
+                .read_to_end(&mut parallel)
+                .unwrap();
+
+            assert_eq!(parallel, compress_multi([&data], options).unwrap());
+        }
+    }
+
+    /// Test [`with_compressed`].
+    mod with_compressed {
+        use super::*;
+
+        /// The closure should see the same bytes that [`compress_to_vec`] returns.
+        #[test]
+        fn len() {
+            let data = noise(150_000);
+            let expected = compress_to_vec(&data).unwrap();
+
+            let len = super::with_compressed(&data[..], CompressOptions::default(), |output| {
+                assert_eq!(output, expected);
+                output.len()
+            })
+            .unwrap();
+
+            assert_eq!(len, expected.len());
+        }
+    }
+
+    /// Test [`compress_capped`].
+    mod compress_capped {
+        use super::*;
+
+        /// Noise doesn't compress, so a cap of half its size should stop compression after the
+        /// first few blocks have been written.
+        #[test]
+        fn too_large() {
+            let data = noise(1_000_000);
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+
+            let result = super::compress_capped(&data[..], options, 500_000);
+
+            let Err(CompressError::OutputTooLarge { produced, cap }) = result else {
+                panic!("expected OutputTooLarge, got {result:?}");
+            };
+            assert_eq!(cap, 500_000);
+            assert!(produced > cap);
+            // Each block is a little over 100 kB, so it stops with the sixth.
+            assert!(produced < 700_000, "{produced}");
+        }
+
+        /// The cap is checked against the whole of the output, footer included.
+        #[test]
+        fn exact() {
+            let data = noise(10_000);
+            let compressed = compress_to_vec(&data).unwrap();
+
+            let result =
+                super::compress_capped(&data[..], CompressOptions::default(), compressed.len());
+            assert_eq!(result.unwrap(), compressed);
+
+            let result =
+                super::compress_capped(&data[..], CompressOptions::default(), compressed.len() - 1);
+            assert!(matches!(
+                result,
+                Err(CompressError::OutputTooLarge { produced, .. }) if produced == compressed.len()
+            ));
+        }
+    }
+
+    /// Test [`compress_with_boundaries`].
+    mod compress_with_boundaries {
+        use super::*;
+
+        /// There should be a block for each stretch between the boundaries, holding just that
+        /// stretch.
+        #[test]
+        fn exact_blocks() {
+            let data = noise(200_000);
+            let boundaries = [1, 50_000, 50_010, 140_000];
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+
+            let compressed = super::compress_with_boundaries(&data, &boundaries, options).unwrap();
+
+            let mut lengths = vec![];
+            decompress_each_block(&compressed[..], |_, block| lengths.push(block.len())).unwrap();
+            assert_eq!(lengths, [1, 49_999, 10, 89_990, 60_000]);
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), data);
+            assert_eq!(bzip2_decompress(&compressed), data);
+        }
+
+        /// Without any boundaries, everything should go in the one block.
+        #[test]
+        fn no_boundaries() {
+            let data = noise(1_000);
+
+            let compressed =
+                super::compress_with_boundaries(&data, &[], CompressOptions::default()).unwrap();
+
+            assert_eq!(block_bit_ranges(&compressed[..]).unwrap().len(), 1);
+            assert_eq!(decompress_to_vec(&compressed).unwrap(), data);
+        }
+
+        /// Empty data should give a stream with no blocks.
+        #[test]
+        fn empty() {
+            let compressed =
+                super::compress_with_boundaries(b"", &[], CompressOptions::default()).unwrap();
+
+            assert_eq!(compressed, compress_to_vec(b"").unwrap());
+        }
+
+        #[test]
+        fn invalid_boundaries() {
+            let data = noise(1_000);
+
+            for (boundaries, bad) in [
+                (&[0][..], 0),
+                (&[1_000], 0),
+                (&[2_000], 0),
+                (&[500, 500], 1),
+                (&[500, 400], 1),
+                (&[100, 200, 1_000], 2),
+            ] {
+                let result =
+                    super::compress_with_boundaries(&data, boundaries, CompressOptions::default());
+
+                assert!(
+                    matches!(result, Err(CompressError::InvalidBoundary { index }) if index == bad),
+                    "{boundaries:?}"
+                );
+            }
+        }
+
+        /// A stretch that doesn't fit in a block should be an error, rather than being split.
+        #[test]
+        fn too_large() {
+            let data = noise(150_000);
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+
+            let result = super::compress_with_boundaries(&data, &[10_000], options);
+
+            assert!(matches!(
+                result,
+                Err(CompressError::BlockTooLarge {
+                    index: 1,
+                    max_len: 99_981,
+                    ..
+                })
+            ));
+        }
+    }
+
+    /// Test [`compress_file`] and [`decompress_file`].
+    mod file {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        /// A file in the temporary directory, which is deleted when this is dropped.
+        struct TempFile(PathBuf);
+
+        impl TempFile {
+            fn new(name: &str, contents: &[u8]) -> Self {
+                let path =
+                    std::env::temp_dir().join(format!("beeziptoo-{}-{name}", std::process::id()));
+                std::fs::write(&path, contents).unwrap();
+                TempFile(path)
+            }
+        }
+
+        impl Drop for TempFile {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+
+        #[test]
+        fn roundtrip() {
+            let data = noise(250_000);
+            let options = CompressOptions {
+                block_size: BlockSize::K100,
+                ..Default::default()
+            };
+            let plain = TempFile::new("roundtrip", &data);
+
+            let compressed = compress_file(&plain.0, options).unwrap();
+
+            assert_eq!(compressed, compress_multi([&data], options).unwrap());
+            let compressed = TempFile::new("roundtrip.bz2", &compressed);
+            assert_eq!(decompress_file(&compressed.0).unwrap(), data);
+        }
+
+        /// A corrupt file should give the same error as decompressing it from memory.
+        #[test]
+        fn corrupt() {
+            let mut compressed = compress_to_vec(b"If Peter Piper picked a peck").unwrap();
+            compressed[13] ^= 0x01;
+            let compressed = TempFile::new("corrupt.bz2", &compressed);
+
+            assert!(matches!(
+                decompress_file(&compressed.0),
+                Err(DecompressError::ChecksumMismatch { .. })
+            ));
+        }
+
+        #[test]
+        fn missing() {
+            let path = std::env::temp_dir().join("beeziptoo-there-is-no-such-file");
+
+            assert!(matches!(
+                compress_file(&path, CompressOptions::default()),
+                Err(CompressError::IOError(error)) if error.kind() == io::ErrorKind::NotFound
+            ));
+            assert!(matches!(
+                decompress_file(&path),
+                Err(DecompressError::IOError(error)) if error.kind() == io::ErrorKind::NotFound
+            ));
+        }
+    }
+
+    /// Test [`recompress`].
+    mod recompress {
+        use super::*;
+
+        /// Moving from level 1 to level 9 should put all of the data in one block.
+        #[test]
+        fn larger_blocks() {
+            let data = noise(250_000);
+            let compressed = bzip2(&data, 1);
+            assert_eq!(block_bit_ranges(&compressed[..]).unwrap().len(), 3);
+            let options = CompressOptions {
+                block_size: BlockSize::K900,
+                ..Default::default()
+            };
+
+            let recompressed = super::recompress(&compressed, options).unwrap();
+
+            assert_eq!(peek_block_size(&recompressed[..]).unwrap(), BlockSize::K900);
+            assert_eq!(block_bit_ranges(&recompressed[..]).unwrap().len(), 1);
+            assert_eq!(decompress_to_vec(&recompressed).unwrap(), data);
+        }
+
+        /// A damaged input should give the decompression error.
+        #[test]
+        fn corrupt() {
+            let mut compressed = compress_to_vec(b"If Peter Piper picked a peck").unwrap();
+            compressed[13] ^= 0x01;
+
+            let error = super::recompress(&compressed, CompressOptions::default()).unwrap_err();
+
+            assert!(matches!(error, DecompressError::ChecksumMismatch { .. }));
+        }
+    }
+
+    /// Compressing and then decompressing should give back the data we started with.
+    mod roundtrip {
+        use super::*;
+
+        fn roundtrip(data: &[u8]) -> Vec<u8> {
+            let mut compressed = compress(data).expect("Could not compress data");
+            let mut decompressed = decompress(&mut compressed).expect("Could not decompress data");
+
+            let mut buffer = vec![];
+            let _num_bytes = decompressed
+                .read_to_end(&mut buffer)
+                .expect("Could not read decompressed data");
+            buffer
+        }
+
+        #[test]
+        fn empty() {
+            assert_eq!(roundtrip(b""), b"");
+        }
+
+        #[test]
+        fn tiny() {
+            for data in [&b"a"[..], b"ab", b"aba", b"aaaaaaaa"] {
+                assert_eq!(roundtrip(data), data);
+            }
+        }
+
+        /// A few KB of text, with some runs and some noise mixed in.
+        #[test]
+        fn mixed_text() {
+            let mut data = vec![];
+            for i in 0..40 {
+                data.extend_from_slice(
+                    b"If Peter Piper picked a peck of pickled peppers, where's the peck of pickled \
+                      peppers Peter Piper picked?\n",
+                );
+                data.extend(std::iter::repeat_n(b'!', i * 7));
+                data.extend(noise(i));
+            }
+
+            assert_eq!(roundtrip(&data), data);
+        }
+
+        /// Long stretches of a repeated byte become long runs of zeros after move-to-front, which
+        /// is exactly what the second run-length stage encodes.
+        #[test]
+        fn zero_runs() {
+            let mut data = vec![];
+            for (i, byte) in (0..=255_u8).cycle().step_by(37).take(300).enumerate() {
+                data.extend(std::iter::repeat_n(byte, i % 13 + 1));
+                data.extend(std::iter::repeat_n(b'z', i * 3));
+            }
+
+            let rle2_data = rle2::encode(&move_to_front::encode(
+                &burrows_wheeler::encode(&rle1::encode(&data)).data,
+            ));
+            assert!(rle2_data
+                .iter()
+                .any(|symbol| matches!(symbol, rle2::Symbol::RunB)));
+            assert_eq!(roundtrip(&data), data);
+        }
+    }
+
+    /// Round-trip arbitrary inputs, which proptest shrinks to a minimal one if it finds a failure.
+    mod property {
+        use proptest::{
+            collection::vec,
+            prelude::*,
+            test_runner::{Config, RngSeed},
+        };
+
+        use super::*;
+
+        /// Inputs of up to 64 KB. Uniformly random bytes hardly ever repeat, so some of the inputs
+        /// only use a few different bytes, and some are made of long runs, to exercise the
+        /// run-length stages too.
+        fn input() -> impl Strategy<Value = Vec<u8>> {
+            prop_oneof![
+                vec(any::<u8>(), 0..=65_536),
+                vec(0..4_u8, 0..=65_536),
+                vec((any::<u8>(), 1..600_usize), 0..=200).prop_map(|runs| {
+                    runs.into_iter()
+                        .flat_map(|(byte, len)| std::iter::repeat_n(byte, len))
+                        .collect()
+                }),
+            ]
+        }
+
+        proptest! {
+            // A fixed seed, so that a failure in CI happens again locally.
+            #![proptest_config(Config {
+                cases: 64,
+                rng_seed: RngSeed::Fixed(0x6265_657a),
+                failure_persistence: None,
+                ..Config::default()
+            })]
+
+            #[test]
+            fn roundtrip(data in input()) {
+                let compressed = compress_to_vec(&data).unwrap();
+
+                prop_assert_eq!(decompress_to_vec(&compressed).unwrap(), data);
+            }
+        }
     }
 }
@@ -2,32 +2,91 @@
 //!
 //! [move-to-front]: https://en.wikipedia.org/wiki/Move-to-front_transform
 
-use crate::file_format::SymbolStack;
+use alloc::{vec, vec::Vec};
+
+use thiserror::Error as ThisError;
+
+/// The order of the bytes that the move-to-front indices refer to.
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub(crate) struct SymbolStack(pub(crate) Vec<u8>);
+
+#[derive(Debug, PartialEq, ThisError)]
+pub(crate) enum MtfError {
+    #[error("The symbol {0} is not in the alphabet")]
+    SymbolNotInAlphabet(u8),
+}
+
+/// An index in the data to decode that is past the end of the alphabet.
+#[derive(Debug, PartialEq, ThisError)]
+#[error("Index {index} is not in an alphabet of {alphabet_len}, after {offset} bytes of output")]
+pub(crate) struct DecodeError {
+    pub(crate) index: u8,
+    pub(crate) alphabet_len: usize,
+    /// How many bytes had been decoded before the bad index.
+    pub(crate) offset: usize,
+}
 
 /// Convert the data into the move-to-front encoded format.
 pub(super) fn encode(data: &[u8]) -> Vec<u8> {
-    // This list contains all the possible bytes that can be found in data. As we encounter each
-    // byte in input, we find it in this list and the index in the list is encoded into the output.
-    // Then the value is move to the front of the list. Over time, this results in the frequently
-    // used bytes tending towards the front of the list, and the infrequently used bytes tending
-    // towards the back.
-    let mut symbols: Vec<u8> = (0..=255).collect();
+    encode_with_alphabet(data, SymbolStack((0..=255).collect()))
+        .expect("Every possible byte should be here")
+}
+
+/// Find the bytes that are used in `data`, in order.
+///
+/// Using these as the alphabet for [`encode_with_alphabet`] keeps the encoded values as small as
+/// possible, and they are what the used map in a block header records.
+pub(super) fn used_symbols(data: &[u8]) -> SymbolStack {
+    let mut used = [false; 256];
+    for &byte in data {
+        used[usize::from(byte)] = true;
+    }
+
+    SymbolStack((0..=255).filter(|&byte| used[usize::from(byte)]).collect())
+}
+
+/// Convert the data into the move-to-front encoded format, using only the symbols in `alphabet`.
+///
+/// `alphabet` contains all the possible bytes that can be found in data. As we encounter each byte
+/// in input, we find it in this list and the index in the list is encoded into the output. Then
+/// the value is move to the front of the list. Over time, this results in the frequently used
+/// bytes tending towards the front of the list, and the infrequently used bytes tending towards
+/// the back.
+///
+/// Returns [`MtfError::SymbolNotInAlphabet`] if `data` contains a byte that isn't in `alphabet`.
+pub(super) fn encode_with_alphabet(
+    data: &[u8],
+    symbol_stack: SymbolStack,
+) -> Result<Vec<u8>, MtfError> {
     let mut output = vec![];
 
+    encode_with_alphabet_into(data, symbol_stack, &mut output)?;
+
+    Ok(output)
+}
+
+/// Convert the data into the move-to-front encoded format, appending it to `output`.
+///
+/// See [`encode_with_alphabet`].
+pub(super) fn encode_with_alphabet_into(
+    data: &[u8],
+    SymbolStack(mut symbols): SymbolStack,
+    output: &mut Vec<u8>,
+) -> Result<(), MtfError> {
     for byte in data {
-        let (index, _) = symbols
+        let index = symbols
             .iter()
-            .enumerate()
-            .find(|(_, value)| *value == byte)
-            .expect("Every possible byte should be here");
-        // This as should be safe because we know there are only 256 values in symbols, so the
+            .position(|value| value == byte)
+            .ok_or(MtfError::SymbolNotInAlphabet(*byte))?;
+        // This as should be safe because there are at most 256 distinct values in symbols, so the
         // index should always encodable as a u8.
         output.push(index as u8);
         let value = symbols.remove(index);
         symbols.insert(0, value);
     }
 
-    output
+    Ok(())
 }
 
 /// Convert the move-to-front encoded data back to the original data.
@@ -35,20 +94,41 @@ pub(super) fn encode(data: &[u8]) -> Vec<u8> {
 /// The `symbol_stack` contains all the possible values that can be found in the output data. As we
 /// encounter each byte in input, we use it as an index into this list in this list and the value
 /// at that index is encoded into the output. Then the value is move to the front of the list.
-pub(super) fn decode(data: &[u8], SymbolStack(mut symbols): SymbolStack) -> Vec<u8> {
+///
+/// Returns a [`DecodeError`] if `data` has an index that is past the end of `symbol_stack`, which
+/// corrupt input can have when the alphabet is smaller than 256 bytes.
+pub(super) fn decode(data: &[u8], symbol_stack: SymbolStack) -> Result<Vec<u8>, DecodeError> {
     let mut output = vec![];
 
-    for index in data {
-        // This should be safe because on all platforms a u8 should always be safe to convert to a
-        // usize.
-        let index = *index as usize;
-        let value = symbols[index];
+    decode_into(data, symbol_stack, &mut output)?;
+
+    Ok(output)
+}
+
+/// Convert the move-to-front encoded data back to the original data, appending it to `output`.
+///
+/// See [`decode`].
+pub(super) fn decode_into(
+    data: &[u8],
+    SymbolStack(mut symbols): SymbolStack,
+    output: &mut Vec<u8>,
+) -> Result<(), DecodeError> {
+    let start = output.len();
+
+    for &index in data {
+        if usize::from(index) >= symbols.len() {
+            return Err(DecodeError {
+                index,
+                alphabet_len: symbols.len(),
+                offset: output.len() - start,
+            });
+        }
+        let value = symbols.remove(index.into());
         output.push(value);
-        let value = symbols.remove(index);
         symbols.insert(0, value);
     }
 
-    output
+    Ok(())
 }
 
 #[cfg(test)]
@@ -63,7 +143,7 @@ mod tests {
             let symbol_stack = SymbolStack((0..=255).collect());
             let input = [1, 1, 13, 1, 1, 1];
 
-            let decoded = decode(&input, symbol_stack);
+            let decoded = decode(&input, symbol_stack).unwrap();
 
             // This is basically "banana" if you map a-z to 0-25.
             assert_eq!(decoded, [1, 0, 13, 0, 13, 0]);
@@ -75,10 +155,94 @@ mod tests {
             let symbol_stack = SymbolStack((0..=255).collect());
             let input = [255];
 
-            let decoded = decode(&input, symbol_stack);
+            let decoded = decode(&input, symbol_stack).unwrap();
 
             assert_eq!(decoded, [255]);
         }
+
+        /// An index past the end of a reduced alphabet should be an error rather than a panic.
+        #[test]
+        fn out_of_range() {
+            let symbol_stack = SymbolStack(vec![b'a', b'b', b'n', b'z']);
+            let input = [1, 0, 2, 255];
+
+            let decoded = decode(&input, symbol_stack);
+
+            assert_eq!(
+                decoded,
+                Err(DecodeError {
+                    index: 255,
+                    alphabet_len: 4,
+                    offset: 3,
+                })
+            );
+        }
+    }
+
+    mod test_decode_into {
+        use super::*;
+
+        /// The decoded data should be appended to what is already there.
+        #[test]
+        fn appends() {
+            let symbol_stack = SymbolStack((0..=255).collect());
+            let mut output = vec![7];
+
+            decode_into(&[1, 1, 13], symbol_stack, &mut output).unwrap();
+
+            assert_eq!(output, [7, 1, 0, 13]);
+        }
+    }
+
+    mod test_encode_with_alphabet {
+        use super::*;
+
+        /// Indices are positions in the given alphabet, not byte values.
+        #[test]
+        fn compacted() {
+            let alphabet = SymbolStack(vec![b'a', b'b', b'n']);
+
+            let encoded = encode_with_alphabet(b"banana", alphabet);
+
+            assert_eq!(encoded, Ok(vec![1, 1, 2, 1, 1, 1]));
+        }
+
+        #[test]
+        fn symbol_not_in_alphabet() {
+            let alphabet = SymbolStack(vec![b'a', b'b', b'n']);
+
+            let encoded = encode_with_alphabet(b"bandana", alphabet);
+
+            assert_eq!(encoded, Err(MtfError::SymbolNotInAlphabet(b'd')));
+        }
+    }
+
+    mod test_used_symbols {
+        use super::*;
+
+        #[test]
+        fn sorted() {
+            assert_eq!(used_symbols(b"banana"), SymbolStack(vec![b'a', b'b', b'n']));
+        }
+
+        #[test]
+        fn empty() {
+            assert_eq!(used_symbols(b""), SymbolStack(vec![]));
+        }
+
+        /// With only the used bytes as the alphabet, a block with 4 different bytes should only
+        /// need the values 0 to 3.
+        #[test]
+        fn four_bytes() {
+            let data: Vec<u8> = (0..1_000_u32)
+                .map(|i| [b'w', 0xff, b'\n', 0x00][(i.wrapping_mul(2_654_435_761) >> 30) as usize])
+                .collect();
+
+            let encoded = encode_with_alphabet(&data, used_symbols(&data)).unwrap();
+
+            assert!(encoded.iter().all(|&index| index <= 3));
+            assert!(encoded.contains(&3));
+        }
     }
 
     mod test_encode {
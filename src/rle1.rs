@@ -1,74 +1,320 @@
 //! Run length encoding.
+use alloc::vec::Vec;
+use core::iter::Peekable;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
 use thiserror::Error as ThisError;
 
+#[cfg(feature = "std")]
+use crate::crc::Crc;
+
 #[derive(Debug, ThisError)]
 pub(crate) enum Error {
-    #[error("A run length was invalid: {0}")]
-    RunLengthInvalid(u8),
-
-    #[error("The run length encoded array was truncated")]
-    RunLengthTruncated,
+    /// The data ended four bytes into a run, where its count should have been.
+    #[error("The run length encoded array was truncated after {offset} bytes of output")]
+    RunLengthTruncated {
+        /// How many bytes had been decoded before the truncated run.
+        offset: usize,
+    },
+
+    #[cfg(feature = "std")]
+    #[error("IoError: {0}")]
+    Io(#[from] io::Error),
 }
 
+/// The longest a single run can be: four literal bytes and a count byte.
+pub(super) const MAX_RUN_LENGTH: usize = 5;
+
 /// Convert `data` into a run-length encoded byte array.
 pub(super) fn encode(data: &[u8]) -> Vec<u8> {
-    if data.is_empty() {
-        return Vec::new();
+    encode_iter(data.iter().copied()).collect()
+}
+
+/// Run-length encode `data`, writing the result to `output`.
+///
+/// The encoded bytes are written a few thousand at a time, so nothing the size of the output is
+/// ever allocated.
+#[cfg(feature = "std")]
+pub(super) fn encode_to<W>(data: &[u8], mut output: W) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut encoded = encode_iter(data.iter().copied());
+    let mut buffer = [0; 4096];
+    loop {
+        // The buffer comes first, so that no byte is taken from `encoded` once it is full.
+        let mut len = 0;
+        for (slot, byte) in buffer.iter_mut().zip(&mut encoded) {
+            *slot = byte;
+            len += 1;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        output.write_all(&buffer[..len])?;
+    }
+}
+
+/// Run-length encode the bytes from `input` as they are needed, rather than all at once.
+pub(super) fn encode_iter<I>(input: I) -> EncodeIter<I::IntoIter>
+where
+    I: IntoIterator<Item = u8>,
+{
+    EncodeIter {
+        input: input.into_iter().peekable(),
+        run: [0; MAX_RUN_LENGTH],
+        run_len: 0,
+        position: 0,
+    }
+}
+
+/// The iterator returned by [`encode_iter`].
+#[derive(Debug)]
+pub(super) struct EncodeIter<I>
+where
+    I: Iterator<Item = u8>,
+{
+    input: Peekable<I>,
+    /// The encoded form of the current run.
+    run: [u8; MAX_RUN_LENGTH],
+    run_len: usize,
+    /// How much of `run` has been yielded so far.
+    position: usize,
+}
+
+impl<I> Iterator for EncodeIter<I>
+where
+    I: Iterator<Item = u8>,
+{
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.position == self.run_len {
+            let byte = self.input.next()?;
+            let mut length: u8 = 1;
+            // Stopping at 255 keeps the count byte at 251 or less, which is all `bzip2` writes.
+            while length < u8::MAX && self.input.next_if_eq(&byte).is_some() {
+                length += 1;
+            }
+
+            self.run_len = if length <= 3 {
+                self.run[..length.into()].fill(byte);
+                length.into()
+            } else {
+                self.run = [byte, byte, byte, byte, length - 4];
+                MAX_RUN_LENGTH
+            };
+            self.position = 0;
+        }
+
+        let byte = self.run[self.position];
+        self.position += 1;
+
+        Some(byte)
+    }
+}
+
+/// Run-length encodes data that arrives in pieces, splitting the output into blocks.
+///
+/// A block is cut as soon as it holds `max_len` or more encoded bytes, before the next run is added,
+/// which is where bzip2 cuts its blocks. Runs are never split, so a block can hold up to 4 bytes
+/// more than `max_len`, and every block can be decoded on its own.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub(super) struct BlockEncoder {
+    max_len: usize,
+    /// The byte that the current run repeats, and how many times it has so far.
+    run: Option<(u8, u8)>,
+    /// The encoded runs of the current block.
+    block: Vec<u8>,
+    /// The CRC of the bytes that went into `block`, before they were encoded.
+    crc: Crc,
+}
+
+#[cfg(feature = "std")]
+impl BlockEncoder {
+    /// # Panics
+    ///
+    /// If `max_len` is too small to hold a single run.
+    pub(super) fn new(max_len: usize) -> Self {
+        assert!(
+            max_len >= MAX_RUN_LENGTH,
+            "max_len must be at least {MAX_RUN_LENGTH} but was {max_len}"
+        );
+
+        BlockEncoder {
+            max_len,
+            run: None,
+            block: vec![],
+            crc: Crc::default(),
+        }
+    }
+
+    /// Encode `data`, handing each block that fills up to `full_block` along with its CRC.
+    ///
+    /// Stops at the first error from `full_block`.
+    pub(super) fn write<F, E>(&mut self, data: &[u8], mut full_block: F) -> Result<(), E>
+    where
+        F: FnMut(Vec<u8>, u32) -> Result<(), E>,
+    {
+        for &byte in data {
+            self.run = match self.run {
+                Some((run_byte, length)) if run_byte == byte && length < u8::MAX => {
+                    Some((run_byte, length + 1))
+                }
+                Some((run_byte, length)) => {
+                    self.end_run(run_byte, length, &mut full_block)?;
+                    Some((byte, 1))
+                }
+                None => Some((byte, 1)),
+            };
+        }
+
+        Ok(())
     }
-    // This is safe because we checked for data being empty above.
-    let mut run_start = 0;
-    let mut output = vec![];
 
-    for (i, byte) in data.iter().enumerate() {
-        let run_length: u8 = (i - run_start) as u8;
-        if *byte != data[run_start] || run_length == 255 {
-            encode_run(&data[run_start..i], &mut output);
-            run_start = i;
+    /// Encode the last run, and hand every block that is left to `full_block`.
+    pub(super) fn finish<F, E>(mut self, mut full_block: F) -> Result<(), E>
+    where
+        F: FnMut(Vec<u8>, u32) -> Result<(), E>,
+    {
+        if let Some((byte, length)) = self.run.take() {
+            self.end_run(byte, length, &mut full_block)?;
+        }
+        if !self.block.is_empty() {
+            full_block(self.block, self.crc.value())?;
         }
+
+        Ok(())
     }
 
-    encode_run(&data[run_start..], &mut output);
+    fn end_run<F, E>(&mut self, byte: u8, length: u8, full_block: &mut F) -> Result<(), E>
+    where
+        F: FnMut(Vec<u8>, u32) -> Result<(), E>,
+    {
+        let run = [byte; u8::MAX as usize];
+        let run = &run[..length.into()];
+
+        if self.block.len() >= self.max_len {
+            let block = std::mem::take(&mut self.block);
+            let crc = std::mem::take(&mut self.crc);
+            full_block(block, crc.value())?;
+        }
+
+        encode_run(run, &mut self.block).expect("Writing to a Vec cannot fail");
+        self.crc.update(run);
 
-    output
+        Ok(())
+    }
 }
 
 /// De-convert `data` from a run-length encoded byte array to a byte array.
-pub(super) fn decode(mut data: &[u8]) -> Result<Vec<u8>, Error> {
-    if data.is_empty() {
-        return Ok(Vec::new());
-    }
+#[cfg(test)]
+pub(super) fn decode(data: &[u8]) -> Result<Vec<u8>, Error> {
     let mut output = Vec::new();
 
-    while !data.is_empty() {
-        let run = get_run(data)?;
-        data = &data[run.len()..];
-        decode_run(run, &mut output)?;
-    }
+    decode_into(data, &mut output)?;
 
     Ok(output)
 }
 
-fn decode_run(data: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
-    debug_assert!(
-        [1, 2, 3, 5].contains(&data.len()),
-        "data is an invalid length: {}",
-        data.len()
-    );
+/// De-convert `data` from a run-length encoded byte array, appending the result to `output`.
+///
+/// Unlike [`decode_to`], this doesn't need `std`.
+pub(super) fn decode_into(data: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+    let mut decoder = Decoder::default();
+    decoder.push(data, output);
+
+    decoder.finish()
+}
+
+/// Decodes run-length encoded data that arrives in pieces, which may split a run anywhere.
+#[derive(Debug, Default)]
+pub(super) struct Decoder {
+    /// The byte that the current run repeats, and how many times it has so far. After four, the
+    /// next byte is the count of extra repeats.
+    run: Option<(u8, u8)>,
+    /// How many bytes have been decoded so far.
+    output_len: usize,
+}
 
-    if data.len() < 4 {
-        output.extend_from_slice(data);
-    } else if data[4] < 252 {
-        for _ in 0..data[data.len() - 1] + 4 {
-            output.push(data[0]);
+impl Decoder {
+    /// Decode `chunk`, appending the result to `output`.
+    ///
+    /// The four bytes at the start of a run are decoded straight away, so a run that is cut off
+    /// only has its count left over for the next chunk.
+    pub(super) fn push(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+        let start = output.len();
+
+        for &byte in chunk {
+            self.run = match self.run {
+                Some((run_byte, 4)) => {
+                    output.resize(output.len() + usize::from(byte), run_byte);
+                    None
+                }
+                Some((run_byte, repeats)) if run_byte == byte => {
+                    output.push(byte);
+                    Some((byte, repeats + 1))
+                }
+                _ => {
+                    output.push(byte);
+                    Some((byte, 1))
+                }
+            };
         }
-    } else {
-        return Err(Error::RunLengthInvalid(data[4]));
+
+        self.output_len += output.len() - start;
+    }
+
+    /// Check that the data didn't end part of the way through a run.
+    pub(super) fn finish(self) -> Result<(), Error> {
+        match self.run {
+            Some((_, 4)) => Err(Error::RunLengthTruncated {
+                offset: self.output_len - 4,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// De-convert run-length encoded bytes read from `input`, writing the result to `output`.
+///
+/// Returns the total number of bytes written.
+#[cfg(feature = "std")]
+pub(super) fn decode_to<R, W>(mut input: R, mut output: W) -> Result<usize, Error>
+where
+    R: Read,
+    W: Write,
+{
+    let mut decoder = Decoder::default();
+    let mut buffer = [0; 4096];
+    let mut decoded = Vec::new();
+    let mut output_len = 0;
+
+    loop {
+        let bytes_read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
+        };
+
+        decoded.clear();
+        decoder.push(&buffer[..bytes_read], &mut decoded);
+        output.write_all(&decoded)?;
+        output_len += decoded.len();
     }
+    decoder.finish()?;
 
-    Ok(())
+    Ok(output_len)
 }
 
-fn encode_run(data: &[u8], output: &mut Vec<u8>) {
+#[cfg(feature = "std")]
+fn encode_run<W>(data: &[u8], output: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
     debug_assert!(
         data.iter().skip(1).all(|n| *n == data[0]),
         "Items in data should all be the same"
@@ -76,27 +322,10 @@ fn encode_run(data: &[u8], output: &mut Vec<u8>) {
     debug_assert!(data.len() <= 255, "Data cannot be longer than 255 bytes.");
 
     if data.len() <= 3 {
-        output.extend_from_slice(data);
-    } else {
-        output.extend_from_slice(&data[..4]);
-        output.push((data.len() - 4) as u8);
-    }
-}
-
-fn get_run(data: &[u8]) -> Result<&[u8], Error> {
-    let length = std::cmp::min(data.len() - 1, 3);
-
-    for (i, byte) in data[..=length].iter().enumerate().skip(1) {
-        if *byte != data[0] && i != 4 {
-            return Ok(&data[..i]);
-        }
-    }
-
-    if data.len() == 4 {
-        Err(Error::RunLengthTruncated)
+        output.write_all(data)
     } else {
-        let length = std::cmp::min(data.len(), 5);
-        Ok(&data[..length])
+        output.write_all(&data[..4])?;
+        output.write_all(&[(data.len() - 4) as u8])
     }
 }
 
@@ -104,6 +333,20 @@ fn get_run(data: &[u8]) -> Result<&[u8], Error> {
 mod tests {
     use super::*;
 
+    /// A reader that hands out a single byte per read, so that runs straddle reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
     // Test the encode function.
     mod encode {
         use super::*;
@@ -128,6 +371,16 @@ mod tests {
             assert_eq!(encoded, data);
         }
 
+        /// Test with every possible byte, none of which repeat.
+        #[test]
+        fn all_distinct() {
+            let data: Vec<u8> = (0..=255).collect();
+
+            let encoded = encode(&data);
+
+            assert_eq!(encoded, data);
+        }
+
         /// Test with runs longer than 255.
         #[test]
         fn big_bytes_four_too_many() {
@@ -149,6 +402,183 @@ mod tests {
             let expected = b"eeee\xfbe";
             assert_eq!(encoded, expected);
         }
+
+        /// A run of 252 fits in one count byte, which is 248.
+        #[test]
+        fn run_of_252() {
+            let data = [b'e'; 252];
+
+            let encoded = encode(&data);
+
+            assert_eq!(encoded, b"eeee\xf8");
+        }
+
+        /// Test a full run of 255 that is followed by a different byte.
+        #[test]
+        fn full_run_then_change() {
+            let mut data = vec![b'a'; 255];
+            data.push(b'b');
+
+            let encoded = encode(&data);
+
+            let expected = b"aaaa\xfbb";
+            assert_eq!(encoded, expected);
+            assert_eq!(decode(&encoded).unwrap(), data);
+        }
+    }
+
+    /// Test the encode_to() function.
+    mod encode_to {
+        use super::*;
+
+        /// The writer should receive exactly what the slice-based function returns.
+        #[test]
+        fn matches_encode() {
+            let long: Vec<u8> = (0..20_000_u32).map(|i| (i / 5) as u8).collect();
+            let inputs: [&[u8]; 5] = [b"", b"abbccc", b"aeeeeebb", &[b'e'; 600], &long];
+
+            for data in inputs {
+                let mut output = Vec::new();
+
+                encode_to(data, &mut output).unwrap();
+
+                assert_eq!(output, encode(data));
+            }
+        }
+    }
+
+    mod encode_iter {
+        use super::*;
+
+        /// The iterator should yield exactly what the slice-based function returns.
+        #[test]
+        fn matches_encode() {
+            let mut mixed = b"abbcccddddeeeee".to_vec();
+            mixed.extend([b'f'; 255]);
+            mixed.extend([b'f'; 256]);
+            mixed.extend((0..=255).cycle().take(1000));
+            let inputs: [&[u8]; 6] = [b"", b"a", b"aaaa", b"aeeeeebb", &[b'e'; 600], &mixed];
+
+            for data in inputs {
+                let encoded: Vec<u8> = encode_iter(data.iter().copied()).collect();
+
+                assert_eq!(encoded, encode(data));
+                assert_eq!(decode(&encoded).unwrap(), data);
+            }
+        }
+
+        /// Nothing should be read from the input until it is needed.
+        #[test]
+        fn lazy() {
+            let mut read = 0;
+            let input = b"abcccccd".iter().copied().inspect(|_| read += 1);
+
+            let first_two: Vec<u8> = encode_iter(input).take(2).collect();
+
+            assert_eq!(first_two, b"ab");
+            assert_eq!(read, 3);
+        }
+    }
+
+    /// Test the BlockEncoder.
+    mod block_encoder {
+        use std::convert::Infallible;
+
+        use super::*;
+
+        /// Encode `pieces` one after another, returning each block and its CRC.
+        fn encode_blocks(pieces: &[&[u8]], max_len: usize) -> Vec<(Vec<u8>, u32)> {
+            let mut blocks = vec![];
+            let mut encoder = BlockEncoder::new(max_len);
+
+            for piece in pieces {
+                encoder
+                    .write(piece, |block, crc| {
+                        blocks.push((block, crc));
+                        Ok::<_, Infallible>(())
+                    })
+                    .unwrap();
+            }
+            encoder
+                .finish(|block, crc| {
+                    blocks.push((block, crc));
+                    Ok::<_, Infallible>(())
+                })
+                .unwrap();
+
+            blocks
+        }
+
+        /// With room for everything, there should be one block, the same as encode() gives.
+        #[test]
+        fn matches_encode() {
+            let inputs: [&[u8]; 3] = [b"abbccc", b"aeeeeebb", &[b'e'; 600]];
+
+            for data in inputs {
+                assert_eq!(
+                    encode_blocks(&[data], usize::MAX),
+                    [(encode(data), crate::crc::checksum(data))]
+                );
+            }
+        }
+
+        /// No data means no blocks.
+        #[test]
+        fn empty() {
+            assert!(encode_blocks(&[], 100).is_empty());
+            assert!(encode_blocks(&[b""], 100).is_empty());
+        }
+
+        /// A run that ends before the block is full should go in it, even past `max_len`.
+        #[test]
+        fn run_finishes_block() {
+            // "abc" is three bytes, so the run of d still goes in, and then the block is full.
+            let blocks = encode_blocks(&[b"abcdddddde"], 7);
+
+            let blocks: Vec<Vec<u8>> = blocks.into_iter().map(|(block, _crc)| block).collect();
+            assert_eq!(blocks, [&b"abcdddd\x02"[..], b"e"]);
+        }
+
+        /// A run that ends once the block is full should start the next block.
+        #[test]
+        fn run_starts_next_block() {
+            let blocks = encode_blocks(&[b"abcdefghhhhhi"], 7);
+
+            let blocks: Vec<Vec<u8>> = blocks.into_iter().map(|(block, _crc)| block).collect();
+            assert_eq!(blocks, [&b"abcdefg"[..], b"hhhh\x01i"]);
+        }
+
+        /// A run that spans two writes should be encoded as one run.
+        #[test]
+        fn run_across_writes() {
+            let blocks = encode_blocks(&[b"xaa", b"aaaa", b"ay"], 100);
+
+            assert_eq!(
+                blocks,
+                [(b"xaaaa\x03y".to_vec(), crate::crc::checksum(b"xaaaaaaay"))]
+            );
+        }
+
+        /// The blocks should decode back to all of the data, and each CRC should cover its block.
+        #[test]
+        fn pieces() {
+            let data: Vec<u8> = (0..2_000_u32)
+                .flat_map(|i| std::iter::repeat_n(i as u8, (i % 7) as usize))
+                .collect();
+            let pieces: Vec<&[u8]> = data.chunks(33).collect();
+
+            let blocks = encode_blocks(&pieces, 100);
+
+            let mut decoded = vec![];
+            for (block, crc) in blocks {
+                // The last run can take a block up to 4 bytes past `max_len`.
+                assert!(block.len() <= 104);
+                let decoded_block = decode(&block).unwrap();
+                assert_eq!(crate::crc::checksum(&decoded_block), crc);
+                decoded.extend(decoded_block);
+            }
+            assert_eq!(decoded, data);
+        }
     }
 
     /// Test the encode_run() function.
@@ -160,7 +590,7 @@ mod tests {
             let data = [b'e'; 255];
             let mut output = Vec::new();
 
-            encode_run(&data, &mut output);
+            encode_run(&data, &mut output).unwrap();
 
             let expected = b"eeee\xfb";
             assert_eq!(output, expected);
@@ -171,7 +601,7 @@ mod tests {
             let data = b"ddddd";
             let mut output = Vec::new();
 
-            encode_run(data, &mut output);
+            encode_run(data, &mut output).unwrap();
 
             let expected = b"dddd\x01";
             assert_eq!(output, expected);
@@ -182,7 +612,7 @@ mod tests {
             let data = b"dddd";
             let mut output = Vec::new();
 
-            encode_run(data, &mut output);
+            encode_run(data, &mut output).unwrap();
 
             let expected = b"dddd\0";
             assert_eq!(output, expected);
@@ -193,7 +623,7 @@ mod tests {
             let data = b"ccc";
             let mut output = Vec::new();
 
-            encode_run(data, &mut output);
+            encode_run(data, &mut output).unwrap();
 
             assert_eq!(output, data);
         }
@@ -203,12 +633,66 @@ mod tests {
             let data = b"bb";
             let mut output = Vec::new();
 
-            encode_run(data, &mut output);
+            encode_run(data, &mut output).unwrap();
 
             assert_eq!(output, data);
         }
     }
 
+    /// Test the decode_to() function.
+    mod decode_to {
+        use super::*;
+
+        /// The writer should receive exactly what the slice-based function returns.
+        #[test]
+        fn matches_decode() {
+            let data = b"aeeee\x01bbdddd\xfbdddd\x00c";
+            let mut output = Vec::new();
+
+            decode_to(Trickle(data), &mut output).expect("data should decode");
+
+            assert_eq!(output, decode(data).unwrap());
+        }
+
+        /// Input that is larger than the internal buffer should decode.
+        #[test]
+        fn large() {
+            let data: Vec<u8> = (0..10_000).map(|i| (i / 7) as u8).collect();
+            let encoded = encode(&data);
+            let mut output = Vec::new();
+
+            decode_to(&encoded[..], &mut output).expect("data should decode");
+
+            assert_eq!(output, data);
+        }
+
+        /// A truncated run is only detected at the end of the input.
+        #[test]
+        fn run_length_truncated() {
+            let mut output = Vec::new();
+
+            let result = decode_to(Trickle(b"abbcccc"), &mut output);
+
+            assert!(matches!(
+                result,
+                Err(Error::RunLengthTruncated { offset: 3 })
+            ));
+        }
+
+        /// The returned length should be the number of bytes written.
+        #[test]
+        fn run_heavy_len() {
+            let data = b"aaaa\xfbbcccc\x00dd\x00\x00\x00\x00\x10eeee\x01";
+            let mut output = Vec::new();
+
+            let len = decode_to(Trickle(data), &mut output).expect("data should decode");
+
+            assert_eq!(len, 287);
+            assert_eq!(len, output.len());
+            assert_eq!(output, decode(data).unwrap());
+        }
+    }
+
     /// Test the decode function
     mod decode {
         use super::*;
@@ -235,6 +719,16 @@ mod tests {
             assert_eq!(encoded, expected);
         }
 
+        /// Test with every possible byte, none of which repeat.
+        #[test]
+        fn all_distinct() {
+            let data: Vec<u8> = (0..=255).rev().collect();
+
+            let decoded = decode(&data).expect("data should decode");
+
+            assert_eq!(decoded, data);
+        }
+
         /// Test with an empty slice.
         #[test]
         fn empty() {
@@ -254,27 +748,37 @@ mod tests {
             match decode(data) {
                 Ok(_) => panic!("This should have resulted in an error"),
                 Err(err) => match err {
-                    Error::RunLengthInvalid(_) => panic!("The wrong error was returned"),
-                    Error::RunLengthTruncated => {}
+                    // "a" and "bb" decode before the truncated run of "c"s.
+                    Error::RunLengthTruncated { offset } => assert_eq!(offset, 3),
+                    Error::Io(_) => panic!("The wrong error was returned"),
                 },
             }
         }
 
-        /// Test with a run length that is too large
+        /// Every count byte should decode to that many extra bytes, including the ones our
+        /// encoder never writes.
         #[test]
-        fn run_length_invalid() {
-            // 255 is too large for a run length
-            let data = [255, 255, 255, 255, 255];
+        fn every_count() {
+            for count in 0..=255 {
+                let data = [b'a', b'a', b'a', b'a', count];
 
-            match decode(&data[..]) {
-                Ok(_) => panic!("This should have resulted in an error"),
-                Err(err) => match err {
-                    Error::RunLengthInvalid(length) => assert_eq!(length, 255),
-                    Error::RunLengthTruncated => panic!("The wrong error was returned"),
-                },
+                let decoded = decode(&data).expect("data should decode");
+
+                assert_eq!(decoded.len(), count as usize + 4);
+                assert!(decoded.iter().all(|byte| *byte == b'a'));
             }
         }
 
+        /// The largest count would overflow a `u8` once the four repeats are added to it.
+        #[test]
+        fn largest_count() {
+            let mut decoded = vec![];
+
+            decode_to(&b"eeee\xff"[..], &mut decoded).expect("data should decode");
+
+            assert_eq!(decoded, [b'e'; 259]);
+        }
+
         /// Test with no repeats 4 or longer.
         #[test]
         fn no_four() {
@@ -307,6 +811,53 @@ mod tests {
             assert_eq!(encoded, expected);
         }
 
+        /// Test with two runs back to back.
+        #[test]
+        fn consecutive_runs() {
+            let data = b"aaaa\x00bbbb\x00";
+
+            let decoded = decode(data).expect("data should decode");
+
+            let expected = b"aaaabbbb";
+            assert_eq!(decoded, expected);
+        }
+
+        /// Test with several back to back runs of varying lengths.
+        #[test]
+        fn many_consecutive_runs() {
+            let data = b"aaaa\x00bbbb\x05cccc\xfbcccc\x01dddd\x02";
+
+            let decoded = decode(data).expect("data should decode");
+
+            let mut expected = vec![b'a'; 4];
+            expected.extend([b'b'; 9]);
+            expected.extend([b'c'; 260]);
+            expected.extend([b'd'; 6]);
+            assert_eq!(decoded, expected);
+        }
+
+        /// A count byte can be followed by more of the same byte, which starts a new run.
+        #[test]
+        fn same_byte_after_count() {
+            let data = b"aaaa\x02aaa";
+
+            let decoded = decode(data).expect("data should decode");
+
+            assert_eq!(decoded, [b'a'; 9]);
+        }
+
+        /// A count byte that happens to equal the next run's byte is still a count.
+        #[test]
+        fn count_equals_next_byte() {
+            let data = b"aaaa\x62bbbb\x61";
+
+            let decoded = decode(data).expect("data should decode");
+
+            let mut expected = vec![b'a'; 0x62 + 4];
+            expected.extend([b'b'; 0x61 + 4]);
+            assert_eq!(decoded, expected);
+        }
+
         /// Test with a small run
         #[test]
         fn small_run_middle() {
@@ -318,4 +869,62 @@ mod tests {
             assert_eq!(encoded, expected);
         }
     }
+
+    /// Test [`Decoder`].
+    mod decoder {
+        use super::*;
+
+        /// Decode `data` in pieces of `chunk_size` bytes.
+        fn decode_chunks(data: &[u8], chunk_size: usize) -> Result<Vec<u8>, Error> {
+            let mut decoder = Decoder::default();
+            let mut output = vec![];
+
+            for chunk in data.chunks(chunk_size) {
+                decoder.push(chunk, &mut output);
+            }
+            decoder.finish()?;
+
+            Ok(output)
+        }
+
+        /// Splitting a run between every byte should not change what it decodes to.
+        #[test]
+        fn one_byte_at_a_time() {
+            let data = b"eeee\x05";
+
+            let decoded = decode_chunks(data, 1).unwrap();
+
+            assert_eq!(decoded, decode(data).unwrap());
+        }
+
+        /// Any size of piece should give the same output as decoding all at once.
+        #[test]
+        fn every_chunk_size() {
+            let data = b"aaaa\x00bbbb\x05cccc\xfbcccc\x01dddd\x02aaaa\x62bbbb\x61xyzzy";
+
+            for chunk_size in 1..=data.len() {
+                assert_eq!(
+                    decode_chunks(data, chunk_size).unwrap(),
+                    decode(data).unwrap(),
+                    "chunks of {chunk_size}"
+                );
+            }
+        }
+
+        /// A missing count should only be an error at the end, after any number of pieces.
+        #[test]
+        fn truncated() {
+            let mut decoder = Decoder::default();
+            let mut output = vec![];
+
+            decoder.push(b"abbc", &mut output);
+            decoder.push(b"ccc", &mut output);
+
+            assert_eq!(output, b"abbcccc");
+            assert!(matches!(
+                decoder.finish(),
+                Err(Error::RunLengthTruncated { offset: 3 })
+            ));
+        }
+    }
 }
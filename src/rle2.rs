@@ -4,6 +4,38 @@
 //! zeros. This tranform efficiently encodes runs of zeros by transforming them into sequences of
 //! [`Symbol`]s.
 
+use alloc::vec::Vec;
+
+use thiserror::Error as ThisError;
+
+#[derive(Debug, PartialEq, ThisError)]
+pub(crate) enum Error {
+    #[error("A run of zeros had more symbols than any block allows")]
+    RunTooLong,
+    #[error("A zero was written as a byte rather than as a run")]
+    ZeroByte,
+    #[error("The block decoded to more bytes than the stream's block size allows")]
+    BlockTooLong,
+}
+
+/// An [`Error`], along with how far into the block it happened.
+#[derive(Debug, PartialEq, ThisError)]
+#[error("{error} after {offset} bytes of output")]
+pub(crate) struct DecodeError {
+    pub(crate) error: Error,
+    /// How many bytes had been decoded before the run that was bad.
+    pub(crate) offset: usize,
+}
+
+/// The most `RunA` and `RunB` symbols a single run may have.
+///
+/// A block is at most 900 kB, so a run from bzip2 never needs more than 20 symbols, since
+/// 2^20 > 900,000. We allow a slightly looser bound of 24, which is only there to keep a corrupt
+/// run from overflowing the shift in [`decode_run`]: it still allows a run of over 33 million
+/// zeros. The real limit on how much a block decodes to is the `max_len` that [`decode_into`] is
+/// given, which is the block size from the stream header.
+const MAX_RUN_SYMBOLS: u32 = 24;
+
 /// The output of this transformation.
 ///
 /// `RunA` and `RunB` are used to encode runs of zeros in similar fashion to binary numbers. To
@@ -13,7 +45,7 @@
 ///
 /// Bytes that are not zero are not transformed.
 #[derive(Debug, PartialEq)]
-pub(super) enum Symbol {
+pub enum Symbol {
     /// Represents 1 times its position in the sequence.
     RunA,
     /// Represents 2 times its position in the sequence.
@@ -23,77 +55,204 @@ pub(super) enum Symbol {
     Byte(u8),
 }
 
+impl Symbol {
+    /// The position of this symbol in a Huffman alphabet of `alphabet_size` symbols.
+    ///
+    /// The alphabet is numbered `RunA` = 0, `RunB` = 1, and then `Byte(b)` = `b + 1` for each
+    /// move-to-front index `b`, which starts at 1 since zeros are always runs. The last position,
+    /// `alphabet_size - 1`, is the end of block symbol, which has no `Symbol` of its own. The
+    /// Huffman stage numbers its symbols through this and [`Symbol::from_index`].
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if this is a `Byte` that doesn't fit before the end of block symbol.
+    pub(crate) fn to_index(&self, alphabet_size: usize) -> u16 {
+        match self {
+            Symbol::RunA => 0,
+            Symbol::RunB => 1,
+            Symbol::Byte(byte) => {
+                let index = u16::from(*byte) + 1;
+                debug_assert!(
+                    *byte != 0 && usize::from(index) < alphabet_size - 1,
+                    "Byte({byte}) does not fit before Eob in an alphabet of {alphabet_size}"
+                );
+                index
+            }
+        }
+    }
+
+    /// Undo [`Symbol::to_index`], or return `None` for the end of block symbol.
+    pub(crate) fn from_index(index: u16, alphabet_size: usize) -> Option<Self> {
+        debug_assert!(
+            usize::from(index) < alphabet_size,
+            "{index} is not in an alphabet of {alphabet_size}"
+        );
+
+        match index {
+            0 => Some(Symbol::RunA),
+            1 => Some(Symbol::RunB),
+            index if usize::from(index) == alphabet_size - 1 => None,
+            index => Some(Symbol::Byte((index - 1) as u8)),
+        }
+    }
+}
+
 /// Encode the bytes into `Symbol`s.
-pub(super) fn encode(mut data: &[u8]) -> Vec<Symbol> {
+pub(super) fn encode(data: &[u8]) -> Vec<Symbol> {
     let mut output = Vec::new();
+    encode_into(data, &mut output);
+    output
+}
+
+/// Encode the bytes into `Symbol`s, appending them to `output`.
+pub(super) fn encode_into(mut data: &[u8], output: &mut Vec<Symbol>) {
     while !data.is_empty() {
-        let (mut symbols, rest) = get_symbols(data);
-        output.append(&mut symbols);
-        data = rest;
+        data = get_symbols(data, output);
     }
-    output
 }
 
 /// Decode the `Symbol`s back to bytes.
-pub(super) fn decode(mut data: &[Symbol]) -> Vec<u8> {
+#[cfg(test)]
+pub(super) fn decode(data: &[Symbol]) -> Result<Vec<u8>, DecodeError> {
     let mut output = Vec::new();
+    decode_into(data, &mut output, usize::MAX)?;
+    Ok(output)
+}
+
+/// Decode the `Symbol`s back to bytes, appending them to `output`.
+///
+/// At most `max_len` bytes are appended. This stage's output is a whole block, so when decoding a
+/// stream, `max_len` is the block size from its header. It is checked before each run is pushed,
+/// so a corrupt block can't grow `output` past that, however long its runs claim to be.
+///
+/// # Errors
+///
+/// If a run is too long to decode, or the output would be longer than `max_len`.
+pub(super) fn decode_into(
+    data: &[Symbol],
+    output: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<(), DecodeError> {
+    decode_into_checked(data, output, max_len, false)
+}
+
+/// Like [`decode_into`], but also check that `data` is exactly what [`encode`] would give for its
+/// output.
+///
+/// Every sequence of `RunA` and `RunB` is the only way to write its number of zeros, so the only
+/// thing that can be out of place is a zero written as `Byte(0)`, which is an
+/// [`Error::ZeroByte`] here.
+pub(super) fn decode_strict_into(
+    data: &[Symbol],
+    output: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<(), DecodeError> {
+    decode_into_checked(data, output, max_len, true)
+}
+
+fn decode_into_checked(
+    mut data: &[Symbol],
+    output: &mut Vec<u8>,
+    max_len: usize,
+    strict: bool,
+) -> Result<(), DecodeError> {
+    let start = output.len();
+    let limit = start.saturating_add(max_len);
     while !data.is_empty() {
-        let (mut bytes, rest) = get_bytes(data);
-        output.append(&mut bytes);
-        data = rest;
+        let offset = output.len() - start;
+        data = get_bytes(data, output, limit, strict)
+            .map_err(|error| DecodeError { error, offset })?;
     }
-    output
+    Ok(())
 }
 
-/// Breaks the input into a leading `Symbol`s and the remaining bytes.
+/// Encodes the leading bytes of the input onto `output` as `Symbol`s and returns the remaining
+/// bytes.
 ///
 /// Example:
-/// [0, 0, 0, 1] -> ([A, A], [1])
-/// [1, 0, 0, 0] -> ([1], [0, 0, 0])
-fn get_symbols(input: &[u8]) -> (Vec<Symbol>, &[u8]) {
+/// [0, 0, 0, 1] -> pushes [A, A], returns [1]
+/// [1, 0, 0, 0] -> pushes [1], returns [0, 0, 0]
+fn get_symbols<'a>(input: &'a [u8], output: &mut Vec<Symbol>) -> &'a [u8] {
     assert!(!input.is_empty());
     if input[0] != 0 {
-        (vec![Symbol::Byte(input[0])], &input[1..])
+        output.push(Symbol::Byte(input[0]));
+        &input[1..]
     } else {
         let first_non_zero = input.iter().enumerate().find(|&(_, &byte)| byte != 0);
         match first_non_zero {
             // Encode how many zeros are in the current run and return the remaining bytes.
-            Some((length, _)) => (encode_run(length), &input[length..]),
+            Some((length, _)) => {
+                encode_run(length, output);
+                &input[length..]
+            }
             // After this function returns, there is nothing else to encode.
-            None => (encode_run(input.len()), &[]),
+            None => {
+                encode_run(input.len(), output);
+                &[]
+            }
         }
     }
 }
 
-/// Breaks the input into leading bytes and remaining unprocessed `Symbol`s.
+/// Decodes the leading bytes of the input onto `output` and returns the remaining unprocessed
+/// `Symbol`s.
 ///
 /// Example:
-/// [A, A, 1] -> ([0, 0, 0], [1])
-/// [1, A, A] -> ([1], [A, A])
-fn get_bytes(input: &[Symbol]) -> (Vec<u8>, &[Symbol]) {
-    assert!(!input.is_empty());
-    if let Symbol::Byte(byte) = input[0] {
-        (vec![byte], &input[1..])
+/// [A, A, 1] -> pushes [0, 0, 0], returns [1]
+/// [1, A, A] -> pushes [1], returns [A, A]
+///
+/// `output` may not grow past `limit` bytes. If `strict`, a `Byte(0)` is an error.
+fn get_bytes<'a>(
+    input: &'a [Symbol],
+    output: &mut Vec<u8>,
+    limit: usize,
+    strict: bool,
+) -> Result<&'a [Symbol], Error> {
+    let Some(first) = input.first() else {
+        return Ok(input);
+    };
+    if let Symbol::Byte(byte) = first {
+        if strict && *byte == 0 {
+            return Err(Error::ZeroByte);
+        }
+        if output.len() >= limit {
+            return Err(Error::BlockTooLong);
+        }
+        output.push(*byte);
+        Ok(&input[1..])
     } else {
         let first_byte = input
             .iter()
             .enumerate()
             .find(|(_, symbol)| matches!(symbol, Symbol::Byte(_)));
         match first_byte {
-            // Return the number of zeros that were encoded and the remaining `Symbol`s.
-            Some((length, _)) => (decode_run(&input[..length]), &input[length..]),
+            // Push the number of zeros that were encoded and return the remaining `Symbol`s.
+            Some((length, _)) => {
+                decode_run(&input[..length], output, limit)?;
+                Ok(&input[length..])
+            }
             // After this function returns, there's nothing else to decode.
-            None => (decode_run(input), &[]),
+            None => {
+                decode_run(input, output, limit)?;
+                Ok(&[])
+            }
         }
     }
 }
 
-/// Represents an integer with a sequence of `Symbol::RunA` and `Symbol::RunB`.
-fn encode_run(length: usize) -> Vec<Symbol> {
+/// Represents an integer with a sequence of `Symbol::RunA` and `Symbol::RunB`, pushed onto
+/// `output`.
+///
+/// We assert that the run fits in [`MAX_RUN_SYMBOLS`] so that we notice if our blocks ever get
+/// bigger than bzip2 allows.
+fn encode_run(length: usize, output: &mut Vec<Symbol>) {
     assert!(length != 0);
-    let mut output = Vec::new();
     let repr = length + 1;
     let num_symbols = repr.ilog2();
+    debug_assert!(
+        num_symbols <= MAX_RUN_SYMBOLS,
+        "A run of {length} zeros is longer than any block should allow"
+    );
     let mut repr = (1 << num_symbols) ^ repr;
     for _ in 0..num_symbols {
         let rmb = 1 & repr;
@@ -104,12 +263,20 @@ fn encode_run(length: usize) -> Vec<Symbol> {
         }
         repr >>= 1;
     }
-    output
 }
 
-/// Encodes a sequence of `Symbol::RunA` and `Symbol::RunB` as a run of zeros.
-fn decode_run(run: &[Symbol]) -> Vec<u8> {
-    assert!(!run.is_empty());
+/// Decodes a sequence of `Symbol::RunA` and `Symbol::RunB` as a run of zeros onto `output`.
+///
+/// [`get_bytes`] splits the runs off at the first `Byte`, so `run` never has one in it.
+///
+/// # Errors
+///
+/// If the run has more than [`MAX_RUN_SYMBOLS`] symbols, or if it would take `output` past `limit`
+/// bytes.
+fn decode_run(run: &[Symbol], output: &mut Vec<u8>, limit: usize) -> Result<(), Error> {
+    if run.len() > MAX_RUN_SYMBOLS as usize {
+        return Err(Error::RunTooLong);
+    }
     let mut repr = 0;
     for symbol in run.iter().rev() {
         match symbol {
@@ -117,11 +284,15 @@ fn decode_run(run: &[Symbol]) -> Vec<u8> {
             Symbol::RunA => repr <<= 1,
             // Put a 1 in the LSB to represent B.
             Symbol::RunB => repr = (repr << 1) | 1,
-            _ => unreachable!(),
+            Symbol::Byte(_) => unreachable!("get_bytes ends each run at the first Byte"),
         }
     }
     let zero_count = ((1 << run.len()) | repr) - 1;
-    vec![0; zero_count]
+    if zero_count > limit.saturating_sub(output.len()) {
+        return Err(Error::BlockTooLong);
+    }
+    output.resize(output.len() + zero_count, 0);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -140,11 +311,26 @@ mod tests {
         ];
 
         let encoded = encode(&data);
-        let decoded = decode(&encoded);
+        let decoded = decode(&encoded).unwrap();
 
         assert_eq!(data, &decoded[..]);
     }
 
+    /// Test with long runs of zeros, which is most of what this stage sees.
+    #[test]
+    fn roundtrip_zero_heavy() {
+        let mut data = vec![0; 1000];
+        data.extend([1, 0, 2, 0, 0, 3]);
+        data.extend([0; 12345]);
+        data.push(4);
+        data.extend([0; 7]);
+
+        let encoded = encode(&data);
+        let decoded = decode(&encoded).unwrap();
+
+        assert_eq!(data, decoded);
+    }
+
     mod encode {
         use super::*;
 
@@ -158,6 +344,34 @@ mod tests {
             assert_eq!(encoded, expected);
         }
 
+        /// The longest possible run is a whole block of zeros, and it should need few symbols.
+        #[test]
+        fn full_block_of_zeros() {
+            let data = vec![0; 900_000];
+
+            let encoded = encode(&data);
+
+            assert_eq!(encoded.len(), 19);
+            assert!(encoded
+                .iter()
+                .all(|symbol| matches!(symbol, Symbol::RunA | Symbol::RunB)));
+            assert_eq!(decode(&encoded).unwrap(), data);
+        }
+
+        /// Every length 1 run of zeros between bytes should be a single `RunA`.
+        #[test]
+        fn alternating() {
+            let data: Vec<u8> = (1..=250).flat_map(|byte| [0, byte]).collect();
+
+            let encoded = encode(&data);
+
+            let expected: Vec<Symbol> = (1..=250)
+                .flat_map(|byte| [Symbol::RunA, Symbol::Byte(byte)])
+                .collect();
+            assert_eq!(encoded, expected);
+            assert_eq!(decode(&encoded).unwrap(), data);
+        }
+
         #[test]
         fn simple() {
             let data = [0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 0, 0];
@@ -192,10 +406,217 @@ mod tests {
                 Symbol::RunA,
             ];
 
-            let decoded = decode(&data);
+            let decoded = decode(&data).unwrap();
 
             let expected = [0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 0, 0];
             assert_eq!(decoded, expected);
         }
+
+        /// Test a run of zeros that ends the data.
+        #[test]
+        fn trailing_run() {
+            let data = [Symbol::Byte(3), Symbol::RunB, Symbol::RunB, Symbol::RunA];
+
+            let decoded = decode(&data).unwrap();
+
+            let expected = [3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            assert_eq!(decoded, expected);
+        }
+
+        /// Test data that is nothing but a run of zeros.
+        #[test]
+        fn only_runs() {
+            let data = [Symbol::RunB, Symbol::RunA, Symbol::RunA, Symbol::RunA];
+
+            let decoded = decode(&data).unwrap();
+
+            assert_eq!(decoded, [0; 16]);
+        }
+    }
+
+    mod decode_strict_into {
+        use super::*;
+
+        /// What [`encode`] gives should always pass.
+        #[test]
+        fn canonical() {
+            let data = [0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 0, 0];
+            let mut output = vec![];
+
+            decode_strict_into(&encode(&data), &mut output, usize::MAX).unwrap();
+
+            assert_eq!(output, data);
+        }
+
+        /// A zero written as a byte between two runs is only an error in strict mode.
+        #[test]
+        fn zero_byte() {
+            let data = [Symbol::RunB, Symbol::Byte(0), Symbol::RunA, Symbol::Byte(3)];
+            let mut output = vec![];
+
+            assert_eq!(
+                decode_strict_into(&data, &mut output, usize::MAX),
+                Err(DecodeError {
+                    error: Error::ZeroByte,
+                    offset: 2
+                })
+            );
+            assert_eq!(decode(&data).unwrap(), [0, 0, 0, 0, 3]);
+        }
+    }
+
+    /// Test the `max_len` of [`decode_into`].
+    mod max_len {
+        use super::*;
+
+        /// Output of exactly `max_len` bytes is fine, whether it ends in a byte or a run.
+        #[test]
+        fn exact() {
+            for data in [&[0, 0, 0, 7][..], &[7, 0, 0, 0]] {
+                let mut output = vec![];
+
+                decode_into(&encode(data), &mut output, 4).unwrap();
+
+                assert_eq!(output, data);
+            }
+        }
+
+        /// A byte that would go past `max_len` is an error.
+        #[test]
+        fn byte_too_many() {
+            let mut output = vec![];
+
+            assert_eq!(
+                decode_into(&encode(&[0, 0, 0, 7]), &mut output, 3),
+                Err(DecodeError {
+                    error: Error::BlockTooLong,
+                    offset: 3,
+                })
+            );
+        }
+
+        /// A run that would go past `max_len` is an error, and none of it is pushed.
+        #[test]
+        fn run_too_long() {
+            let data = [Symbol::Byte(7), Symbol::RunB, Symbol::RunB];
+            let mut output = vec![];
+
+            assert_eq!(
+                decode_into(&data, &mut output, 6),
+                Err(DecodeError {
+                    error: Error::BlockTooLong,
+                    offset: 1,
+                })
+            );
+            assert_eq!(output, [7]);
+
+            let mut output = vec![];
+            decode_into(&data, &mut output, 7).unwrap();
+            assert_eq!(output, [7, 0, 0, 0, 0, 0, 0]);
+        }
+
+        /// `max_len` only counts what is appended, not what was already in `output`.
+        #[test]
+        fn appends() {
+            let mut output = vec![1, 2, 3];
+
+            decode_into(&encode(&[0, 0]), &mut output, 2).unwrap();
+
+            assert_eq!(output, [1, 2, 3, 0, 0]);
+        }
+    }
+
+    /// Test [`Symbol::to_index`] and [`Symbol::from_index`].
+    mod index {
+        use super::*;
+
+        /// Every symbol in the largest alphabet should map to its index and back.
+        #[test]
+        fn roundtrip() {
+            let symbols = [Symbol::RunA, Symbol::RunB]
+                .into_iter()
+                .chain((1..=255).map(Symbol::Byte));
+
+            for (index, symbol) in (0..).zip(symbols) {
+                assert_eq!(symbol.to_index(258), index);
+                assert_eq!(Symbol::from_index(index, 258), Some(symbol));
+            }
+            assert_eq!(Symbol::from_index(257, 258), None);
+        }
+
+        /// The end of block symbol moves with the size of the alphabet.
+        #[test]
+        fn small_alphabet() {
+            assert_eq!(Symbol::Byte(2).to_index(5), 3);
+            assert_eq!(Symbol::from_index(3, 5), Some(Symbol::Byte(2)));
+            assert_eq!(Symbol::from_index(4, 5), None);
+        }
+
+        /// A `Byte` that would land on the end of block symbol means the alphabet is too small.
+        #[test]
+        #[should_panic]
+        fn byte_on_eob() {
+            let _index = Symbol::Byte(2).to_index(4);
+        }
+    }
+
+    mod decode_run {
+        use super::*;
+
+        /// The run should be appended to what is already in the output.
+        #[test]
+        fn appends() {
+            let mut output = vec![7, 8];
+
+            decode_run(&[Symbol::RunA, Symbol::RunB], &mut output, usize::MAX).unwrap();
+
+            assert_eq!(output, [7, 8, 0, 0, 0, 0, 0]);
+        }
+
+        /// The longest run we allow is one more symbol than a block can need.
+        #[test]
+        fn longest() {
+            let run: Vec<Symbol> = (0..MAX_RUN_SYMBOLS).map(|_| Symbol::RunA).collect();
+            let mut output = vec![];
+
+            decode_run(&run, &mut output, usize::MAX).unwrap();
+
+            assert_eq!(output.len(), (1 << MAX_RUN_SYMBOLS) - 1);
+        }
+
+        /// A run long enough to overflow the shift should be an error, not a panic.
+        #[test]
+        fn too_long() {
+            let run: Vec<Symbol> = (0..64).map(|_| Symbol::RunB).collect();
+            let mut output = vec![];
+
+            assert_eq!(
+                decode_run(&run, &mut output, usize::MAX),
+                Err(Error::RunTooLong)
+            );
+            assert!(output.is_empty());
+        }
+    }
+
+    /// Symbols from a corrupt block should give an error rather than a panic.
+    #[test]
+    fn decode_corrupt() {
+        let mut symbols = vec![Symbol::Byte(1), Symbol::RunA];
+        symbols.extend((0..40).map(|i| {
+            if i % 3 == 0 {
+                Symbol::RunA
+            } else {
+                Symbol::RunB
+            }
+        }));
+        symbols.push(Symbol::Byte(2));
+
+        assert_eq!(
+            decode(&symbols),
+            Err(DecodeError {
+                error: Error::RunTooLong,
+                offset: 1,
+            })
+        );
     }
 }
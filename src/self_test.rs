@@ -0,0 +1,140 @@
+//! Check that each stage of the pipeline produces known outputs for known inputs.
+//!
+//! This is useful for validating a build on a new platform or configuration, similar to the
+//! self-test that `bzip2` runs on startup.
+
+use std::io::Read;
+
+use crate::{burrows_wheeler, decompress, file_format::SymbolStack, move_to_front, rle1, rle2};
+
+/// `If Peter Piper picked a peck of pickled peppers`, compressed with `bzip2 -9`.
+const PETER_PIPER_BZ2: [u8; 74] = [
+    0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x5a, 0x0f, 0x6f, 0x58, 0x00, 0x00,
+    0x04, 0x17, 0x80, 0x40, 0x00, 0x00, 0x20, 0x40, 0x00, 0x2f, 0x2c, 0xdc, 0x00, 0x20, 0x00, 0x21,
+    0xa0, 0x93, 0x6a, 0x3f, 0x51, 0xe8, 0xa1, 0x4c, 0x00, 0x13, 0x4a, 0x54, 0x6a, 0x27, 0x57, 0xb6,
+    0x3c, 0x0e, 0x2f, 0xe5, 0x18, 0xe1, 0x2c, 0x91, 0x38, 0xd4, 0x58, 0xf9, 0x00, 0x80, 0x9f, 0xf1,
+    0x77, 0x24, 0x53, 0x85, 0x09, 0x05, 0xa0, 0xf6, 0xf5, 0x80,
+];
+
+/// These are the checks that [`self_test`] can report as failing.
+#[derive(Debug, thiserror::Error)]
+pub enum SelfTestError {
+    /// The first run-length stage did not produce the expected output.
+    #[error("The first run-length stage failed its self-test")]
+    RunLength1,
+    /// The Burrows-Wheeler stage did not produce the expected output.
+    #[error("The burrows-wheeler stage failed its self-test")]
+    BurrowsWheeler,
+    /// The move-to-front stage did not produce the expected output.
+    #[error("The move-to-front stage failed its self-test")]
+    MoveToFront,
+    /// The second run-length stage did not produce the expected output.
+    #[error("The second run-length stage failed its self-test")]
+    RunLength2,
+    /// Decompressing a known `bzip2` stream did not produce the expected output.
+    #[error("Decompressing a known stream failed its self-test")]
+    Decompress,
+}
+
+/// Run a fixed set of known vectors through each stage and the full decompression pipeline.
+///
+/// # Errors
+///
+/// Returns the first check that failed.
+pub fn self_test() -> Result<(), SelfTestError> {
+    rle1_check().ok_or(SelfTestError::RunLength1)?;
+    burrows_wheeler_check().ok_or(SelfTestError::BurrowsWheeler)?;
+    move_to_front_check().ok_or(SelfTestError::MoveToFront)?;
+    rle2_check().ok_or(SelfTestError::RunLength2)?;
+    decompress_check().ok_or(SelfTestError::Decompress)?;
+
+    Ok(())
+}
+
+/// Turn a failed comparison into `None`, so the checks can use `?` throughout.
+fn check(passed: bool) -> Option<()> {
+    passed.then_some(())
+}
+
+fn rle1_check() -> Option<()> {
+    let data = [b'e'; 259];
+    let expected = b"eeee\xfbeeee\0";
+
+    let encoded = rle1::encode(&data);
+    check(encoded == expected)?;
+
+    let mut decoded = vec![];
+    rle1::decode_to(&encoded[..], &mut decoded).ok()?;
+    check(decoded == data)
+}
+
+fn burrows_wheeler_check() -> Option<()> {
+    let data = b"cdab";
+
+    let encoded = burrows_wheeler::encode(data);
+    check(encoded.data == b"dabc")?;
+    check(usize::try_from(encoded.origin_pointer).ok()? == 2)?;
+
+    let mut decoded = vec![];
+    burrows_wheeler::decode_into(
+        &encoded.data,
+        encoded.origin_pointer,
+        &mut vec![],
+        &mut decoded,
+    )
+    .ok()?;
+    check(decoded == data)
+}
+
+fn move_to_front_check() -> Option<()> {
+    // This is basically "banana" if you map a-z to 0-25.
+    let data = [1, 0, 13, 0, 13, 0];
+
+    let encoded = move_to_front::encode(&data);
+    check(encoded == [1, 1, 13, 1, 1, 1])?;
+
+    let mut decoded = vec![];
+    move_to_front::decode_into(&encoded, SymbolStack((0..=255).collect()), &mut decoded).ok()?;
+    check(decoded == data)
+}
+
+fn rle2_check() -> Option<()> {
+    let data = [0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 0, 0];
+
+    let encoded = rle2::encode(&data);
+    check(
+        encoded
+            == [
+                rle2::Symbol::RunA,
+                rle2::Symbol::RunB,
+                rle2::Symbol::Byte(1),
+                rle2::Symbol::RunB,
+                rle2::Symbol::Byte(2),
+                rle2::Symbol::RunB,
+                rle2::Symbol::RunA,
+            ],
+    )?;
+
+    let mut decoded = vec![];
+    rle2::decode_strict_into(&encoded, &mut decoded, usize::MAX).ok()?;
+    check(decoded == data)
+}
+
+fn decompress_check() -> Option<()> {
+    let mut decoded = vec![];
+    decompress(&PETER_PIPER_BZ2[..])
+        .ok()?
+        .read_to_end(&mut decoded)
+        .ok()?;
+    check(decoded == b"If Peter Piper picked a peck of pickled peppers")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes() {
+        self_test().expect("The self-test should pass");
+    }
+}
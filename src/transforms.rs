@@ -0,0 +1,211 @@
+//! The individual stages of compression, for composing or benchmarking them on their own.
+//!
+//! [`compress`](crate::compress) runs these in order, one block at a time, and
+//! [`decompress`](crate::decompress) runs their inverses in the opposite order. The functions here
+//! work on whole slices and don't split anything into blocks, so they are not a replacement for
+//! either.
+//!
+//! # Example
+//!
+//! ```rust
+//! use beeziptoo::transforms::rle1;
+//!
+//! let data = b"If Peter Piper pickeeeeeeeed a peck";
+//! let encoded = rle1::encode(data);
+//!
+//! assert_eq!(encoded, b"If Peter Piper pickeeee\x04d a peck");
+//! assert_eq!(rle1::decode(&encoded).unwrap(), data);
+//! ```
+
+/// The first run-length encoding, which shortens runs of 4 to 255 identical bytes.
+pub mod rle1 {
+    use alloc::{vec, vec::Vec};
+
+    use crate::DecompressError;
+
+    /// Run-length encode `data`.
+    pub fn encode(data: &[u8]) -> Vec<u8> {
+        crate::rle1::encode(data)
+    }
+
+    /// Like [`encode`], but write the encoded bytes to `output` rather than returning them.
+    ///
+    /// # Errors
+    ///
+    /// If writing to `output` fails.
+    #[cfg(feature = "std")]
+    pub fn encode_to<W>(data: &[u8], output: W) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        crate::rle1::encode_to(data, output)
+    }
+
+    /// Like [`encode`], but only reads from `input` as the encoded bytes are needed.
+    pub fn encode_iter<I>(input: I) -> impl Iterator<Item = u8>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        crate::rle1::encode_iter(input)
+    }
+
+    /// Undo [`encode`].
+    ///
+    /// # Errors
+    ///
+    /// If `data` ends part of the way through a run.
+    pub fn decode(data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+        let mut output = vec![];
+
+        crate::rle1::decode_into(data, &mut output)?;
+
+        Ok(output)
+    }
+}
+
+/// The Burrows-Wheeler transform, which sorts every rotation of the data to bring similar
+/// contexts together.
+pub mod burrows_wheeler {
+    use alloc::{vec, vec::Vec};
+
+    use crate::DecompressError;
+
+    /// Transform `data`, returning the last byte of each sorted rotation and the origin pointer,
+    /// which is where the rotation that is `data` itself ended up.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is 900,000 bytes or longer, which is more than a block can hold.
+    pub fn encode(data: &[u8]) -> (Vec<u8>, u32) {
+        let encoded = crate::burrows_wheeler::encode(data);
+        let origin_pointer = usize::try_from(encoded.origin_pointer)
+            .expect("An origin pointer fits in a usize")
+            .try_into()
+            .expect("An origin pointer fits in 24 bits");
+
+        (encoded.data, origin_pointer)
+    }
+
+    /// Undo [`encode`], given its output and origin pointer.
+    ///
+    /// # Errors
+    ///
+    /// If `origin_pointer` is out of range, or `data` is not the output of the transform.
+    pub fn decode(data: &[u8], origin_pointer: u32) -> Result<Vec<u8>, DecompressError> {
+        let mut output = vec![];
+
+        crate::burrows_wheeler::decode_into(data, origin_pointer.into(), &mut vec![], &mut output)?;
+
+        Ok(output)
+    }
+}
+
+/// The move-to-front transform, which turns recently seen bytes into small numbers.
+///
+/// These use all 256 bytes as the alphabet, where [`compress`](crate::compress) only uses the
+/// bytes that are in each block.
+pub mod move_to_front {
+    use alloc::vec::Vec;
+
+    use crate::move_to_front::SymbolStack;
+
+    /// Transform `data`.
+    pub fn encode(data: &[u8]) -> Vec<u8> {
+        crate::move_to_front::encode(data)
+    }
+
+    /// Undo [`encode`].
+    pub fn decode(data: &[u8]) -> Vec<u8> {
+        crate::move_to_front::decode(data, SymbolStack((0..=255).collect()))
+            .expect("Every index is in an alphabet of all 256 bytes")
+    }
+}
+
+/// The second run-length encoding, which turns runs of zeros into [`Symbol`](rle2::Symbol)s.
+pub mod rle2 {
+    use alloc::{vec, vec::Vec};
+
+    pub use crate::rle2::Symbol;
+    use crate::DecompressError;
+
+    /// Run-length encode the zeros in `data`.
+    pub fn encode(data: &[u8]) -> Vec<Symbol> {
+        crate::rle2::encode(data)
+    }
+
+    /// Undo [`encode`].
+    ///
+    /// There is no limit on the length of the output, since these symbols aren't from a block with
+    /// a size in a stream header. A single run can have at most 24 symbols, which keeps it from
+    /// overflowing, but that still allows over 33 million zeros per run.
+    ///
+    /// # Errors
+    ///
+    /// If a run of zeros is malformed, or has more than 24 symbols.
+    pub fn decode(symbols: &[Symbol]) -> Result<Vec<u8>, DecompressError> {
+        let mut output = vec![];
+
+        crate::rle2::decode_into(symbols, &mut output, usize::MAX)?;
+
+        Ok(output)
+    }
+
+    /// Like [`decode`], but also check that `symbols` is exactly what [`encode`] would give.
+    ///
+    /// [`decode`] accepts a zero written as `Byte(0)`, which `bzip2` never does, in case other
+    /// encoders are less careful. This rejects it, which is useful for catching encoder bugs.
+    ///
+    /// # Errors
+    ///
+    /// If a run of zeros is malformed, or has more than 24 symbols, or a zero isn't part of a run.
+    pub fn decode_strict(symbols: &[Symbol]) -> Result<Vec<u8>, DecompressError> {
+        let mut output = vec![];
+
+        crate::rle2::decode_strict_into(symbols, &mut output, usize::MAX)?;
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Running every stage and then every inverse should give back the data.
+    #[test]
+    fn roundtrip() {
+        let data = b"If Peter Piper picked a peck of pickled peppers, where's the peck of pickled \
+            peppers Peter Piper picked?????";
+
+        let rle1_data = rle1::encode(data);
+        let (burrows_wheeler_data, origin_pointer) = burrows_wheeler::encode(&rle1_data);
+        let move_to_front_data = move_to_front::encode(&burrows_wheeler_data);
+        let symbols = rle2::encode(&move_to_front_data);
+
+        let move_to_front_data = rle2::decode(&symbols).unwrap();
+        let burrows_wheeler_data = move_to_front::decode(&move_to_front_data);
+        let rle1_data = burrows_wheeler::decode(&burrows_wheeler_data, origin_pointer).unwrap();
+        assert_eq!(rle1::decode(&rle1_data).unwrap(), data);
+    }
+
+    /// Bad input to the inverses should be an error.
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            rle1::decode(b"aaaa"),
+            Err(crate::DecompressError::RunLengthDecode { .. })
+        ));
+        assert!(matches!(
+            burrows_wheeler::decode(b"abc", 3),
+            Err(crate::DecompressError::BurrowsWheelerDecode(_))
+        ));
+        assert!(matches!(
+            rle2::decode(&[(); 30].map(|()| rle2::Symbol::RunA)),
+            Err(crate::DecompressError::RunLengthDecode { .. })
+        ));
+        assert!(matches!(
+            rle2::decode_strict(&[rle2::Symbol::Byte(1), rle2::Symbol::Byte(0)]),
+            Err(crate::DecompressError::RunLengthDecode { offset: 1 })
+        ));
+    }
+}
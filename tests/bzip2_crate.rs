@@ -0,0 +1,69 @@
+//! Check that beeziptoo and the `bzip2` crate can read each other's output.
+//!
+//! These are ignored by default, so that the usual test run doesn't depend on another bzip2
+//! implementation. Run them with `cargo test --test bzip2_crate -- --ignored`.
+use std::io::{Read, Write};
+
+use beeziptoo::{compress_to_vec, decompress_to_vec};
+use bzip2::{read::BzDecoder, write::BzEncoder, Compression};
+
+/// A few hundred KB of real text: the license and this crate's own source.
+fn text() -> Vec<u8> {
+    [
+        include_str!("../LICENSE"),
+        include_str!("../README.md"),
+        include_str!("../src/lib.rs"),
+        include_str!("../src/burrows_wheeler.rs"),
+        include_str!("../src/file_format.rs"),
+        include_str!("../src/file_format/bitstream.rs"),
+        include_str!("../src/huffman.rs"),
+        include_str!("../src/rle1.rs"),
+        include_str!("../src/rle2.rs"),
+    ]
+    .concat()
+    .into_bytes()
+}
+
+/// Inputs from a few bytes up to the whole of [`text`].
+fn inputs() -> Vec<Vec<u8>> {
+    let text = text();
+    assert!(text.len() > 250_000);
+
+    [0, 1, 47, 1_000, 50_000, text.len()]
+        .into_iter()
+        .map(|len| text[..len].to_vec())
+        .collect()
+}
+
+/// `bzip2` should decompress what beeziptoo compresses.
+#[test]
+#[ignore = "compares with the bzip2 crate; run with --ignored"]
+fn beeziptoo_to_bzip2() {
+    for input in inputs() {
+        let compressed = compress_to_vec(&input).expect("Could not compress data");
+        let mut decompressed = vec![];
+
+        BzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .expect("bzip2 could not decompress data");
+
+        assert_eq!(decompressed, input);
+    }
+}
+
+/// beeziptoo should decompress what `bzip2` compresses, at the fastest and best levels.
+#[test]
+#[ignore = "compares with the bzip2 crate; run with --ignored"]
+fn bzip2_to_beeziptoo() {
+    for input in inputs() {
+        for level in [Compression::fast(), Compression::best()] {
+            let mut encoder = BzEncoder::new(vec![], level);
+            encoder.write_all(&input).unwrap();
+            let compressed = encoder.finish().expect("bzip2 could not compress data");
+
+            let decompressed = decompress_to_vec(&compressed).expect("Could not decompress data");
+
+            assert_eq!(decompressed, input);
+        }
+    }
+}
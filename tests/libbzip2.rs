@@ -0,0 +1,127 @@
+//! Check that beeziptoo writes the same bytes as libbzip2.
+//!
+//! The files in `tests/libbzip2/` were written by `bzip2 -c -<level>` from bzip2 1.0.8, with the
+//! inputs from [`cases`]. We follow libbzip2's heuristics for cutting blocks, seeding and refining
+//! the Huffman tables and limiting code lengths, so our output should match them exactly.
+//!
+//! The one place we know we differ is a block that is a whole number of repeats of a shorter
+//! string, such as `abab…ab`. Several of its rotations are then equal, and libbzip2's sort puts
+//! equal rotations in a different order from ours. Equal rotations end in the same byte, so the
+//! transformed block is the same, but the 24-bit origin pointer in the block header can differ.
+//! Those cases only check that each side can read the other's output.
+#![cfg(feature = "libbzip2-golden")]
+use std::io::Read;
+
+use beeziptoo::{compress_with, decompress_to_vec, BlockSize, CompressOptions};
+use bzip2::read::BzDecoder;
+
+/// Generate `len` bytes that are unlikely to compress well.
+fn noise(len: usize) -> Vec<u8> {
+    // A simple linear congruential generator is plenty here.
+    let mut state: u32 = 0x9e37_79b9;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+/// Generate `len` bytes of words separated by spaces, which compress about as well as text.
+fn words(len: usize, seed: u32) -> Vec<u8> {
+    const WORDS: [&str; 16] = [
+        "peter", "piper", "picked", "a", "peck", "of", "pickled", "peppers", "where", "is", "the",
+        "that", "bzip", "block", "sorted", "huffman",
+    ];
+
+    let mut state = seed;
+    let mut text = vec![];
+    while text.len() < len {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        text.extend_from_slice(WORDS[(state >> 16) as usize % WORDS.len()].as_bytes());
+        text.push(b' ');
+    }
+    text.truncate(len);
+    text
+}
+
+/// Text with a run that is added just before a 100 kB block fills up, and another that comes
+/// just after the next block has filled, so both sides of the block-cutting rule are covered.
+fn block_edges() -> Vec<u8> {
+    // libbzip2 cuts a level 1 block once it holds 99,981 bytes after the first run-length stage,
+    // and none of the words repeat a letter four times, so that stage leaves them alone.
+    let mut data = words(99_978, 1);
+    data.extend([b'x'; 40]);
+    data.extend(words(99_981, 2));
+    data.extend([b'z'; 40]);
+    data.extend(words(1_000, 3));
+    data
+}
+
+/// Each case's name, which is also its file name, the level to compress it at, the input, and
+/// whether we expect to match libbzip2 exactly.
+fn cases() -> Vec<(&'static str, u8, Vec<u8>, bool)> {
+    vec![
+        ("empty", 9, vec![], true),
+        ("one_byte", 9, b"a".to_vec(), true),
+        (
+            "peter",
+            9,
+            b"If Peter Piper picked a peck of pickled peppers".to_vec(),
+            true,
+        ),
+        ("words", 9, words(50_000, 4), true),
+        ("noise", 9, noise(20_000), true),
+        (
+            "runs",
+            1,
+            (0..400_000u32).map(|i| (i / 7 % 5) as u8).collect(),
+            true,
+        ),
+        ("block_edges", 1, block_edges(), true),
+        ("periodic", 9, b"ab".repeat(1_000), false),
+    ]
+}
+
+/// Our output should be byte-identical to libbzip2's, apart from the cases we know differ.
+#[test]
+fn golden() {
+    for (name, level, input, exact) in cases() {
+        let path = format!("{}/tests/libbzip2/{name}.bz2", env!("CARGO_MANIFEST_DIR"));
+        let expected =
+            std::fs::read(&path).unwrap_or_else(|e| panic!("Could not read {path}: {e}"));
+        let options = CompressOptions {
+            block_size: match level {
+                1 => BlockSize::K100,
+                9 => BlockSize::K900,
+                _ => unreachable!("The cases only use levels 1 and 9"),
+            },
+            ..Default::default()
+        };
+
+        let mut compressed = vec![];
+        compress_with(&input[..], options)
+            .expect("Could not compress data")
+            .read_to_end(&mut compressed)
+            .expect("Could not read compressed data");
+
+        assert_eq!(
+            decompress_to_vec(&expected).expect("Could not decompress libbzip2's output"),
+            input,
+            "{name}"
+        );
+        if exact {
+            assert!(
+                compressed == expected,
+                "{name} differs from libbzip2's output"
+            );
+        } else {
+            assert!(compressed != expected, "{name} is now byte-identical");
+            let mut decompressed = vec![];
+            BzDecoder::new(&compressed[..])
+                .read_to_end(&mut decompressed)
+                .expect("bzip2 could not decompress data");
+            assert_eq!(decompressed, input, "{name}");
+        }
+    }
+}